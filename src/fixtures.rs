@@ -0,0 +1,68 @@
+//! Test images shared by the tests of the library and of the binary.
+
+// each of the two crates uses only some of these
+#![allow(dead_code)]
+
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+
+pub const PALETTE: [[u8; 4]; 6] = [
+    [0, 0, 0, 255],
+    [255, 255, 255, 255],
+    [200, 40, 40, 255],
+    [40, 160, 60, 255],
+    [30, 60, 200, 128],
+    [0, 0, 0, 0],
+];
+
+/// The colors of [`PALETTE`] that formats without alpha, like GIF, keep as they are.
+pub const OPAQUE_PALETTE: &[[u8; 4]] = &[PALETTE[0], PALETTE[1], PALETTE[2], PALETTE[3]];
+
+/// A simple xorshift, so the tests need no random number crate.
+pub struct Random(pub u64);
+
+impl Random {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Native pixel art where each pixel differs from its left and top
+/// neighbor, so every run is exactly one pixel.
+pub fn native_art(width: u32, height: u32, seed: u64) -> RgbaImage {
+    native_art_of(&PALETTE, width, height, seed)
+}
+
+/// Like [`native_art`], with the colors of `palette`, which needs at least 3.
+pub fn native_art_of(palette: &[[u8; 4]], width: u32, height: u32, seed: u64) -> RgbaImage {
+    let mut random = Random(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1);
+    let mut img = RgbaImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let left = (x > 0).then(|| *img.get_pixel(x - 1, y));
+            let top = (y > 0).then(|| *img.get_pixel(x, y - 1));
+            let mut index = random.next() as usize % palette.len();
+            while Some(Rgba(palette[index])) == left || Some(Rgba(palette[index])) == top {
+                index = (index + 1) % palette.len();
+            }
+            img.put_pixel(x, y, Rgba(palette[index]));
+        }
+    }
+    img
+}
+
+pub fn upscale(img: &RgbaImage, scale: u32) -> RgbaImage {
+    imageops::resize(img, img.width() * scale, img.height() * scale, FilterType::Nearest)
+}
+
+/// Changes every channel by up to `amount` in either direction.
+pub fn add_noise(img: &mut RgbaImage, amount: u8, seed: u64) {
+    let mut random = Random(seed | 1);
+    for channel in img.iter_mut() {
+        let delta = (random.next() % (amount as u64 * 2 + 1)) as i16 - amount as i16;
+        *channel = (*channel as i16 + delta).clamp(0, 255) as u8;
+    }
+}
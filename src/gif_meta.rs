@@ -0,0 +1,190 @@
+//! Reads the bits of GIF metadata the image crate doesn't expose.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GifMeta {
+    /// Loop count of the NETSCAPE2.0 application extension, 0 means infinite.
+    /// `None` if there is no such extension.
+    pub loop_count: Option<u16>,
+
+    /// Background color of the logical screen, i.e. the entry of the global
+    /// color table the background color index points to. `None` if there is no
+    /// global color table.
+    pub background_color: Option<[u8; 3]>,
+
+    /// Number of images, i.e. frames, in the stream.
+    pub frame_count: usize,
+}
+
+const EXTENSION_INTRODUCER: u8 = 0x21;
+const IMAGE_SEPARATOR: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+const APPLICATION_LABEL: u8 = 0xFF;
+
+/// Scans all blocks of a GIF stream and collects the metadata.
+pub fn read_gif_meta(reader: impl Read) -> io::Result<GifMeta> {
+    read_blocks(reader, true)
+}
+
+/// Reads the metadata up to the first image, for a stream that can't be
+/// scanned to the end before decoding it. The loop count comes before the
+/// first image, but the frame count stays 0.
+pub fn read_gif_meta_head(reader: impl Read) -> io::Result<GifMeta> {
+    read_blocks(reader, false)
+}
+
+fn read_blocks(mut reader: impl Read, all: bool) -> io::Result<GifMeta> {
+    let mut meta = GifMeta::default();
+
+    let mut header = [0u8; 13];
+    reader.read_exact(&mut header)?;
+    if &header[..3] != b"GIF" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a GIF file"));
+    }
+    let flags = header[10];
+    if flags & 0x80 != 0 {
+        let mut color_table = vec![0u8; 3 * (2 << (flags & 0x07))];
+        reader.read_exact(&mut color_table)?;
+        let index = 3 * header[11] as usize;
+        meta.background_color = color_table.get(index..index + 3).map(|color| [color[0], color[1], color[2]]);
+    }
+
+    loop {
+        match read_u8(&mut reader)? {
+            EXTENSION_INTRODUCER => {
+                let label = read_u8(&mut reader)?;
+                if label == APPLICATION_LABEL {
+                    let mut block = read_sub_block(&mut reader)?;
+                    if block == b"NETSCAPE2.0" || block == b"ANIMEXTS1.0" {
+                        while !block.is_empty() {
+                            block = read_sub_block(&mut reader)?;
+                            if block.len() >= 3 && block[0] == 1 {
+                                meta.loop_count = Some(u16::from_le_bytes([block[1], block[2]]));
+                            }
+                        }
+                    } else {
+                        skip_sub_blocks(&mut reader)?;
+                    }
+                } else {
+                    skip_sub_blocks(&mut reader)?;
+                }
+            }
+            IMAGE_SEPARATOR if !all => break,
+            IMAGE_SEPARATOR => {
+                meta.frame_count += 1;
+                let mut descriptor = [0u8; 9];
+                reader.read_exact(&mut descriptor)?;
+                let flags = descriptor[8];
+                if flags & 0x80 != 0 {
+                    skip_color_table(&mut reader, flags)?;
+                }
+                // LZW minimum code size
+                read_u8(&mut reader)?;
+                skip_sub_blocks(&mut reader)?;
+            }
+            TRAILER => break,
+            byte => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("illegal GIF block: 0x{byte:02X}")));
+            }
+        }
+    }
+
+    Ok(meta)
+}
+
+/// Sets the background color of a GIF written by the image crate. Its
+/// encoder always writes a global color table of two black entries with the
+/// background color index 0, but gives every frame a local color table. So the
+/// first entry can be changed without changing any frame.
+pub fn write_background_color<W: Write + Seek>(writer: &mut W, color: [u8; 3]) -> io::Result<()> {
+    let end = writer.stream_position()?;
+    // header and logical screen descriptor
+    writer.seek(SeekFrom::Start(13))?;
+    writer.write_all(&color)?;
+    writer.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn skip(reader: &mut impl Read, size: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+    if skipped != size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn skip_color_table(reader: &mut impl Read, flags: u8) -> io::Result<()> {
+    skip(reader, 3 * (2 << (flags & 0x07)))
+}
+
+/// Reads one data sub-block. An empty sub-block terminates a sequence of them.
+fn read_sub_block(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let size = read_u8(reader)?;
+    let mut block = vec![0u8; size as usize];
+    reader.read_exact(&mut block)?;
+    Ok(block)
+}
+
+fn skip_sub_blocks(reader: &mut impl Read) -> io::Result<()> {
+    loop {
+        let size = read_u8(reader)?;
+        if size == 0 {
+            return Ok(());
+        }
+        skip(reader, size as u64)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::{GifDecoder, GifEncoder};
+    use image::{AnimationDecoder, Frame, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn background_color_survives_reencoding() {
+        let palette = [0, 0, 0, 255, 255, 255, 10, 20, 30, 40, 50, 60];
+        let mut input = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut input, 2, 2, &palette).unwrap();
+            encoder.set_repeat(gif::Repeat::Finite(3)).unwrap();
+            for buffer in [[0, 1, 1, 0], [3, 3, 1, 1]] {
+                encoder.write_frame(&gif::Frame { width: 2, height: 2, buffer: buffer.to_vec().into(), ..gif::Frame::default() }).unwrap();
+            }
+        }
+        // the encoder always writes a background color index of 0
+        input[11] = 2;
+
+        let meta = read_gif_meta(Cursor::new(&input)).unwrap();
+        assert_eq!(meta, GifMeta { loop_count: Some(3), background_color: Some([10, 20, 30]), frame_count: 2 });
+        assert_eq!(read_gif_meta_head(Cursor::new(&input)).unwrap(), GifMeta { frame_count: 0, ..meta.clone() });
+
+        let frames = [
+            RgbaImage::from_pixel(2, 2, Rgba([200, 0, 0, 255])),
+            RgbaImage::from_pixel(2, 2, Rgba([0, 200, 0, 255])),
+        ];
+        let mut output = Cursor::new(Vec::new());
+        {
+            let mut encoder = GifEncoder::new(&mut output);
+            encoder.encode_frames(frames.iter().cloned().map(Frame::new)).unwrap();
+        }
+        write_background_color(&mut output, meta.background_color.unwrap()).unwrap();
+        let output = output.into_inner();
+
+        assert_eq!(output[11], 0);
+        assert_eq!(output[13..16], [10, 20, 30]);
+        assert_eq!(read_gif_meta(Cursor::new(&output)).unwrap().background_color, Some([10, 20, 30]));
+        let decoded: Vec<_> = GifDecoder::new(Cursor::new(&output)).unwrap()
+            .into_frames().collect_frames().unwrap()
+            .into_iter().map(Frame::into_buffer).collect();
+        assert_eq!(decoded, frames);
+    }
+}
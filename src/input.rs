@@ -0,0 +1,201 @@
+//! Where the input is read from, either a file, stdin, or an entry of an
+//! archive that is already in memory.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, StdinLock};
+use std::sync::Arc;
+
+/// Input path that means stdin.
+pub const STDIN: &str = "-";
+
+pub fn is_stdin(input: &OsStr) -> bool {
+    input == STDIN
+}
+
+/// How many bytes at the start of a stream are kept to seek back to. That is
+/// enough for guessing the format and reading the headers before decoding.
+const STREAM_HEAD_LIMIT: usize = 1 << 20;
+
+/// Format guessing and some decoders need to seek, which stdin can't, so
+/// stdin is read into memory as a whole, unless it is streamed.
+pub enum InputReader {
+    File(BufReader<File>),
+    Stdin(Cursor<Vec<u8>>),
+    Stream(StreamReader),
+    Memory(Cursor<Arc<[u8]>>),
+}
+
+impl InputReader {
+    pub fn open(input: &OsStr) -> io::Result<Self> {
+        if is_stdin(input) {
+            let mut buffer = Vec::new();
+            io::stdin().lock().read_to_end(&mut buffer)?;
+            Ok(InputReader::Stdin(Cursor::new(buffer)))
+        } else {
+            Ok(InputReader::File(BufReader::new(File::open(input)?)))
+        }
+    }
+
+    /// Reads data that is already in memory, like an entry of --zip.
+    pub fn from_memory(data: Arc<[u8]>) -> Self {
+        InputReader::Memory(Cursor::new(data))
+    }
+
+    /// Reads stdin as it is decoded instead of reading it as a whole first,
+    /// e.g. to stop after the first frame of an animation. Only the start of
+    /// the stream can be seeked back to.
+    pub fn open_streaming() -> Self {
+        InputReader::Stream(StreamReader {
+            inner: io::stdin().lock(),
+            head: Vec::new(),
+            consumed: 0,
+            pos: 0,
+        })
+    }
+
+    pub fn is_stream(&self) -> bool {
+        matches!(self, InputReader::Stream(_))
+    }
+
+    /// Reads the rest of a stream into memory, for decoders that seek around.
+    /// Other inputs are returned as they are.
+    pub fn into_buffered(self) -> io::Result<Self> {
+        let InputReader::Stream(mut stream) = self else {
+            return Ok(self);
+        };
+        if !stream.can_rewind() {
+            return Err(cant_rewind());
+        }
+        let mut buffer = stream.head;
+        stream.inner.read_to_end(&mut buffer)?;
+        let mut cursor = Cursor::new(buffer);
+        cursor.set_position(stream.pos);
+        Ok(InputReader::Stdin(cursor))
+    }
+}
+
+/// Stdin read as it is decoded, see [`InputReader::open_streaming`]. The
+/// bytes read so far are kept up to [`STREAM_HEAD_LIMIT`], so it can seek
+/// back as long as it didn't read past that.
+pub struct StreamReader {
+    inner: StdinLock<'static>,
+    head: Vec<u8>,
+    /// Number of bytes read from stdin.
+    consumed: u64,
+    /// Position in the stream, behind `consumed` after seeking back.
+    pos: u64,
+}
+
+impl StreamReader {
+    /// Whether all bytes read so far are kept.
+    fn can_rewind(&self) -> bool {
+        self.head.len() as u64 == self.consumed
+    }
+}
+
+fn cant_rewind() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "can't seek back in stdin that far")
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for StreamReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos < self.consumed {
+            // only possible after seeking back into the head
+            return Ok(&self.head[self.pos as usize..]);
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.pos < self.consumed {
+            self.pos += amt as u64;
+            return;
+        }
+        if self.can_rewind() && self.head.len() + amt <= STREAM_HEAD_LIMIT {
+            // the bytes are still in the buffer of stdin, so this doesn't read
+            if let Ok(buffer) = self.inner.fill_buf() {
+                self.head.extend_from_slice(&buffer[..amt]);
+            }
+        }
+        self.inner.consume(amt);
+        self.consumed += amt as u64;
+        self.pos = self.consumed;
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(target) => Some(target),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        let Some(target) = target else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "can't seek relative to the end of stdin"));
+        };
+        if target <= self.consumed && self.can_rewind() {
+            self.pos = target;
+        } else if target >= self.pos {
+            let distance = target - self.pos;
+            if io::copy(&mut self.by_ref().take(distance), &mut io::sink())? != distance {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        } else {
+            return Err(cant_rewind());
+        }
+        Ok(self.pos)
+    }
+}
+
+impl Read for InputReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            InputReader::File(reader) => reader.read(buf),
+            InputReader::Stdin(buffer) => buffer.read(buf),
+            InputReader::Stream(stream) => stream.read(buf),
+            InputReader::Memory(data) => data.read(buf),
+        }
+    }
+}
+
+impl BufRead for InputReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match self {
+            InputReader::File(reader) => reader.fill_buf(),
+            InputReader::Stdin(buffer) => buffer.fill_buf(),
+            InputReader::Stream(stream) => stream.fill_buf(),
+            InputReader::Memory(data) => data.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match self {
+            InputReader::File(reader) => reader.consume(amt),
+            InputReader::Stdin(buffer) => buffer.consume(amt),
+            InputReader::Stream(stream) => stream.consume(amt),
+            InputReader::Memory(data) => data.consume(amt),
+        }
+    }
+}
+
+impl Seek for InputReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            InputReader::File(reader) => reader.seek(pos),
+            InputReader::Stdin(buffer) => buffer.seek(pos),
+            InputReader::Stream(stream) => stream.seek(pos),
+            InputReader::Memory(data) => data.seek(pos),
+        }
+    }
+}
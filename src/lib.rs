@@ -0,0 +1,1994 @@
+//! Detection of the scaling factor of upscaled pixel art.
+//!
+//! Pixel art that was scaled up by an integer factor using nearest neighbor
+//! consists of runs of equal colored pixels that are all multiples of that
+//! factor. This finds the smallest such factor so the image can be scaled back
+//! to its native resolution.
+
+#[cfg(test)]
+mod fixtures;
+
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel, Primitive, Rgba, RgbaImage};
+
+use rayon::prelude::*;
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How the scale is picked from the lengths of the runs of equal colored pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// The largest length that the runs are multiples of, i.e. their greatest
+    /// common divisor. Safest for clean pixel art. See
+    /// [`DetectOptions::grid_agreement`] for how many runs have to fit.
+    #[default]
+    Gcd,
+    /// The most common length. Runs of other lengths, e.g. from dithering or
+    /// resampled edges, are ignored, so this works for pixel art that isn't
+    /// clean, but it can also mistake an image with few colors for pixel art.
+    Mode,
+    /// The shortest length, whether the other runs are multiples of it or not.
+    /// For pixel art whose pixels were scaled by slightly different factors.
+    /// Runs of a single pixel are tolerated as far as
+    /// [`DetectOptions::grid_agreement`] allows.
+    Min,
+}
+
+/// Options controlling the scale detection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DetectOptions {
+    /// Ignore the first run of pixels of each row and column. Helps with pixel
+    /// art framed by a border that doesn't fit the pixel grid.
+    pub ignore_border: bool,
+
+    /// Maximum difference per channel for two colors to be considered equal.
+    /// Useful for pixel art that was saved in a lossy format.
+    pub tolerance: u8,
+
+    /// Only runs of pixels with an alpha value greater than this count towards
+    /// the detection. With 0 every run that isn't fully transparent counts.
+    pub alpha_threshold: u8,
+
+    /// Count runs of transparent pixels too, regardless of `alpha_threshold`.
+    /// Helps if the transparent background itself is aligned to the grid.
+    pub include_transparent: bool,
+
+    /// Compare only the color channels, so pixels that differ just in their
+    /// alpha value are considered the same color.
+    pub ignore_alpha: bool,
+
+    /// Runs of this color, within `tolerance`, don't count towards the
+    /// detection, just like transparent runs. For pixel art on an opaque mat
+    /// whose size doesn't fit the grid. See [`detect_mat_color`].
+    pub mat_color: Option<[u8; 3]>,
+
+    /// Don't assume the pixel grid starts at the top left corner, so partial
+    /// pixels at the edges of cropped images are ignored. Use
+    /// [`detect_phase()`] to find where the grid starts.
+    pub detect_phase: bool,
+
+    /// Smallest stride that is accepted as a scale. Smaller strides count as
+    /// not upscaled. A stride of 1 never counts as upscaled anyway.
+    pub min_stride: Option<u32>,
+
+    /// Largest stride that is accepted as a scale. Large uniform areas can make
+    /// a large stride seem plausible, which is more likely a wrong detection.
+    pub max_stride: Option<u32>,
+
+    /// Percentage of runs of pixels that have to be multiples of the detected
+    /// scale. With `None` every run has to be, and a single run of one pixel
+    /// means the image isn't upscaled. With a percentage a few stray runs, e.g.
+    /// from anti-aliased edges or a watermark, don't spoil the detection.
+    pub grid_agreement: Option<u8>,
+
+    /// How the scale is picked from the lengths of the runs of pixels.
+    pub strategy: Strategy,
+
+    /// Number of threads the frames of an animation, or the rows and columns
+    /// of a still image, are analyzed with. `None` analyzes them one after
+    /// another on the current thread, `Some(0)` uses the current rayon thread
+    /// pool, which by default has one thread per core.
+    pub threads: Option<usize>,
+}
+
+/// Detects the factor by which the pixel art in `img` was scaled up.
+///
+/// Returns `None` if the image doesn't look like upscaled pixel art, i.e. it
+/// is already at its native resolution or isn't pixel art at all.
+///
+/// If `ignore_border` is set the first run of pixels of each row and column is
+/// ignored, which helps with pixel art framed by a border that doesn't fit the
+/// pixel grid.
+pub fn detect_scale(img: &DynamicImage, ignore_border: bool) -> Option<u32> {
+    detect_scale_with(img, &DetectOptions { ignore_border, ..DetectOptions::default() })
+}
+
+/// Same as [`detect_scale`], but with all the options.
+pub fn detect_scale_with(img: &DynamicImage, options: &DetectOptions) -> Option<u32> {
+    stride_to_scale(get_smallest_stride(img, options), img.width(), img.height())
+}
+
+/// Detects the factor by which the frames of an animation were scaled up.
+///
+/// All frames have to be consistent with the same scale. `width` and `height`
+/// are the dimensions of the animation. Returns `None` if no scaling is
+/// detected.
+pub fn detect_scale_from_frames<'a>(width: u32, height: u32, frames: impl IntoIterator<Item=&'a DynamicImage>, ignore_border: bool) -> Option<u32> {
+    detect_scale_from_frames_with(width, height, frames, &DetectOptions { ignore_border, ..DetectOptions::default() })
+}
+
+/// Same as [`detect_scale_from_frames`], but with all the options.
+pub fn detect_scale_from_frames_with<'a>(width: u32, height: u32, frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) -> Option<u32> {
+    stride_to_scale(get_smallest_stride_from_animation(width, height, frames.into_iter(), options), width, height)
+}
+
+/// Detects the scale of each frame of an animation on its own.
+///
+/// Returns the index and the scale of each frame that contains anything to
+/// detect the scale from. Returns `None` if any frame is at its native
+/// resolution.
+pub fn detect_scale_of_each_frame<'a>(width: u32, height: u32, frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) -> Option<Vec<(usize, u32)>> {
+    let mut strides = vec![0; width.max(height) as usize + 1];
+    let mut frame_strides = Vec::new();
+    for (index, frame) in frames.into_iter().enumerate() {
+        strides.fill(0);
+        if !get_smallest_stride_phase1(frame, &mut strides, options) {
+            return None;
+        }
+        if strides.iter().any(|&count| count > 0) {
+            let scale = stride_to_scale(get_smallest_stride_phase2(&strides, options), width, height)?;
+            frame_strides.push((index, scale));
+        }
+    }
+
+    Some(frame_strides)
+}
+
+/// Same as [`detect_scale_from_frames_with`], but also returns the scale of
+/// each frame on its own, so frames that disagree can be reported.
+///
+/// Each frame that contains anything to detect the scale from is listed with
+/// its index and scale, which is 1 for a frame at its native resolution. All
+/// frames are analyzed, even if one of them already rules out any scaling.
+pub fn detect_scale_and_frame_scales<'a>(width: u32, height: u32, frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) -> (Option<u32>, Vec<(usize, u32)>) {
+    let len = width.max(height) as usize + 1;
+    let frames = frames.into_iter().collect::<Vec<_>>();
+    let analyze = |frame: &&DynamicImage| {
+        let mut strides = vec![0; len];
+        let upscaled = get_smallest_stride_phase1(frame, &mut strides, options);
+        (upscaled, strides)
+    };
+    let analyzed: Vec<_> = match options.threads {
+        None => frames.iter().map(analyze).collect(),
+        Some(0) => frames.par_iter().map(analyze).collect(),
+        Some(threads) => match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+            Ok(pool) => pool.install(|| frames.par_iter().map(analyze).collect()),
+            Err(_) => frames.iter().map(analyze).collect(),
+        },
+    };
+
+    let mut detector = FrameDetector::new(width, height, options.clone());
+    for (frame_upscaled, frame_strides) in analyzed {
+        detector.add(frame_upscaled, frame_strides);
+    }
+    detector.finish()
+}
+
+/// Detects the scale of an animation whose frames are fed one at a time, e.g.
+/// while they are being decoded, so only one of them has to be in memory at
+/// once. Gives the same result as [`detect_scale_and_frame_scales`].
+pub struct FrameDetector {
+    width: u32,
+    height: u32,
+    options: DetectOptions,
+    strides: Vec<u64>,
+    upscaled: bool,
+    frame_scales: Vec<(usize, u32)>,
+    index: usize,
+}
+
+impl FrameDetector {
+    /// Creates a detector for frames of `width` x `height` pixels.
+    pub fn new(width: u32, height: u32, options: DetectOptions) -> Self {
+        FrameDetector {
+            width,
+            height,
+            options,
+            strides: vec![0; width.max(height) as usize + 1],
+            upscaled: true,
+            frame_scales: Vec::new(),
+            index: 0,
+        }
+    }
+
+    /// The options given to [`FrameDetector::new`].
+    pub fn options(&self) -> &DetectOptions {
+        &self.options
+    }
+
+    /// Analyzes the next frame. It has to be of the size given to [`FrameDetector::new`].
+    pub fn feed_frame(&mut self, frame: &DynamicImage) {
+        let mut frame_strides = vec![0; self.strides.len()];
+        let frame_upscaled = get_smallest_stride_phase1(frame, &mut frame_strides, &self.options);
+        self.add(frame_upscaled, frame_strides);
+    }
+
+    fn add(&mut self, frame_upscaled: bool, frame_strides: Vec<u64>) {
+        let index = self.index;
+        self.index += 1;
+        if !frame_upscaled {
+            self.upscaled = false;
+            self.frame_scales.push((index, 1));
+            return;
+        }
+        if frame_strides.iter().any(|&count| count > 0) {
+            self.frame_scales.push((index, get_smallest_stride_phase2(&frame_strides, &self.options)));
+        }
+        for (count, frame_count) in self.strides.iter_mut().zip(frame_strides) {
+            *count += frame_count;
+        }
+    }
+
+    /// Returns the detected scale and the scale of each frame, like
+    /// [`detect_scale_and_frame_scales`].
+    pub fn finish(self) -> (Option<u32>, Vec<(usize, u32)>) {
+        let scale = if self.upscaled {
+            stride_to_scale(get_smallest_stride_phase2(&self.strides, &self.options), self.width, self.height)
+        } else {
+            None
+        };
+        (scale, self.frame_scales)
+    }
+}
+
+/// How sure it is that the frames are pixel art scaled up by `scale`, from 0
+/// to 1.
+///
+/// This is the fraction of runs of exactly equal colors whose length is a
+/// multiple of `scale`. Without a tolerance every run of a detected image
+/// is, so it is 1. With a tolerance a lower value means the colors within
+/// the pixels vary, i.e. the image might not be clean pixel art. Runs are
+/// counted by the same rules as for the detection.
+pub fn detect_confidence<'a>(frames: impl IntoIterator<Item=&'a DynamicImage>, scale: u32, options: &DetectOptions) -> f64 {
+    let (mut total, mut multiples) = (0u64, 0u64);
+    for frame in frames {
+        for_each_run(frame, options, true, |_, stride| {
+            total += 1;
+            if scale > 0 && stride.is_multiple_of(scale) {
+                multiples += 1;
+            }
+        });
+    }
+    if total == 0 {
+        return 1.0;
+    }
+    multiples as f64 / total as f64
+}
+
+/// Estimates the scale of pixel art that was scaled up by a factor that isn't
+/// an integer, e.g. 2.5, so runs of a single pixel alternate between 2 and 3.
+///
+/// This is only informational, such an image can't be scaled down cleanly.
+/// The two shortest common run lengths of at least 2 are taken as single
+/// pixels and averaged by how often they occur. Runs of length 1 are taken
+/// as edges of pixels that were blended by a smoothing filter, so their
+/// number per longer run is added. Returns `None` if most runs have length 1
+/// or the estimate is below 1.5.
+pub fn estimate_scale<'a>(frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) -> Option<f64> {
+    let mut histogram: Vec<u64> = vec![0; 3];
+    for frame in frames {
+        for_each_run(frame, options, false, |_, stride| {
+            let stride = stride as usize;
+            if stride + 1 >= histogram.len() {
+                histogram.resize(stride + 2, 0);
+            }
+            histogram[stride] += 1;
+        });
+    }
+
+    let blended = histogram[1];
+    let longer: u64 = histogram[2..].iter().sum();
+    if longer == 0 || blended > longer {
+        return None;
+    }
+    // rare run lengths are noise
+    let shortest = (2..histogram.len()).find(|&length| histogram[length] * 100 >= longer)?;
+    let (short, long) = (histogram[shortest], histogram[shortest + 1]);
+    let single = (shortest as u64 * short + (shortest as u64 + 1) * long) as f64 / (short + long) as f64;
+    let estimate = single + blended as f64 / longer as f64;
+    if estimate < 1.5 {
+        return None;
+    }
+    Some(estimate)
+}
+
+/// Whether a failed detection looks like it is due to chroma subsampling, as
+/// done by JPEG and video with 4:2:0. That keeps the brightness of each pixel,
+/// but shares the color between blocks of 2x2 pixels, so colors bleed over
+/// the edges of pixels that don't fall on the edges of such blocks.
+///
+/// This is the case if the brightness alone shows a scale, and the colors only
+/// change off that grid within 2 pixels of its lines. Colors that were
+/// clipped when converted back to RGB change the brightness slightly, so both
+/// are compared with a tolerance of at least 16, which also keeps mere noise
+/// from counting as bleeding colors.
+pub fn looks_chroma_subsampled(img: &DynamicImage, options: &DetectOptions) -> bool {
+    let rgba = img.to_rgba8();
+    // the luma of YCbCr as used by JPEG, which differs from the one of the image crate
+    let luma = image::GrayAlphaImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [red, green, blue, alpha] = rgba.get_pixel(x, y).0.map(f32::from);
+        image::LumaA([(0.299 * red + 0.587 * green + 0.114 * blue).round() as u8, alpha as u8])
+    });
+    let luma_options = DetectOptions { tolerance: options.tolerance.max(16), ..options.clone() };
+    let Some(scale) = detect_scale_with(&DynamicImage::ImageLumaA8(luma), &luma_options) else {
+        return false;
+    };
+    let rgb = DynamicImage::ImageRgba8(rgba).into_rgb8();
+    let (width, height) = rgb.dimensions();
+    let differs = |a: &image::Rgb<u8>, b: &image::Rgb<u8>| {
+        a.0.iter().zip(b.0).any(|(&a, b)| a.abs_diff(b) > luma_options.tolerance)
+    };
+    let (mut off_grid, mut near_grid) = (0u64, 0u64);
+    let mut count = |pos: u32| {
+        let offset = pos % scale;
+        if offset != 0 {
+            off_grid += 1;
+            if offset.min(scale - offset) <= 2 {
+                near_grid += 1;
+            }
+        }
+    };
+    for y in 0..height {
+        for x in 1..width {
+            if differs(rgb.get_pixel(x - 1, y), rgb.get_pixel(x, y)) {
+                count(x);
+            }
+        }
+    }
+    for x in 0..width {
+        for y in 1..height {
+            if differs(rgb.get_pixel(x, y - 1), rgb.get_pixel(x, y)) {
+                count(y);
+            }
+        }
+    }
+    off_grid > 0 && near_grid * 10 >= off_grid * 9
+}
+
+/// Detects where the pixel grid of pixel art that was scaled up by `scale`
+/// starts, i.e. the offset of the first complete pixel per axis.
+///
+/// Cropped screenshots often start in the middle of a pixel. The offset is
+/// always smaller than `scale` and 0 for an axis without any change of color.
+pub fn detect_phase(img: &DynamicImage, scale: u32, options: &DetectOptions) -> (u32, u32) {
+    detect_phase_from_frames([img], scale, options)
+}
+
+/// Same as [`detect_phase`], but for the frames of an animation.
+pub fn detect_phase_from_frames<'a>(frames: impl IntoIterator<Item=&'a DynamicImage>, scale: u32, options: &DetectOptions) -> (u32, u32) {
+    let mut phase = (None, None);
+    for frame in frames {
+        find_phase(frame, scale, options, &mut phase);
+        if let (Some(x), Some(y)) = phase {
+            return (x, y);
+        }
+    }
+    (phase.0.unwrap_or(0), phase.1.unwrap_or(0))
+}
+
+/// Finds the first change of color along each axis that isn't found yet. All
+/// changes of color of upscaled pixel art lie on the pixel grid.
+fn find_phase(img: &DynamicImage, scale: u32, options: &DetectOptions, phase: &mut (Option<u32>, Option<u32>)) {
+    match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => find_phase_of(&rgba, scale, options, phase),
+        RgbaPixels::Sixteen(rgba) => find_phase_of(&rgba, scale, options, phase),
+    }
+}
+
+fn find_phase_of<T: Channel>(rgba: &RgbaBuffer<T>, scale: u32, options: &DetectOptions, phase: &mut (Option<u32>, Option<u32>))
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let row_len = rgba.width() as usize * 4;
+    if row_len == 0 || scale == 0 {
+        return;
+    }
+
+    let rows = rgba.as_raw().chunks_exact(row_len);
+    // colors the current runs of each column started with
+    let mut column_colors = rgba.as_raw()[..row_len].chunks_exact(4).collect::<Vec<_>>();
+
+    for (y, row) in rows.enumerate() {
+        let y = y as u32;
+        let mut run_color = &row[..4];
+        for (x, pixel) in row.chunks_exact(4).enumerate() {
+            let color = to_rgba(pixel);
+            if !same_color(color, to_rgba(run_color), options) {
+                if phase.0.is_none() {
+                    phase.0 = Some(x as u32 % scale);
+                }
+                run_color = pixel;
+            }
+            if !same_color(color, to_rgba(column_colors[x]), options) {
+                if phase.1.is_none() {
+                    phase.1 = Some(y % scale);
+                }
+                column_colors[x] = pixel;
+            }
+        }
+        if phase.0.is_some() && phase.1.is_some() {
+            return;
+        }
+    }
+}
+
+#[inline]
+fn to_rgba<T: Channel>(pixel: &[T]) -> Rgba<T> {
+    Rgba([pixel[0], pixel[1], pixel[2], pixel[3]])
+}
+
+/// Type of the channels the colors of pixels are compared at.
+trait Channel: Primitive + Eq + Send + Sync + 'static {
+    /// Whether two values differ by at most `tolerance`, which is given for
+    /// 8 bit channels.
+    fn within(self, other: Self, tolerance: u8) -> bool;
+
+    /// Whether the value is greater than `threshold`, which is given for 8 bit
+    /// channels.
+    fn above(self, threshold: u8) -> bool;
+
+    /// The mean of two values.
+    fn mix(self, other: Self) -> Self;
+
+    /// Whether the value differs from `value` by at most `tolerance`, which
+    /// are both given for 8 bit channels.
+    fn near(self, value: u8, tolerance: u8) -> bool;
+
+    /// Whether each channel of two colors differs by at most `tolerance`,
+    /// except for alpha if it is ignored. This compares channel by channel,
+    /// types with a faster way override it.
+    #[inline]
+    fn same(a: Rgba<Self>, b: Rgba<Self>, tolerance: u8, ignore_alpha: bool) -> bool {
+        same_by_channel(a, b, tolerance, ignore_alpha)
+    }
+}
+
+/// The comparison of [`Channel::same`] channel by channel.
+#[inline]
+fn same_by_channel<T: Channel>(a: Rgba<T>, b: Rgba<T>, tolerance: u8, ignore_alpha: bool) -> bool {
+    let channels = if ignore_alpha { 3 } else { 4 };
+    if tolerance == 0 {
+        return a.0[..channels] == b.0[..channels];
+    }
+    a.0[..channels].iter().zip(&b.0[..channels]).all(|(&a, &b)| a.within(b, tolerance))
+}
+
+/// Each of the 4 lanes of 16 bits of a u64 set to `value`.
+const fn lanes(value: u16) -> u64 {
+    value as u64 * 0x0001_0001_0001_0001
+}
+
+/// The 4 bytes of a pixel spread out to a lane of 16 bits each, so there is
+/// room for carries and borrows between them.
+#[inline]
+fn spread(pixel: u32) -> u64 {
+    let pixel = pixel as u64;
+    let pairs = (pixel | (pixel << 16)) & 0x0000_ffff_0000_ffff;
+    (pairs | (pairs << 8)) & lanes(0x00ff)
+}
+
+impl Channel for u8 {
+    #[inline]
+    fn within(self, other: Self, tolerance: u8) -> bool {
+        self.abs_diff(other) <= tolerance
+    }
+
+    #[inline]
+    fn above(self, threshold: u8) -> bool {
+        self > threshold
+    }
+
+    #[inline]
+    fn mix(self, other: Self) -> Self {
+        ((self as u16 + other as u16) / 2) as u8
+    }
+
+    #[inline]
+    fn near(self, value: u8, tolerance: u8) -> bool {
+        self.within(value, tolerance)
+    }
+
+    /// Compares the 4 channels at once as a u32, and with a tolerance as 4
+    /// lanes of a u64, instead of channel by channel.
+    #[inline]
+    fn same(a: Rgba<Self>, b: Rgba<Self>, tolerance: u8, ignore_alpha: bool) -> bool {
+        // alpha is the last byte in memory, whatever the byte order
+        let mask = if ignore_alpha { u32::from_ne_bytes([0xff, 0xff, 0xff, 0]) } else { u32::MAX };
+        let (a, b) = (u32::from_ne_bytes(a.0) & mask, u32::from_ne_bytes(b.0) & mask);
+        if tolerance == 0 {
+            return a == b;
+        }
+        // 256 + a - b in each lane, which has to be within 256 +/- tolerance
+        let diff = (spread(a) | lanes(0x0100)) - spread(b);
+        let tolerance = tolerance as u16;
+        // bit 15 ends up set in the lanes that are within each bound, no lane over- or underflows
+        let below_upper = lanes(0x8100 + tolerance) - diff;
+        let above_lower = diff + lanes(0x7f00 + tolerance);
+        below_upper & above_lower & lanes(0x8000) == lanes(0x8000)
+    }
+}
+
+impl Channel for u16 {
+    #[inline]
+    fn within(self, other: Self, tolerance: u8) -> bool {
+        self.abs_diff(other) <= tolerance as u16 * 257
+    }
+
+    #[inline]
+    fn above(self, threshold: u8) -> bool {
+        self > threshold as u16 * 257
+    }
+
+    #[inline]
+    fn mix(self, other: Self) -> Self {
+        ((self as u32 + other as u32) / 2) as u16
+    }
+
+    #[inline]
+    fn near(self, value: u8, tolerance: u8) -> bool {
+        self.within(value as u16 * 257, tolerance)
+    }
+}
+
+type RgbaBuffer<T> = ImageBuffer<Rgba<T>, Vec<T>>;
+
+/// The pixels of an image as RGBA. Images with 16 bits per channel keep them,
+/// so no precision is lost, everything else is converted to 8 bits.
+enum RgbaPixels<'a> {
+    Eight(Cow<'a, RgbaImage>),
+    Sixteen(Cow<'a, RgbaBuffer<u16>>),
+}
+
+fn rgba_pixels(img: &DynamicImage) -> RgbaPixels<'_> {
+    match img {
+        DynamicImage::ImageRgba8(img) => RgbaPixels::Eight(Cow::Borrowed(img)),
+        DynamicImage::ImageRgba16(img) => RgbaPixels::Sixteen(Cow::Borrowed(img)),
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgb16(_) => {
+            RgbaPixels::Sixteen(Cow::Owned(img.to_rgba16()))
+        }
+        img => RgbaPixels::Eight(Cow::Owned(img.to_rgba8())),
+    }
+}
+
+/// Width and height of the dither tiles [`detect_dithered_scale`] looks for.
+pub const DITHER_TILE_SIZE: u32 = 2;
+
+/// Detects the scale of pixel art with ordered 2 x 2 dithering, where a pixel
+/// may be a checkerboard of two colors, so the runs are a single pixel long.
+///
+/// Every tile of 2 x 2 pixels, starting at the top left corner, has to be a
+/// single color or a checkerboard of two colors, and at least one has to be a
+/// checkerboard. Each tile is then taken as a pixel of the mean of its colors
+/// and the scale of that is detected. The returned scale includes the size of
+/// the tiles, so it is at least 2. Partial tiles at the right and bottom edge
+/// are ignored. Returns `None` if the image isn't dithered that way.
+pub fn detect_dithered_scale(img: &DynamicImage, options: &DetectOptions) -> Option<u32> {
+    match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => detect_dithered_scale_of(&rgba, options),
+        RgbaPixels::Sixteen(rgba) => detect_dithered_scale_of(&rgba, options),
+    }
+}
+
+fn detect_dithered_scale_of<T: Channel>(rgba: &RgbaBuffer<T>, options: &DetectOptions) -> Option<u32>
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let size = DITHER_TILE_SIZE;
+    let (width, height) = (rgba.width() / size, rgba.height() / size);
+    if too_small_to_detect(width, height) {
+        return None;
+    }
+
+    let mut dithered = false;
+    let mut tiles: RgbaBuffer<T> = ImageBuffer::new(width, height);
+    for (x, y, tile) in tiles.enumerate_pixels_mut() {
+        let pixel = |dx: u32, dy: u32| *rgba.get_pixel(x * size + dx, y * size + dy);
+        let (a, b) = (pixel(0, 0), pixel(1, 0));
+        if !same_color(a, pixel(1, 1), options) || !same_color(b, pixel(0, 1), options) {
+            return None;
+        }
+        dithered |= !same_color(a, b, options);
+        // the mean is the same no matter which color comes first
+        *tile = Rgba(std::array::from_fn(|channel| a[channel].mix(b[channel])));
+    }
+    if !dithered {
+        return None;
+    }
+
+    let mut strides = vec![0; width.max(height) as usize + 1];
+    let scale = if get_smallest_stride_phase1_of(&tiles, &mut strides, options) {
+        stride_to_scale(get_smallest_stride_phase2(&strides, options), width, height)
+    } else {
+        None
+    };
+    // otherwise every tile is a pixel of its own
+    Some(scale.unwrap_or(1) * size)
+}
+
+/// Border around pixel art in pixels per side.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Border {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+impl Border {
+    pub fn is_empty(&self) -> bool {
+        *self == Border::default()
+    }
+
+    /// The part of the border that doesn't fit the pixel grid of pixel art
+    /// scaled up by `scale`, assuming the grid starts right inside the border.
+    /// The rest of the border might just as well be part of the pixel art.
+    pub fn remainder(&self, scale: u32) -> Border {
+        Border {
+            left: self.left % scale,
+            top: self.top % scale,
+            right: self.right % scale,
+            bottom: self.bottom % scale,
+        }
+    }
+}
+
+/// Detects a solid border around the frames, i.e. the lines at the edges that
+/// are a single color in every frame. The color may differ from line to line.
+///
+/// Frames that are a single color altogether are skipped. Use
+/// [`Border::remainder`] to get the part of the border that doesn't fit the
+/// pixel grid once the scale of the pixel art inside the border is known.
+pub fn detect_border<'a>(frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) -> Border {
+    let mut border: Option<Border> = None;
+    for frame in frames {
+        let Some(frame_border) = detect_frame_border(frame, options) else {
+            continue;
+        };
+        border = Some(match border {
+            None => frame_border,
+            Some(border) => Border {
+                left: border.left.min(frame_border.left),
+                top: border.top.min(frame_border.top),
+                right: border.right.min(frame_border.right),
+                bottom: border.bottom.min(frame_border.bottom),
+            },
+        });
+    }
+    border.unwrap_or_default()
+}
+
+/// Peels lines of a single color off the edges until none are left. Returns
+/// `None` if that leaves nothing, i.e. the frame is a single color.
+fn detect_frame_border(img: &DynamicImage, options: &DetectOptions) -> Option<Border> {
+    match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => detect_frame_border_of(&rgba, options),
+        RgbaPixels::Sixteen(rgba) => detect_frame_border_of(&rgba, options),
+    }
+}
+
+fn detect_frame_border_of<T: Channel>(rgba: &RgbaBuffer<T>, options: &DetectOptions) -> Option<Border>
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let (mut left, mut top, mut right, mut bottom) = (0, 0, rgba.width(), rgba.height());
+    let solid_row = |y: u32, left: u32, right: u32| {
+        let color = *rgba.get_pixel(left, y);
+        (left + 1..right).all(|x| same_color(*rgba.get_pixel(x, y), color, options))
+    };
+    let solid_column = |x: u32, top: u32, bottom: u32| {
+        let color = *rgba.get_pixel(x, top);
+        (top + 1..bottom).all(|y| same_color(*rgba.get_pixel(x, y), color, options))
+    };
+
+    loop {
+        let before = (left, top, right, bottom);
+        while top < bottom && left < right && solid_row(top, left, right) {
+            top += 1;
+        }
+        while top < bottom && left < right && solid_row(bottom - 1, left, right) {
+            bottom -= 1;
+        }
+        while left < right && top < bottom && solid_column(left, top, bottom) {
+            left += 1;
+        }
+        while left < right && top < bottom && solid_column(right - 1, top, bottom) {
+            right -= 1;
+        }
+        if left >= right || top >= bottom {
+            return None;
+        }
+        if before == (left, top, right, bottom) {
+            break;
+        }
+    }
+
+    Some(Border {
+        left,
+        top,
+        right: rgba.width() - right,
+        bottom: rgba.height() - bottom,
+    })
+}
+
+/// Infers the color of an opaque mat the pixel art sits on from the corners of
+/// the image, for [`DetectOptions::mat_color`]. That is the color at least two
+/// of the opaque corners share. Returns `None` if there is no such color.
+pub fn detect_mat_color(img: &DynamicImage) -> Option<[u8; 3]> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let corners: Vec<[u8; 3]> = [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)].into_iter()
+        .map(|(x, y)| img.get_pixel(x, y))
+        .filter(|pixel| pixel[3] == u8::MAX)
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    corners.iter()
+        .map(|&color| (color, corners.iter().filter(|&&other| other == color).count()))
+        .filter(|&(_, count)| count >= 2)
+        .max_by_key(|&(_, count)| count)
+        .map(|(color, _)| color)
+}
+
+/// Everything the detection finds out about an image, see [`analyze`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Analysis {
+    /// The number of runs of each length along the rows, indexed by the
+    /// length, as given by [`count_runs`].
+    pub runs_x: Vec<u64>,
+    /// The number of runs of each length along the columns.
+    pub runs_y: Vec<u64>,
+    /// The detected scale, `None` if the image doesn't look scaled up.
+    pub scale: Option<u32>,
+    /// Where the pixel grid starts, i.e. the offset of the first complete
+    /// pixel per axis. (0, 0) if no scale was detected.
+    pub phase: (u32, u32),
+    /// How sure it is about the scale, see [`detect_confidence`].
+    pub confidence: Option<f64>,
+    /// What is cropped with `ignore_border`, i.e. the part of the solid border
+    /// that doesn't fit the grid. `None` without `ignore_border`, with
+    /// `detect_phase`, or if no scale was detected.
+    pub border: Option<Border>,
+    /// The mat color whose runs didn't count, as given by the options.
+    pub mat_color: Option<[u8; 3]>,
+}
+
+/// Runs the whole detection on an image and returns all of its findings, the
+/// same as [`detect_border`], [`detect_scale_with`], [`detect_confidence`],
+/// and [`detect_phase`] as the options ask for.
+pub fn analyze(img: &DynamicImage, options: &DetectOptions) -> Analysis {
+    let solid_border = if options.ignore_border { detect_border([img], options) } else { Border::default() };
+    let interior = if solid_border.is_empty() {
+        Cow::Borrowed(img)
+    } else {
+        Cow::Owned(img.crop_imm(
+            solid_border.left,
+            solid_border.top,
+            img.width() - solid_border.left - solid_border.right,
+            img.height() - solid_border.top - solid_border.bottom,
+        ))
+    };
+    let (runs_x, runs_y) = count_runs(&interior, options);
+    let mat_color = options.mat_color;
+    let Some(scale) = detect_scale_with(&interior, options) else {
+        return Analysis { runs_x, runs_y, mat_color, ..Analysis::default() };
+    };
+    let confidence = Some(detect_confidence([interior.as_ref()], scale, options));
+    let (phase, border) = if options.detect_phase {
+        (detect_phase(img, scale, options), None)
+    } else {
+        let remainder = solid_border.remainder(scale);
+        ((remainder.left, remainder.top), options.ignore_border.then_some(remainder))
+    };
+    Analysis { runs_x, runs_y, scale: Some(scale), phase, confidence, border, mat_color }
+}
+
+/// Counts the runs of each length along the rows and along the columns. Runs
+/// are counted by the same rules as for the detection, but runs of a single
+/// pixel don't end the counting.
+pub fn count_runs(img: &DynamicImage, options: &DetectOptions) -> (Vec<u64>, Vec<u64>) {
+    let mut runs_x = vec![0; img.width() as usize + 1];
+    let mut runs_y = vec![0; img.height() as usize + 1];
+    for_each_run(img, options, false, |axis, stride| match axis {
+        Axis::X => runs_x[stride as usize] += 1,
+        Axis::Y => runs_y[stride as usize] += 1,
+    });
+    (runs_x, runs_y)
+}
+
+/// Bounding box of a sprite on a sprite sheet in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sprite {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Sprite {
+    fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+
+    /// Whether the two boxes overlap or touch, including diagonally.
+    fn touches(&self, other: &Sprite) -> bool {
+        self.x <= other.right() && other.x <= self.right() &&
+        self.y <= other.bottom() && other.y <= self.bottom()
+    }
+
+    fn union(&self, other: &Sprite) -> Sprite {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Sprite {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
+        }
+    }
+}
+
+/// Finds the sprites on a sprite sheet with a transparent background, i.e. the
+/// connected areas of pixels with an alpha value greater than
+/// `options.alpha_threshold`. Pixels touching diagonally are connected too.
+///
+/// Sprites whose bounding boxes overlap or touch are merged, so e.g. a
+/// detached eye doesn't become a sprite of its own. The sprites are returned in
+/// reading order, row by row from top to bottom and left to right in each row.
+pub fn find_sprites(img: &DynamicImage, options: &DetectOptions) -> Vec<Sprite> {
+    match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => find_sprites_of(&rgba, options),
+        RgbaPixels::Sixteen(rgba) => find_sprites_of(&rgba, options),
+    }
+}
+
+fn find_sprites_of<T: Channel>(rgba: &RgbaBuffer<T>, options: &DetectOptions) -> Vec<Sprite>
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let (width, height) = rgba.dimensions();
+    let mut seen = vec![false; width as usize * height as usize];
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        if !pixel[3].above(options.alpha_threshold) {
+            seen[y as usize * width as usize + x as usize] = true;
+        }
+    }
+
+    let mut sprites: Vec<Sprite> = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..seen.len() {
+        if seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        stack.push(start);
+        let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+        while let Some(index) = stack.pop() {
+            let (x, y) = ((index % width as usize) as u32, (index / width as usize) as u32);
+            (left, top, right, bottom) = (left.min(x), top.min(y), right.max(x), bottom.max(y));
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let neighbor = ny as usize * width as usize + nx as usize;
+                    if !seen[neighbor] {
+                        seen[neighbor] = true;
+                        stack.push(neighbor);
+                    }
+                }
+            }
+        }
+        let mut sprite = Sprite { x: left, y: top, width: right - left + 1, height: bottom - top + 1 };
+        // merging can make a box touch ones that were separate before
+        while let Some(index) = sprites.iter().position(|other| other.touches(&sprite)) {
+            sprite = sprite.union(&sprites.swap_remove(index));
+        }
+        sprites.push(sprite);
+    }
+
+    // Sprites in a row may start at different heights, so a row takes in
+    // every sprite that starts above its bottom.
+    sprites.sort_by_key(|sprite| (sprite.y, sprite.x));
+    let mut ordered = Vec::with_capacity(sprites.len());
+    let mut rest = sprites.as_slice();
+    while let Some(first) = rest.first() {
+        let mut bottom = first.bottom();
+        let mut len = 1;
+        while len < rest.len() && rest[len].y < bottom {
+            bottom = bottom.max(rest[len].bottom());
+            len += 1;
+        }
+        let mut row = rest[..len].to_vec();
+        row.sort_by_key(|sprite| sprite.x);
+        ordered.extend(row);
+        rest = &rest[len..];
+    }
+    ordered
+}
+
+/// How [`downscale_by_stride`] picks the color of each block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Sample {
+    /// Resize with a nearest neighbor filter. This picks pixels by a ratio,
+    /// which can land on the edge of a block.
+    Nearest,
+
+    /// Take the pixel at the center of each block.
+    #[default]
+    Center,
+
+    /// Take the most frequent color of each block. Ties are resolved in favor
+    /// of the center pixel. Useful for scanned or anti-aliased pixel art.
+    Majority,
+}
+
+/// Scales pixel art down by `scale_x` and `scale_y`, picking the color of each
+/// block as given by `sample`.
+///
+/// Partial blocks at the right and bottom edge are dropped. The result is
+/// always at least 1 x 1, even if the scale is bigger than the image.
+pub fn downscale_by_stride(img: &DynamicImage, scale_x: u32, scale_y: u32, sample: Sample) -> RgbaImage {
+    match img {
+        DynamicImage::ImageRgba8(img) => downscale_rgba_by_stride(img, scale_x, scale_y, sample),
+        img => downscale_rgba_by_stride(&img.to_rgba8(), scale_x, scale_y, sample),
+    }
+}
+
+/// Same as [`downscale_by_stride`], but images with 16 bits per channel are
+/// scaled down at full precision into an `ImageRgba16`, and grayscale images
+/// stay grayscale. Everything else becomes an `ImageRgba8`.
+pub fn downscale_dynamic_by_stride(img: &DynamicImage, scale_x: u32, scale_y: u32, sample: Sample) -> DynamicImage {
+    let scaled = match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => DynamicImage::ImageRgba8(downscale_rgba_by_stride(&rgba, scale_x, scale_y, sample)),
+        RgbaPixels::Sixteen(rgba) => DynamicImage::ImageRgba16(downscale_rgba_by_stride(&rgba, scale_x, scale_y, sample)),
+    };
+    // The gray channel was copied into red, green, and blue, so converting
+    // back gives exactly the gray levels of the input.
+    match img {
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLuma8(scaled.to_luma8()),
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLumaA8(scaled.to_luma_alpha8()),
+        DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma16(scaled.to_luma16()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLumaA16(scaled.to_luma_alpha16()),
+        _ => scaled,
+    }
+}
+
+fn downscale_rgba_by_stride<T: Channel>(rgba: &RgbaBuffer<T>, scale_x: u32, scale_y: u32, sample: Sample) -> RgbaBuffer<T>
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    if rgba.width() == 0 || rgba.height() == 0 {
+        return rgba.clone();
+    }
+    // A scale bigger than the image still leaves a single pixel.
+    let (width, height) = ((rgba.width() / scale_x).max(1), (rgba.height() / scale_y).max(1));
+    if sample == Sample::Nearest {
+        return image::imageops::resize(rgba, width, height, image::imageops::FilterType::Nearest);
+    }
+
+    let (offset_x, offset_y) = (scale_x / 2, scale_y / 2);
+    let mut counts: Vec<(Rgba<T>, u32)> = Vec::new();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (block_x, block_y) = (x * scale_x, y * scale_y);
+        let center = *rgba.get_pixel((block_x + offset_x).min(rgba.width() - 1), (block_y + offset_y).min(rgba.height() - 1));
+        if sample == Sample::Center {
+            return center;
+        }
+
+        counts.clear();
+        for y in block_y..(block_y + scale_y).min(rgba.height()) {
+            for x in block_x..(block_x + scale_x).min(rgba.width()) {
+                let color = *rgba.get_pixel(x, y);
+                match counts.iter_mut().find(|(other, _)| same_pixels(*other, color, 0, false)) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((color, 1)),
+                }
+            }
+        }
+        let max_count = counts.iter().map(|&(_, count)| count).max().unwrap_or(0);
+        if counts.contains(&(center, max_count)) {
+            return center;
+        }
+        counts.iter()
+            .find(|&&(_, count)| count == max_count)
+            .map_or(center, |&(color, _)| color)
+    })
+}
+
+/// Whether an image is too small to tell anything about its scaling. An
+/// image needs at least 2 x 2 pixels to have runs of pixels in both directions.
+pub fn too_small_to_detect(width: u32, height: u32) -> bool {
+    width < 2 || height < 2
+}
+
+/// The scale of the smallest stride, `None` if it isn't scaled at all, or if
+/// the stride is longer than the image along one of the axes, which would
+/// shrink it to less than a pixel.
+#[inline]
+fn stride_to_scale(stride: u32, width: u32, height: u32) -> Option<u32> {
+    if stride <= 1 || stride > width.min(height) { None } else { Some(stride) }
+}
+
+/// The run of pixels of a single color along a row or column so far.
+struct CurrentStride<T: Channel> {
+    color: Rgba<T>,
+    stride: u32,
+}
+
+impl<T: Channel> CurrentStride<T> {
+    #[inline]
+    fn new() -> Self {
+        CurrentStride {
+            color: Rgba([T::DEFAULT_MIN_VALUE; 4]),
+            stride: 0,
+        }
+    }
+
+    /// Continues the run with the pixel at `pos` of the row or column, or ends
+    /// the run and starts a new one. Returns `false` if the ended run is a
+    /// single pixel and the grid has to be strict, i.e. the image isn't
+    /// upscaled.
+    #[inline]
+    fn push(&mut self, color: Rgba<T>, pos: u32, options: &DetectOptions, strides: &mut [u64]) -> bool {
+        // Compare with the color the run started with, so slight
+        // variations don't add up over the length of the run.
+        if self.stride > 0 && same_color(color, self.color, options) {
+            self.stride += 1;
+            return true;
+        }
+        // The first run of a line is part of the border if it is ignored.
+        if (!ignores_border(options) || pos > self.stride) && !self.record(options, strides) {
+            return false;
+        }
+        self.stride = 1;
+        self.color  = color;
+        true
+    }
+
+    /// Ends the run at the end of the row or column of length `len`. A run
+    /// over the whole line isn't bounded by any change of color, so it says
+    /// nothing about the scale, e.g. in flat areas of images with few colors.
+    #[inline]
+    fn finish(&self, len: u32, options: &DetectOptions, strides: &mut [u64]) -> bool {
+        ignores_border(options) || self.stride == len || self.record(options, strides)
+    }
+
+    #[inline]
+    fn record(&self, options: &DetectOptions, strides: &mut [u64]) -> bool {
+        // A single pixel speaks against any scale, regardless of its alpha.
+        if self.stride == 1 {
+            if options.grid_agreement.is_none() {
+                return false;
+            }
+            strides[1] += 1;
+        } else if self.stride > 0 && counts(self.color, options) {
+            strides[self.stride as usize] += 1;
+        }
+        true
+    }
+}
+
+/// Partial pixels at the edges are just like a border that doesn't fit the grid.
+#[inline]
+fn ignores_border(options: &DetectOptions) -> bool {
+    options.ignore_border || options.detect_phase
+}
+
+#[inline]
+fn get_smallest_stride(img: &DynamicImage, options: &DetectOptions) -> u32 {
+    // before either scan, so the parallel one gives up on the same images
+    if too_small_to_detect(img.width(), img.height()) {
+        return 1;
+    }
+    let mut strides = vec![0; img.width().max(img.height()) as usize + 1];
+    let upscaled = match options.threads {
+        None => get_smallest_stride_phase1(img, &mut strides, options),
+        Some(0) => get_smallest_stride_phase1_parallel(img, &mut strides, options),
+        Some(threads) => match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+            Ok(pool) => pool.install(|| get_smallest_stride_phase1_parallel(img, &mut strides, options)),
+            Err(_) => get_smallest_stride_phase1(img, &mut strides, options),
+        },
+    };
+    if !upscaled {
+        return 1;
+    }
+    get_smallest_stride_phase2(&strides, options)
+}
+
+/// Whether a run of this color counts towards the detection. Transparent runs
+/// and runs of the mat color don't.
+#[inline]
+fn counts<T: Channel>(color: Rgba<T>, options: &DetectOptions) -> bool {
+    (options.include_transparent || color[3].above(options.alpha_threshold)) &&
+        !options.mat_color.is_some_and(|mat| color.0[..3].iter().zip(mat).all(|(&channel, mat)| channel.near(mat, options.tolerance)))
+}
+
+#[inline]
+fn same_color<T: Channel>(a: Rgba<T>, b: Rgba<T>, options: &DetectOptions) -> bool {
+    same_pixels(a, b, options.tolerance, options.ignore_alpha)
+}
+
+/// Makes [`same_pixels`] compare channel by channel, like before whole 8 bit
+/// pixels were compared at once, so the benchmark can time both.
+#[cfg(test)]
+static COMPARE_BY_CHANNEL: AtomicBool = AtomicBool::new(false);
+
+/// [`Channel::same`], unless the benchmark wants it channel by channel.
+#[inline]
+fn same_pixels<T: Channel>(a: Rgba<T>, b: Rgba<T>, tolerance: u8, ignore_alpha: bool) -> bool {
+    #[cfg(test)]
+    if COMPARE_BY_CHANNEL.load(Ordering::Relaxed) {
+        return same_by_channel(a, b, tolerance, ignore_alpha);
+    }
+    T::same(a, b, tolerance, ignore_alpha)
+}
+
+fn get_smallest_stride_phase1(img: &DynamicImage, strides: &mut [u64], options: &DetectOptions) -> bool {
+    if too_small_to_detect(img.width(), img.height()) {
+        return false;
+    }
+    // Going through get_pixel() of DynamicImage for every pixel is slow, so
+    // iterate over the raw buffer instead. get_pixel() converts to RGBA too,
+    // so this yields the same colors, except that 16 bit channels are kept.
+    match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => get_smallest_stride_phase1_of(&rgba, strides, options),
+        RgbaPixels::Sixteen(rgba) => get_smallest_stride_phase1_of(&rgba, strides, options),
+    }
+}
+
+fn get_smallest_stride_phase1_of<T: Channel>(rgba: &RgbaBuffer<T>, strides: &mut [u64], options: &DetectOptions) -> bool
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let row_len = rgba.width() as usize * 4;
+    if row_len == 0 {
+        return true;
+    }
+
+    let mut scanner = RowScanner::new(rgba.width());
+    rgba.as_raw().chunks_exact(row_len).all(|row| scanner.scan_row(row.chunks_exact(4).map(to_rgba), options, strides)) &&
+        scanner.finish(options, strides)
+}
+
+/// Scans an image row by row. The runs along the rows are complete with each
+/// row, the runs along the columns are carried over from row to row.
+struct RowScanner<T: Channel> {
+    curr_y: Vec<CurrentStride<T>>,
+    width: u32,
+    height: u32,
+}
+
+impl<T: Channel> RowScanner<T> {
+    fn new(width: u32) -> Self {
+        RowScanner {
+            curr_y: (0..width).map(|_| CurrentStride::new()).collect(),
+            width,
+            height: 0,
+        }
+    }
+
+    /// Records the runs of the next row. `strides` has to be longer than the
+    /// number of rows so far. Returns `false` if the image isn't upscaled.
+    #[inline]
+    fn scan_row(&mut self, row: impl Iterator<Item=Rgba<T>>, options: &DetectOptions, strides: &mut [u64]) -> bool {
+        let y = self.height;
+        self.height += 1;
+        let mut curr_x = CurrentStride::new();
+        for (x, color) in row.enumerate() {
+            if !curr_x.push(color, x as u32, options, strides) || !self.curr_y[x].push(color, y, options, strides) {
+                return false;
+            }
+        }
+        curr_x.finish(self.width, options, strides)
+    }
+
+    /// Records the last runs of the columns.
+    fn finish(&self, options: &DetectOptions, strides: &mut [u64]) -> bool {
+        self.curr_y.iter().all(|curr_y| curr_y.finish(self.height, options, strides))
+    }
+}
+
+/// Detects the scale of an image that is fed row by row, e.g. while it is
+/// being decoded, so it never has to be in memory as a whole. Gives the same
+/// result as [`detect_scale_with`] on the whole image, as long as it has 8 bits
+/// per channel.
+pub struct StrideDetector {
+    options: DetectOptions,
+    scanner: RowScanner<u8>,
+    strides: Vec<u64>,
+    upscaled: bool,
+}
+
+impl StrideDetector {
+    /// Creates a detector for an image `width` pixels wide. `options.threads`
+    /// is ignored, the rows are scanned on the thread that feeds them.
+    pub fn new(width: u32, options: DetectOptions) -> Self {
+        StrideDetector {
+            options,
+            scanner: RowScanner::new(width),
+            strides: vec![0; width as usize + 1],
+            upscaled: true,
+        }
+    }
+
+    /// Scans the next row of the image.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the length of the row isn't the width given to [`StrideDetector::new`].
+    pub fn feed_row(&mut self, row: &[Rgba<u8>]) {
+        assert_eq!(row.len(), self.scanner.width as usize, "length of the row doesn't match the width");
+        if !self.upscaled {
+            // nothing can change that anymore
+            return;
+        }
+        // the runs along the columns are as long as the image is high so far
+        let len = self.scanner.height as usize + 2;
+        if self.strides.len() < len {
+            self.strides.resize(len, 0);
+        }
+        self.upscaled = self.scanner.scan_row(row.iter().copied(), &self.options, &mut self.strides);
+    }
+
+    /// Returns the detected scale, or `None` if the image isn't upscaled, like
+    /// [`detect_scale_with`].
+    pub fn finish(mut self) -> Option<u32> {
+        if too_small_to_detect(self.scanner.width, self.scanner.height) || !self.upscaled ||
+           !self.scanner.finish(&self.options, &mut self.strides) {
+            return None;
+        }
+        stride_to_scale(get_smallest_stride_phase2(&self.strides, &self.options), self.scanner.width, self.scanner.height)
+    }
+}
+
+/// Same as [`get_smallest_stride_phase1()`], but the rows and then the columns
+/// are scanned in parallel. Scanning a column on its own is slower than
+/// scanning all columns along the rows at once, so this only pays off with
+/// multiple threads.
+fn get_smallest_stride_phase1_parallel(img: &DynamicImage, strides: &mut [u64], options: &DetectOptions) -> bool {
+    match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => get_smallest_stride_phase1_parallel_of(&rgba, strides, options),
+        RgbaPixels::Sixteen(rgba) => get_smallest_stride_phase1_parallel_of(&rgba, strides, options),
+    }
+}
+
+fn get_smallest_stride_phase1_parallel_of<T: Channel>(rgba: &RgbaBuffer<T>, strides: &mut [u64], options: &DetectOptions) -> bool
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let (width, height) = rgba.dimensions();
+    if width == 0 {
+        return true;
+    }
+    let len = strides.len();
+    let scan = |lines: u32, line_len: u32, pixel: &(dyn Fn(u32, u32) -> Rgba<T> + Sync)| {
+        (0..lines).into_par_iter()
+            .try_fold(|| vec![0; len], |mut counts, line| {
+                let mut curr = CurrentStride::new();
+                for pos in 0..line_len {
+                    if !curr.push(pixel(line, pos), pos, options, &mut counts) {
+                        return None;
+                    }
+                }
+                curr.finish(line_len, options, &mut counts).then_some(counts)
+            })
+            .try_reduce(|| vec![0; len], |mut counts, other| {
+                for (count, other_count) in counts.iter_mut().zip(other) {
+                    *count += other_count;
+                }
+                Some(counts)
+            })
+    };
+    let Some(rows) = scan(height, width, &|y, x| *rgba.get_pixel(x, y)) else {
+        return false;
+    };
+    let Some(columns) = scan(width, height, &|x, y| *rgba.get_pixel(x, y)) else {
+        return false;
+    };
+    for (count, (row_count, column_count)) in strides.iter_mut().zip(rows.into_iter().zip(columns)) {
+        *count += row_count + column_count;
+    }
+    true
+}
+
+/// Which way a run of pixels goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+    /// Along a row.
+    X,
+    /// Along a column.
+    Y,
+}
+
+/// Calls `record` with the direction and length of every run of pixels of the
+/// image that counts by the rules of the detection. Colors are compared
+/// exactly or as given by the options.
+fn for_each_run(img: &DynamicImage, options: &DetectOptions, exact: bool, record: impl FnMut(Axis, u32)) {
+    match rgba_pixels(img) {
+        RgbaPixels::Eight(rgba) => for_each_run_of(&rgba, options, exact, record),
+        RgbaPixels::Sixteen(rgba) => for_each_run_of(&rgba, options, exact, record),
+    }
+}
+
+fn for_each_run_of<T: Channel>(rgba: &RgbaBuffer<T>, options: &DetectOptions, exact: bool, mut record: impl FnMut(Axis, u32))
+where
+    Rgba<T>: Pixel<Subpixel = T>,
+{
+    let ignore_border = options.ignore_border || options.detect_phase;
+    let same = |a: Rgba<T>, b: Rgba<T>| if exact { a == b } else { same_color(a, b, options) };
+    let mut record = |axis: Axis, color: Rgba<T>, stride: u32| {
+        if counts(color, options) {
+            record(axis, stride);
+        }
+    };
+
+    let row_len = rgba.width() as usize * 4;
+    if row_len == 0 {
+        return;
+    }
+
+    // color and length of the current run of each column
+    let mut curr_y: Vec<(Rgba<T>, u32)> = Vec::with_capacity(rgba.width() as usize);
+    for (y, row) in rgba.as_raw().chunks_exact(row_len).enumerate() {
+        let y = y as u32;
+        let mut curr_x = (to_rgba(&row[..4]), 0);
+        for (x, pixel) in row.chunks_exact(4).enumerate() {
+            let x = x as u32;
+            let color = to_rgba(pixel);
+            if same(color, curr_x.0) {
+                curr_x.1 += 1;
+            } else {
+                if !ignore_border || x > curr_x.1 {
+                    record(Axis::X, curr_x.0, curr_x.1);
+                }
+                curr_x = (color, 1);
+            }
+
+            if y == 0 {
+                curr_y.push((color, 1));
+            } else {
+                let curr_y = &mut curr_y[x as usize];
+                if same(color, curr_y.0) {
+                    curr_y.1 += 1;
+                } else {
+                    if !ignore_border || y > curr_y.1 {
+                        record(Axis::Y, curr_y.0, curr_y.1);
+                    }
+                    *curr_y = (color, 1);
+                }
+            }
+        }
+        // like CurrentStride::finish(), a run over the whole line doesn't count
+        if !ignore_border && curr_x.1 < rgba.width() {
+            record(Axis::X, curr_x.0, curr_x.1);
+        }
+    }
+
+    if !ignore_border {
+        for &(color, stride) in &curr_y {
+            if stride < rgba.height() {
+                record(Axis::Y, color, stride);
+            }
+        }
+    }
+}
+
+/// `strides` holds the number of runs of each length.
+fn get_smallest_stride_phase2(strides: &[u64], options: &DetectOptions) -> u32 {
+    let stride = match (options.strategy, options.grid_agreement) {
+        (Strategy::Gcd, None) => get_strict_stride(strides),
+        (Strategy::Gcd, Some(agreement)) => get_agreeing_stride(strides, agreement, options),
+        (Strategy::Mode, _) => get_most_common_stride(strides),
+        (Strategy::Min, _) => get_shortest_stride(strides, options),
+    };
+
+    if options.min_stride.is_some_and(|min| stride < min) || options.max_stride.is_some_and(|max| stride > max) {
+        return 1;
+    }
+
+    stride
+}
+
+/// The greatest common divisor of all runs. Unlike taking the shortest run
+/// as the unit, this also finds the scale if no run is a single pixel of the
+/// pixel art, e.g. runs of 6 and 9 at a scale of 3.
+fn get_strict_stride(strides: &[u64]) -> u32 {
+    let mut divisor = 0;
+    for (stride, &count) in strides.iter().enumerate().skip(1) {
+        if count == 0 {
+            continue;
+        }
+        divisor = gcd(divisor, stride as u32);
+        if divisor == 1 {
+            return 1;
+        }
+    }
+    divisor.max(1)
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// The largest stride that at least `agreement` percent of the runs are
+/// multiples of. Any multiple of the scale is also a multiple of its divisors,
+/// so the largest agreeing stride is the scale itself.
+fn get_agreeing_stride(strides: &[u64], agreement: u8, options: &DetectOptions) -> u32 {
+    let total: u64 = strides.iter().sum();
+    if total == 0 {
+        return 1;
+    }
+
+    let min = options.min_stride.map_or(2, |min| min.max(2)) as usize;
+    let max = options.max_stride.map_or(strides.len() - 1, |max| (max as usize).min(strides.len() - 1));
+    let required = total * u64::from(agreement.min(100));
+    (min..=max).rev()
+        .find(|&stride| strides.iter().step_by(stride).sum::<u64>() * 100 >= required)
+        .map_or(1, |stride| stride as u32)
+}
+
+/// The most common run, the shorter one of equally common runs.
+fn get_most_common_stride(strides: &[u64]) -> u32 {
+    let mut most_common = (1, 0);
+    for (stride, &count) in strides.iter().enumerate().skip(1) {
+        if count > most_common.1 {
+            most_common = (stride as u32, count);
+        }
+    }
+    most_common.0
+}
+
+/// The shortest run of at least two pixels, unless there are more runs of a
+/// single pixel than `options.grid_agreement` allows.
+fn get_shortest_stride(strides: &[u64], options: &DetectOptions) -> u32 {
+    let total: u64 = strides.iter().sum();
+    let allowed = options.grid_agreement.map_or(0, |agreement| total * u64::from(100 - agreement.min(100)));
+    if strides.get(1).is_some_and(|&count| count * 100 > allowed) {
+        return 1;
+    }
+    strides.iter().enumerate().skip(2)
+        .find(|&(_, &count)| count > 0)
+        .map_or(1, |(stride, _)| stride as u32)
+}
+
+fn get_smallest_stride_from_animation<'a>(width: u32, height: u32, frames: impl Iterator<Item=&'a DynamicImage>, options: &DetectOptions) -> u32 {
+    if options.threads == Some(0) {
+        let frames = frames.collect::<Vec<_>>();
+        return get_smallest_stride_from_animation_parallel(width, height, &frames, options);
+    }
+    if let Some(threads) = options.threads {
+        if let Ok(pool) = rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+            let frames = frames.collect::<Vec<_>>();
+            return pool.install(|| get_smallest_stride_from_animation_parallel(width, height, &frames, options));
+        }
+    }
+
+    let mut strides = vec![0; width.max(height) as usize + 1];
+    for frame in frames {
+        if !get_smallest_stride_phase1(frame, &mut strides, options) {
+            return 1;
+        }
+    }
+
+    get_smallest_stride_phase2(&strides, options)
+}
+
+/// Each thread collects the strides of its frames on its own, which are then
+/// merged. If any frame isn't upscaled the other threads skip their remaining
+/// frames.
+fn get_smallest_stride_from_animation_parallel(width: u32, height: u32, frames: &[&DynamicImage], options: &DetectOptions) -> u32 {
+    let len = width.max(height) as usize + 1;
+    let failed = AtomicBool::new(false);
+    let strides = frames.par_iter()
+        .fold(|| vec![0; len], |mut strides, frame| {
+            if !failed.load(Ordering::Relaxed) && !get_smallest_stride_phase1(frame, &mut strides, options) {
+                failed.store(true, Ordering::Relaxed);
+            }
+            strides
+        })
+        .reduce(|| vec![0; len], |mut strides, other| {
+            for (count, other_count) in strides.iter_mut().zip(other) {
+                *count += other_count;
+            }
+            strides
+        });
+
+    if failed.load(Ordering::Relaxed) {
+        return 1;
+    }
+
+    get_smallest_stride_phase2(&strides, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::*;
+    use image::imageops;
+
+    /// Phase 1 the way it was before it went over the raw buffer: every pixel
+    /// through get_pixel() of DynamicImage, rows first and then columns.
+    fn get_smallest_stride_phase1_by_get_pixel(img: &DynamicImage, strides: &mut [u64], options: &DetectOptions) -> bool {
+        if too_small_to_detect(img.width(), img.height()) {
+            return false;
+        }
+        for y in 0..img.height() {
+            let mut curr = CurrentStride::<u8>::new();
+            for x in 0..img.width() {
+                if !curr.push(img.get_pixel(x, y), x, options, strides) {
+                    return false;
+                }
+            }
+            if !curr.finish(img.width(), options, strides) {
+                return false;
+            }
+        }
+        for x in 0..img.width() {
+            let mut curr = CurrentStride::<u8>::new();
+            for y in 0..img.height() {
+                if !curr.push(img.get_pixel(x, y), y, options, strides) {
+                    return false;
+                }
+            }
+            if !curr.finish(img.height(), options, strides) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// 8 bit images of different color types, upscaled or not, with noise and
+    /// with a border.
+    fn test_images() -> Vec<DynamicImage> {
+        let art = native_art(12, 9, 1);
+        let mut noisy = upscale(&art, 3);
+        add_noise(&mut noisy, 3, 2);
+        let mut bordered = RgbaImage::from_pixel(22, 22, Rgba([255, 0, 255, 255]));
+        imageops::replace(&mut bordered, &upscale(&native_art(10, 10, 3), 2), 1, 1);
+        vec![
+            DynamicImage::ImageRgba8(upscale(&art, 3)),
+            DynamicImage::ImageRgba8(art.clone()),
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(upscale(&art, 2)).to_rgb8()),
+            DynamicImage::ImageLuma8(DynamicImage::ImageRgba8(upscale(&native_art(7, 11, 4), 4)).to_luma8()),
+            DynamicImage::ImageLumaA8(DynamicImage::ImageRgba8(upscale(&art, 5)).to_luma_alpha8()),
+            DynamicImage::ImageRgba8(noisy),
+            DynamicImage::ImageRgba8(bordered),
+        ]
+    }
+
+    fn test_options() -> Vec<DetectOptions> {
+        vec![
+            DetectOptions::default(),
+            DetectOptions { tolerance: 8, ..DetectOptions::default() },
+            DetectOptions { ignore_border: true, ..DetectOptions::default() },
+            DetectOptions { ignore_alpha: true, include_transparent: true, ..DetectOptions::default() },
+            DetectOptions { grid_agreement: Some(90), tolerance: 8, ..DetectOptions::default() },
+            DetectOptions { alpha_threshold: 200, mat_color: Some([0, 0, 0]), ..DetectOptions::default() },
+        ]
+    }
+
+    #[test]
+    fn raw_buffer_scan_matches_get_pixel() {
+        for (index, img) in test_images().iter().enumerate() {
+            for options in test_options() {
+                let len = img.width().max(img.height()) as usize + 1;
+                let (mut expected, mut actual) = (vec![0; len], vec![0; len]);
+                let upscaled = get_smallest_stride_phase1_by_get_pixel(img, &mut expected, &options);
+                assert_eq!(get_smallest_stride_phase1(img, &mut actual, &options), upscaled, "image {index}, {options:?}");
+                if upscaled {
+                    assert_eq!(actual, expected, "image {index}, {options:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_scan_matches_serial() {
+        let mut images = test_images();
+        images.push(DynamicImage::ImageRgba16(DynamicImage::ImageRgba8(upscale(&native_art(9, 6, 5), 3)).to_rgba16()));
+        // too small, but with runs of 3 along the long side
+        images.push(DynamicImage::ImageRgba8(upscale(&native_art(1, 2, 6), 3).view(0, 0, 1, 6).to_image()));
+        images.push(DynamicImage::ImageRgba8(upscale(&native_art(2, 1, 6), 3).view(0, 0, 6, 1).to_image()));
+        images.push(DynamicImage::ImageRgba8(RgbaImage::new(0, 0)));
+        for (index, img) in images.iter().enumerate() {
+            for options in test_options() {
+                for threads in [Some(0), Some(3)] {
+                    let threaded = DetectOptions { threads, ..options.clone() };
+                    assert_eq!(get_smallest_stride(img, &threaded), get_smallest_stride(img, &options), "image {index}, {threaded:?}");
+                }
+                if too_small_to_detect(img.width(), img.height()) {
+                    // get_smallest_stride() doesn't get to either scan
+                    continue;
+                }
+                let len = img.width().max(img.height()) as usize + 1;
+                let (mut serial, mut parallel) = (vec![0; len], vec![0; len]);
+                let upscaled = get_smallest_stride_phase1(img, &mut serial, &options);
+                assert_eq!(get_smallest_stride_phase1_parallel(img, &mut parallel, &options), upscaled, "image {index}, {options:?}");
+                if upscaled {
+                    assert_eq!(parallel, serial, "image {index}, {options:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tiny_images_are_too_small_to_detect() {
+        for (width, height) in [(1, 1), (1, 6), (6, 1), (0, 0)] {
+            assert!(too_small_to_detect(width, height), "{width} x {height}");
+            let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 255])));
+            assert_eq!(detect_scale_with(&img, &DetectOptions::default()), None, "{width} x {height}");
+            assert_eq!(detect_scale_with(&img, &DetectOptions { grid_agreement: Some(90), ..DetectOptions::default() }), None, "{width} x {height}");
+            let (runs_x, runs_y) = count_runs(&img, &DetectOptions::default());
+            assert!(runs_x.iter().chain(&runs_y).all(|&count| count == 0), "{width} x {height}");
+        }
+        assert!(!too_small_to_detect(2, 2));
+    }
+
+    #[test]
+    fn downscale_clamps_to_one_pixel() {
+        let column = DynamicImage::ImageRgba8(RgbaImage::from_fn(1, 6, |_, y| Rgba([y as u8 / 3 * 100, 0, 0, 255])));
+        let row = DynamicImage::ImageRgba8(RgbaImage::from_fn(6, 1, |x, _| Rgba([0, x as u8 / 3 * 100, 0, 255])));
+        let pixel = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([0, 0, 255, 255])));
+        for sample in [Sample::Nearest, Sample::Center, Sample::Majority] {
+            let scaled = downscale_by_stride(&column, 3, 3, sample);
+            assert_eq!(scaled.dimensions(), (1, 2), "{sample:?}");
+            assert_eq!(scaled.as_raw(), &[0, 0, 0, 255, 100, 0, 0, 255], "{sample:?}");
+            let scaled = downscale_by_stride(&row, 3, 3, sample);
+            assert_eq!(scaled.dimensions(), (2, 1), "{sample:?}");
+            assert_eq!(scaled.as_raw(), &[0, 0, 0, 255, 0, 100, 0, 255], "{sample:?}");
+            assert_eq!(downscale_by_stride(&pixel, 4, 4, sample), pixel.to_rgba8(), "{sample:?}");
+        }
+    }
+
+    #[test]
+    fn checkerboard_is_detected() {
+        let checkerboard = RgbaImage::from_fn(8, 8, |x, y| if (x + y) % 2 == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255; 4]) });
+        let img = DynamicImage::ImageRgba8(upscale(&checkerboard, 4));
+        for strategy in [Strategy::Gcd, Strategy::Mode, Strategy::Min] {
+            assert_eq!(detect_scale_with(&img, &DetectOptions { strategy, ..DetectOptions::default() }), Some(4), "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn whole_line_runs_are_skipped() {
+        let mut native = RgbaImage::from_pixel(64, 64, Rgba([255; 4]));
+        native.put_pixel(10, 20, Rgba([0, 0, 0, 255]));
+        let img = DynamicImage::ImageRgba8(upscale(&native, 4));
+        for strategy in [Strategy::Gcd, Strategy::Mode, Strategy::Min] {
+            assert_eq!(detect_scale_with(&img, &DetectOptions { strategy, ..DetectOptions::default() }), Some(4), "{strategy:?}");
+        }
+
+        // only the rows and columns through the black pixel have runs
+        let (runs_x, runs_y) = count_runs(&img, &DetectOptions::default());
+        let runs = |runs: &[u64]| runs.iter().enumerate().filter(|&(_, &count)| count > 0).map(|(len, &count)| (len, count)).collect::<Vec<_>>();
+        assert_eq!(runs(&runs_x), [(4, 4), (40, 4), (212, 4)]);
+        assert_eq!(runs(&runs_y), [(4, 4), (80, 4), (172, 4)]);
+
+        let flat = DynamicImage::ImageRgba8(RgbaImage::from_pixel(32, 32, Rgba([255; 4])));
+        for strategy in [Strategy::Gcd, Strategy::Mode, Strategy::Min] {
+            assert_eq!(detect_scale_with(&flat, &DetectOptions { strategy, ..DetectOptions::default() }), None, "{strategy:?}");
+        }
+    }
+
+    /// Only runs of `len` in the histogram of runs.
+    fn only_runs_of(runs: &[u64], len: usize) -> bool {
+        runs.iter().enumerate().all(|(run, &count)| (run == len) == (count > 0))
+    }
+
+    #[test]
+    fn analyze_upscaled_art() {
+        let native = native_art(4, 5, 6);
+        let img = DynamicImage::ImageRgba8(upscale(&native, 3));
+        let analysis = analyze(&img, &DetectOptions::default());
+
+        // every visible pixel is a run of 3 along the 3 rows and the 3 columns it was scaled to
+        let visible = native.pixels().filter(|pixel| pixel[3] > 0).count() as u64;
+        let mut runs_x = vec![0; 13];
+        runs_x[3] = visible * 3;
+        let mut runs_y = vec![0; 16];
+        runs_y[3] = visible * 3;
+        assert_eq!(analysis, Analysis { runs_x, runs_y, scale: Some(3), phase: (0, 0), confidence: Some(1.0), border: None, mat_color: None });
+    }
+
+    #[test]
+    fn analyze_native_art() {
+        let native = native_art(8, 6, 7);
+        let analysis = analyze(&DynamicImage::ImageRgba8(native.clone()), &DetectOptions::default());
+        let visible = native.pixels().filter(|pixel| pixel[3] > 0).count() as u64;
+        assert_eq!(analysis.scale, None);
+        assert_eq!(analysis.phase, (0, 0));
+        assert_eq!(analysis.confidence, None);
+        assert_eq!(analysis.runs_x[1], visible);
+        assert_eq!(analysis.runs_y[1], visible);
+        assert!(only_runs_of(&analysis.runs_x, 1) && only_runs_of(&analysis.runs_y, 1));
+    }
+
+    #[test]
+    fn analyze_phase_and_border() {
+        let art = upscale(&native_art(4, 4, 8), 3);
+        // the first column and the last row of pixels are partial
+        let shifted = DynamicImage::ImageRgba8(art.view(1, 0, 11, 10).to_image());
+        let analysis = analyze(&shifted, &DetectOptions { detect_phase: true, ..DetectOptions::default() });
+        assert_eq!((analysis.scale, analysis.phase, analysis.border), (Some(3), (2, 0), None));
+
+        let mut bordered = RgbaImage::from_pixel(16, 17, Rgba([255, 0, 255, 255]));
+        imageops::replace(&mut bordered, &art, 2, 1);
+        let analysis = analyze(&DynamicImage::ImageRgba8(bordered), &DetectOptions { ignore_border: true, ..DetectOptions::default() });
+        assert_eq!(analysis.scale, Some(3));
+        assert_eq!(analysis.phase, (2, 1));
+        assert_eq!(analysis.border, Some(Border { left: 2, top: 1, right: 2, bottom: 1 }));
+        assert!(only_runs_of(&analysis.runs_x, 3) && only_runs_of(&analysis.runs_y, 3));
+    }
+
+    /// A histogram of runs with `count` runs of each length.
+    fn histogram(runs: &[(usize, u64)]) -> Vec<u64> {
+        let mut strides = vec![0; 16];
+        for &(len, count) in runs {
+            strides[len] = count;
+        }
+        strides
+    }
+
+    #[test]
+    fn strict_stride_is_gcd_of_runs() {
+        assert_eq!(gcd(6, 9), 3);
+        assert_eq!(gcd(0, 6), 6);
+        assert_eq!(get_strict_stride(&histogram(&[(6, 10), (9, 4)])), 3);
+        assert_eq!(get_strict_stride(&histogram(&[(3, 1), (6, 20)])), 3);
+        assert_eq!(get_strict_stride(&histogram(&[(12, 5)])), 12);
+        assert_eq!(get_strict_stride(&histogram(&[])), 1);
+
+        // a single stray run of 2 leaves no common divisor, grid agreement tolerates it
+        let stray = histogram(&[(2, 1), (3, 40), (6, 60)]);
+        assert_eq!(get_smallest_stride_phase2(&stray, &DetectOptions::default()), 1);
+        assert_eq!(get_smallest_stride_phase2(&stray, &DetectOptions { grid_agreement: Some(95), ..DetectOptions::default() }), 3);
+    }
+
+    #[test]
+    fn runs_of_6_and_9_are_scale_3() {
+        // blocks of 2 and 3 pixels, so no run of the upscaled image is a single pixel of the art
+        let block = |pos: u32| (pos % 5 >= 2) as u32;
+        let native = RgbaImage::from_fn(10, 10, |x, y| Rgba(PALETTE[(block(x) + block(y) * 2 + x / 5 * 3) as usize % 5]));
+        let img = DynamicImage::ImageRgba8(upscale(&native, 3));
+        let (runs_x, runs_y) = count_runs(&img, &DetectOptions::default());
+        let lengths = |runs: &[u64]| runs.iter().enumerate().filter(|&(_, &count)| count > 0).map(|(len, _)| len).collect::<Vec<_>>();
+        assert_eq!(lengths(&runs_x), [6, 9]);
+        assert_eq!(lengths(&runs_y), [6, 9]);
+        assert_eq!(detect_scale_with(&img, &DetectOptions::default()), Some(3));
+        assert_eq!(detect_scale_with(&img, &DetectOptions { grid_agreement: Some(95), ..DetectOptions::default() }), Some(3));
+    }
+
+    const TOLERANCES: [u8; 9] = [0, 1, 2, 7, 8, 127, 128, 254, 255];
+
+    #[test]
+    fn u8_lanes_match_channel_by_channel() {
+        // every pair of values in each channel, the other channels equal
+        for tolerance in TOLERANCES {
+            for channel in 0..4 {
+                for a in 0..=255 {
+                    for b in 0..=255 {
+                        let (mut x, mut y) = (Rgba([90, 160, 30, 255]), Rgba([90, 160, 30, 255]));
+                        (x.0[channel], y.0[channel]) = (a, b);
+                        for ignore_alpha in [false, true] {
+                            assert_eq!(u8::same(x, y, tolerance, ignore_alpha), same_by_channel(x, y, tolerance, ignore_alpha), "{x:?} {y:?} {tolerance} {ignore_alpha}");
+                        }
+                    }
+                }
+            }
+        }
+
+        // random pixels, and pixels that differ by a little in random channels
+        let mut random = Random(0x5eed);
+        for _ in 0..200_000 {
+            let x = Rgba((random.next() as u32).to_ne_bytes());
+            let deltas = random.next().to_ne_bytes();
+            let near = Rgba(std::array::from_fn(|channel| x.0[channel].wrapping_add(deltas[channel] % 21).wrapping_sub(10)));
+            let far = Rgba((random.next() as u32).to_ne_bytes());
+            let tolerance = match random.next() % 3 {
+                0 => TOLERANCES[random.next() as usize % TOLERANCES.len()],
+                _ => random.next() as u8,
+            };
+            for y in [near, far] {
+                for ignore_alpha in [false, true] {
+                    assert_eq!(u8::same(x, y, tolerance, ignore_alpha), same_by_channel(x, y, tolerance, ignore_alpha), "{x:?} {y:?} {tolerance} {ignore_alpha}");
+                }
+            }
+        }
+    }
+
+    /// Times the comparison of 8 bit pixels in lanes against the one channel by
+    /// channel, on its own and in phase 1 of the detection and the majority
+    /// vote of [`downscale_by_stride`]. Phase 1 is also timed the way it was
+    /// before it went over the raw buffer. Run it with:
+    /// cargo test --release --lib -- --ignored --nocapture same_color_speed
+    ///
+    /// On one core of an Intel Xeon VM it gave, best of 7 runs, on 3600 x 3600
+    /// pixels of art upscaled by 3, or 3 x 2^20 pixels for the comparison alone:
+    ///
+    /// | what                    | get_pixel() | by channel | lanes  |
+    /// |-------------------------|-------------|------------|--------|
+    /// | comparison, exact       |             | 51 ms      | 6.8 ms |
+    /// | comparison, tolerance 8 |             | 23 ms      | 12 ms  |
+    /// | phase 1, exact          | 453 ms      | 379 ms     | 141 ms |
+    /// | phase 1, ignore alpha   | 471 ms      | 368 ms     | 120 ms |
+    /// | phase 1, tolerance 8    | 278 ms      | 158 ms     | 140 ms |
+    /// | majority of 3 x 3       |             | 43 ms      | 44 ms  |
+    ///
+    /// The majority vote gains nothing, comparing two `[u8; 4]` already is a
+    /// single u32 compare, and counting the colors takes most of the time.
+    #[test]
+    #[ignore]
+    fn same_color_speed() {
+        use std::hint::black_box;
+        use std::time::{Duration, Instant};
+
+        fn best_of(runs: usize, mut f: impl FnMut()) -> Duration {
+            (0..runs).map(|_| {
+                let start = Instant::now();
+                f();
+                start.elapsed()
+            }).min().unwrap_or_default()
+        }
+
+        // runs of 3 like upscaled pixel art, with noise for the tolerance
+        let mut random = Random(0xbe4c);
+        let mut pixels = Vec::with_capacity(3 << 20);
+        for _ in 0..1 << 20 {
+            let pixel = (random.next() as u32).to_ne_bytes();
+            for _ in 0..3 {
+                let noise = random.next().to_ne_bytes();
+                pixels.push(Rgba(std::array::from_fn(|channel| pixel[channel].saturating_add(noise[channel] % 4))));
+            }
+        }
+        for (tolerance, ignore_alpha) in [(0, false), (0, true), (8, false)] {
+            let count = |same: fn(Rgba<u8>, Rgba<u8>, u8, bool) -> bool| best_of(7, || {
+                black_box(pixels.windows(2).filter(|pair| same(pair[0], pair[1], black_box(tolerance), black_box(ignore_alpha))).count());
+            });
+            println!("comparison, tolerance {tolerance}, ignore alpha {ignore_alpha}: by channel {:?}, lanes {:?}",
+                count(same_by_channel::<u8>), count(u8::same));
+        }
+
+        let img = DynamicImage::ImageRgba8(upscale(&native_art(1200, 1200, 9), 3));
+        let mut noisy = upscale(&native_art(1200, 1200, 9), 3);
+        add_noise(&mut noisy, 3, 10);
+        let noisy = DynamicImage::ImageRgba8(noisy);
+        let by_channel = |by_channel: bool, f: &mut dyn FnMut()| {
+            COMPARE_BY_CHANNEL.store(by_channel, Ordering::Relaxed);
+            let elapsed = best_of(7, f);
+            COMPARE_BY_CHANNEL.store(false, Ordering::Relaxed);
+            elapsed
+        };
+        for (name, img, options) in [
+            ("exact", &img, DetectOptions::default()),
+            ("ignore alpha", &img, DetectOptions { ignore_alpha: true, ..DetectOptions::default() }),
+            ("tolerance 8", &noisy, DetectOptions { tolerance: 8, ..DetectOptions::default() }),
+        ] {
+            let mut strides = vec![0; img.width().max(img.height()) as usize + 1];
+            let get_pixel = by_channel(true, &mut || {
+                black_box(get_smallest_stride_phase1_by_get_pixel(img, &mut strides, &options));
+            });
+            let mut phase1 = |by| by_channel(by, &mut || {
+                black_box(get_smallest_stride_phase1(img, &mut strides, &options));
+            });
+            println!("phase 1 on {} x {}, {name}: get_pixel() {get_pixel:?}, by channel {:?}, lanes {:?}",
+                img.width(), img.height(), phase1(true), phase1(false));
+        }
+        let majority = |by| by_channel(by, &mut || {
+            black_box(downscale_by_stride(&img, 3, 3, Sample::Majority));
+        });
+        println!("majority of 3 x 3 blocks on {} x {}: by channel {:?}, lanes {:?}", img.width(), img.height(), majority(true), majority(false));
+    }
+
+    #[test]
+    fn stride_detector_matches_whole_image() {
+        let mut images = test_images();
+        images.push(DynamicImage::ImageRgba8(RgbaImage::new(1, 6)));
+        images.push(DynamicImage::ImageRgba8(RgbaImage::new(0, 0)));
+        for (index, img) in images.iter().enumerate() {
+            let rgba = img.to_rgba8();
+            for options in test_options() {
+                let mut detector = StrideDetector::new(img.width(), options.clone());
+                for row in rgba.rows() {
+                    detector.feed_row(&row.copied().collect::<Vec<_>>());
+                }
+                assert_eq!(detector.finish(), detect_scale_with(img, &options), "image {index}, {options:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn sprites_are_found_in_reading_order() {
+        let mut sheet = RgbaImage::new(20, 12);
+        let mut fill = |x: u32, y: u32, width: u32, height: u32, alpha: u8| {
+            for y in y..y + height {
+                for x in x..x + width {
+                    sheet.put_pixel(x, y, Rgba([200, 40, 40, alpha]));
+                }
+            }
+        };
+        // connected diagonally to its bottom right corner
+        fill(1, 1, 3, 3, 255);
+        fill(4, 4, 1, 1, 255);
+        // an L with a detached eye inside its bounding box
+        fill(8, 2, 1, 4, 255);
+        fill(8, 5, 4, 1, 255);
+        fill(10, 2, 1, 1, 255);
+        // lower than the others, but still in the first row
+        fill(15, 3, 2, 2, 255);
+        fill(2, 8, 5, 3, 255);
+        // too faint to count
+        fill(18, 10, 1, 1, 100);
+
+        let options = DetectOptions { alpha_threshold: 128, ..DetectOptions::default() };
+        let expected = [
+            Sprite { x: 1, y: 1, width: 4, height: 4 },
+            Sprite { x: 8, y: 2, width: 4, height: 4 },
+            Sprite { x: 15, y: 3, width: 2, height: 2 },
+            Sprite { x: 2, y: 8, width: 5, height: 3 },
+        ];
+        assert_eq!(find_sprites(&DynamicImage::ImageRgba8(sheet.clone()), &options), expected);
+        assert_eq!(find_sprites(&DynamicImage::ImageRgba16(DynamicImage::ImageRgba8(sheet).to_rgba16()), &options), expected);
+        assert_eq!(find_sprites(&DynamicImage::ImageRgba8(RgbaImage::new(8, 8)), &options), []);
+    }
+
+    /// Pixel art whose pixels are tiles of 2 x 2 pixels, every third one a
+    /// checkerboard of two colors, scaled up by `scale`.
+    fn dithered_art(width: u32, height: u32, scale: u32) -> RgbaImage {
+        let colors = native_art_of(OPAQUE_PALETTE, width, height, 8);
+        let partner = |color: &Rgba<u8>| {
+            let index = OPAQUE_PALETTE.iter().position(|&other| other == color.0).unwrap();
+            Rgba(OPAQUE_PALETTE[(index + 1) % OPAQUE_PALETTE.len()])
+        };
+        RgbaImage::from_fn(width * scale * DITHER_TILE_SIZE, height * scale * DITHER_TILE_SIZE, |x, y| {
+            let (art_x, art_y) = (x / DITHER_TILE_SIZE / scale, y / DITHER_TILE_SIZE / scale);
+            let color = colors.get_pixel(art_x, art_y);
+            if (art_x + art_y * width).is_multiple_of(3) && (x + y) % 2 == 1 { partner(color) } else { *color }
+        })
+    }
+
+    #[test]
+    fn dithered_scale_includes_the_tiles() {
+        let options = DetectOptions::default();
+        let dithered = |width, height, scale| DynamicImage::ImageRgba8(dithered_art(width, height, scale));
+        assert_eq!(detect_dithered_scale(&dithered(10, 7, 3), &options), Some(6));
+        assert_eq!(detect_dithered_scale(&dithered(10, 7, 1), &options), Some(2));
+        // partial tiles at the right and bottom edge are ignored
+        let art = dithered_art(10, 7, 2);
+        let mut extended = RgbaImage::from_pixel(art.width() + 1, art.height() + 1, Rgba([255, 0, 255, 255]));
+        imageops::replace(&mut extended, &art, 0, 0);
+        assert_eq!(detect_dithered_scale(&DynamicImage::ImageRgba8(extended), &options), Some(4));
+        assert_eq!(detect_dithered_scale(&DynamicImage::ImageRgba16(dithered(10, 7, 3).to_rgba16()), &options), Some(6));
+
+        // no checkerboard at all, or tiles that are neither
+        let undithered = DynamicImage::ImageRgba8(upscale(&native_art(10, 7, 8), 4));
+        assert_eq!(detect_dithered_scale(&undithered, &options), None);
+        let mut noisy = dithered_art(10, 7, 3);
+        add_noise(&mut noisy, 3, 9);
+        assert_eq!(detect_dithered_scale(&DynamicImage::ImageRgba8(noisy.clone()), &options), None);
+        assert_eq!(detect_dithered_scale(&DynamicImage::ImageRgba8(noisy), &DetectOptions { tolerance: 8, ..options.clone() }), Some(6));
+        assert_eq!(detect_dithered_scale(&DynamicImage::ImageRgba8(RgbaImage::new(3, 3)), &options), None);
+    }
+
+    #[test]
+    fn raw_buffer_scan_detects_scale_of_each_color_type() {
+        let images = test_images();
+        let scales = images.iter().map(|img| detect_scale_with(img, &DetectOptions::default())).collect::<Vec<_>>();
+        assert_eq!(scales, [Some(3), None, Some(2), Some(4), Some(5), None, None]);
+        assert_eq!(detect_scale_with(&images[5], &DetectOptions { tolerance: 8, ..DetectOptions::default() }), Some(3));
+        assert_eq!(detect_scale(&images[6], true), Some(2));
+    }
+}
@@ -0,0 +1,134 @@
+//! Messages on stderr, by how verbose they shall be (--quiet, --verbose) and
+//! colored as given by --color. Stdout is kept for the output and the result
+//! of --only-analyze. Each message first clears the progress line, if any.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Verbosity {
+    /// Only warnings, errors, and what was asked for, like --timings.
+    Quiet,
+    /// Also status messages like the sizes of the input and output.
+    Normal,
+    /// Also how long scaling each frame took.
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(verbosity: Verbosity) -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= verbosity as u8
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The messages of the current thread, while a test captures them.
+    static CAPTURED: std::cell::RefCell<Option<Vec<String>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Clears the progress line and prints the message. Tests can capture the
+/// messages of their thread instead, see [`capture`].
+pub fn print(message: fmt::Arguments) {
+    #[cfg(test)]
+    if CAPTURED.with_borrow_mut(|captured| captured.as_mut().map(|captured| captured.push(message.to_string()))).is_some() {
+        return;
+    }
+    crate::progress::clear();
+    eprintln!("{message}");
+}
+
+/// Runs `f` and returns the messages it printed instead of printing them.
+#[cfg(test)]
+pub fn capture(f: impl FnOnce()) -> Vec<String> {
+    CAPTURED.set(Some(Vec::new()));
+    f();
+    CAPTURED.take().unwrap_or_default()
+}
+
+/// Prints a message if the verbosity is at least the given one.
+macro_rules! log {
+    ($verbosity:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($verbosity) {
+            $crate::log::print(format_args!($($arg)*));
+        }
+    };
+}
+
+/// Prints a status message, unless --quiet is given.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        log!($crate::log::Verbosity::Normal, $($arg)*)
+    };
+}
+
+/// Prints a status message about written output, green with --color.
+macro_rules! success {
+    ($($arg:tt)*) => {
+        info!("{}", $crate::style::paint($crate::style::Style::Success, format_args!($($arg)*)))
+    };
+}
+
+/// Prints a message only with --verbose.
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        log!($crate::log::Verbosity::Verbose, $($arg)*)
+    };
+}
+
+/// Prints what was explicitly asked for, like --timings, even with --quiet.
+macro_rules! note {
+    ($($arg:tt)*) => {
+        log!($crate::log::Verbosity::Quiet, $($arg)*)
+    };
+}
+
+/// Prints a warning, yellow with --color. Also printed with --quiet.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        note!("{}", $crate::style::paint($crate::style::Style::Warning, format_args!($($arg)*)))
+    };
+}
+
+/// Prints an error, red with --color.
+macro_rules! error {
+    ($($arg:tt)*) => {
+        note!("{}", $crate::style::paint($crate::style::Style::Error, format_args!($($arg)*)))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_all() {
+        info!("info {}", 1);
+        success!("success {}", 2);
+        verbose!("verbose {}", 3);
+        note!("note {}", 4);
+        warn!("warn {}", 5);
+        error!("error {}", 6);
+    }
+
+    #[test]
+    fn messages_by_verbosity() {
+        // the verbosity is global, so all levels are checked in one test
+        set_verbosity(Verbosity::Quiet);
+        let quiet = capture(log_all);
+        set_verbosity(Verbosity::Normal);
+        let normal = capture(log_all);
+        set_verbosity(Verbosity::Verbose);
+        let verbose = capture(log_all);
+        set_verbosity(Verbosity::Normal);
+
+        assert_eq!(quiet, ["note 4", "warn 5", "error 6"]);
+        assert_eq!(normal, ["info 1", "success 2", "note 4", "warn 5", "error 6"]);
+        assert_eq!(verbose, ["info 1", "success 2", "verbose 3", "note 4", "warn 5", "error 6"]);
+        assert!(enabled(Verbosity::Normal) && !enabled(Verbosity::Verbose));
+    }
+}
@@ -1,369 +1,4410 @@
-use clap::Parser;
+#[cfg(test)]
+mod fixtures;
+mod gif_meta;
+mod input;
+#[macro_use]
+mod log;
+mod output;
+mod progress;
+mod spool;
+mod style;
+mod webp_anim;
+mod zip;
+
+use clap::{ArgGroup, Parser};
+
+use gif_meta::{read_gif_meta, read_gif_meta_head, write_background_color};
+use input::{is_stdin, InputReader};
+use log::Verbosity;
+use output::{is_stdout, MemoryOutputs, OutputWriter};
+use progress::Progress;
+use spool::{Spool, SpooledFrames};
+use webp_anim::AnimatedWebPEncoder;
+use zip::ZipWriter;
+
+use fix_pixelart::{count_runs, detect_phase, detect_phase_from_frames, detect_confidence, detect_scale_and_frame_scales, detect_scale_from_frames_with, detect_scale_of_each_frame, detect_scale_with, estimate_scale, detect_border, detect_mat_color, detect_dithered_scale, downscale_by_stride, FrameDetector, downscale_dynamic_by_stride, find_sprites, looks_chroma_subsampled, too_small_to_detect, Analysis, Border, DetectOptions, Sample, Strategy, DITHER_TILE_SIZE};
 
 use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
 use image::codecs::png::PngDecoder;
-use image::codecs::webp::WebPDecoder;
-use image::{Frames, GenericImageView, ImageFormat, ImageResult};
-use image::io::Reader as ImageReader;
-use image::{AnimationDecoder, DynamicImage, Frame, ImageDecoder, Rgba};
+use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::{Frames, GenericImageView, ImageBuffer, ImageError, ImageFormat, ImageResult, Luma, Pixel, Primitive, Rgb, Rgba, RgbaImage};
+use image::buffer::ConvertBuffer;
+use image::error::{DecodingError, EncodingError, ImageFormatHint};
+use image::ImageReader;
+use image::{AnimationDecoder, Delay, DynamicImage, ExtendedColorType, Frame, ImageDecoder, ImageEncoder};
 use image::imageops::{self, FilterType};
+use image::metadata::Orientation;
+
+use color_quant::NeuQuant;
+use image_webp::LoopCount;
 
+use std::borrow::Cow;
+use rayon::prelude::*;
+
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
-use std::io::BufWriter;
+use std::fs::{File, Metadata};
+use std::io::{ErrorKind, IsTerminal, Read, Seek, Write};
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
+#[command(group(ArgGroup::new("json_output").args(["only_analyze", "summary"]).multiple(true)))]
 struct Args {
     /// Overwrite the original file.
     /// Ignored if an explicit output is defined.
+    /// If the file is a symbolic link its target is overwritten, the link stays as it is.
     #[clap(verbatim_doc_comment)]
     #[arg(short, long, default_value_t = false)]
     in_place: bool,
 
+    /// With --in-place, refuse to overwrite files that are given as symbolic links.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, requires = "in_place")]
+    no_follow_symlinks: bool,
+
+    /// Write the output into DIR, keeping the file name and extension of the input,
+    /// e.g. sprites/hero.png is written to DIR/hero.png. Only the extension changes if
+    /// the output format differs. With --recursive the directory structure is mirrored in DIR.
+    /// The input is never overwritten this way, use --in-place for that.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["in_place", "output", "stdout"])]
+    out_dir: Option<OsString>,
+
+    /// Skip files whose output already exists. Doesn't apply to --in-place.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "interactive")]
+    no_clobber: bool,
+
+    /// Ask before overwriting an existing output. Doesn't apply to --in-place.
+    /// Files are skipped if stdin isn't a terminal.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    interactive: bool,
+
+    /// Give the output the modification time of the input.
+    /// Always done with --in-place.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    preserve_timestamps: bool,
+
+    /// Write the detected scaling, grid offset, border and confidence next to the output as
+    /// <output>.fixpixelart.json. Also done with --only-analyze, for the output that would be written.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "stdout")]
+    sidecar: bool,
+
     /// Only analyze the file and print the new size as `{width}x{height}`.
     /// This can be used if scaling shall be done with a different tool, e.g. ImageMagick:
     /// 
     /// if size=$(fix-pixelart -a image.gif); then
     ///     convert image.gif -scale "$size" scaled.gif
     /// fi
+    ///
+    /// If no scaling is detected the unchanged size is printed.
     #[clap(verbatim_doc_comment)]
     #[arg(short = 'a', long, default_value_t = false)]
     only_analyze: bool,
 
-    /// Only analyze the first frame of an animation.
-    /// This can lead to a big speed-up, but will create a 1x1 pixel image if the first frame is a blank screen.
-    #[clap(verbatim_doc_comment)]
-    #[arg(short = 'f', long, default_value_t = false)]
-    only_analyze_first_frame: bool,
+    /// Print the result of --only-analyze as a JSON object instead, e.g.:
+    /// {"input":"a.gif","detected":true,"stride_x":3,"stride_y":3,"confidence":1.000,"width":320,"height":240,"frames":12,"animated":true,"runs_x":{"3":96,"6":20},"runs_y":{"3":88,"9":12}}
+    /// "frames" is null if not all frames of an animation were decoded. "runs_x" and "runs_y"
+    /// count the runs of equal colored pixels along the rows and columns by their length,
+    /// e.g. {"3":120,"6":14}, which shows why a scale was or wasn't detected.
+    /// Also prints the --summary as JSON.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, requires = "json_output")]
+    json: bool,
+
+    /// Detect the scaling and print which file would be written with which size, but don't
+    /// write anything: {input} -> {output}: {width} x {height} -> {new width} x {new height}
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with_all = ["only_analyze", "interactive", "debug_grid", "sidecar"])]
+    dry_run: bool,
+
+    /// Only analyze the first frame of an animation.
+    /// This can lead to a big speed-up, but will create a 1x1 pixel image if the first frame is a blank screen.
+    /// A GIF or APNG piped in on stdin is then read as it is decoded, so --only-analyze stops after
+    /// the first frame instead of reading the whole stream into memory first.
+    #[clap(verbatim_doc_comment)]
+    #[arg(short = 'f', long, default_value_t = false)]
+    only_analyze_first_frame: bool,
+
+    /// Only analyze the first N frames of an animation that aren't blank, i.e. a single color or
+    /// fully transparent. Faster than analyzing all frames, but more robust than
+    /// --only-analyze-first-frame. Blank frames are skipped with a note.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["only_analyze_first_frame", "spool_dir"])]
+    analyze_frames: Option<u32>,
+
+    /// Spool the frames of an animation to a temporary file in DIR while detecting the scaling,
+    /// instead of keeping them all in memory until they are written. They are read back from
+    /// it for the encoding, so long animations need about as much memory as a few frames.
+    /// Doesn't apply if the frames are decoded while writing anyway, e.g. with --force-scale.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "DIR",
+        conflicts_with_all = ["only_analyze", "only_analyze_first_frame", "ignore_border", "detect_phase", "min_confidence", "estimate_scale"])]
+    spool_dir: Option<OsString>,
+
+    /// Detect a solid border around the image and ignore it.
+    /// Sometimes pixel art is framed in a border that doesn't fit the pixel grid.
+    /// Lines of a single color are stripped from each edge and the scaling is detected
+    /// on what remains. The part of the border that doesn't fit the pixel grid is cropped.
+    /// --only-analyze then also prints the offset of the grid: {width}x{height}+{x}+{y}
+    #[clap(verbatim_doc_comment)]
+    #[arg(short = 'b', long, default_value_t = false, conflicts_with_all = ["detect_phase", "force_scale"])]
+    ignore_border: bool,
+
+    /// Ignore the first and last run of pixels of each row and column when detecting the
+    /// scaling. This is the extremely primitive strategy --ignore-border used before.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    ignore_border_simple: bool,
+
+    /// Detect the scaling only in this rectangle of the image, e.g. one sprite of a sheet
+    /// with sprites of different scales. The whole image is still scaled down by it.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_region, conflicts_with = "force_scale")]
+    region: Option<Region>,
+
+    /// Don't count runs of this opaque color when detecting the scaling, like transparent runs.
+    /// For pixel art that sits on a solid mat or frame whose size doesn't fit the pixel grid.
+    /// Either R,G,B or auto to take the color at least two corners of the image share.
+    /// The mat is still part of the output. --only-analyze then also reports the color.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "R,G,B|auto", value_parser = parse_mat_color, conflicts_with = "force_scale")]
+    mat_color: Option<MatColor>,
+
+    /// Maximum difference per color channel (0 to 255) for two pixels to be considered the same color.
+    /// Use this for pixel art that was saved in a lossy format like JPEG.
+    #[clap(verbatim_doc_comment)]
+    #[arg(short = 't', long, value_name = "N", default_value_t = 0)]
+    tolerance: u8,
+
+    /// Treat a detected scaling smaller than N as a failed detection.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = clap::value_parser!(u32).range(2..))]
+    min_stride: u32,
+
+    /// Treat a detected scaling larger than N as a failed detection. Images with large areas
+    /// of a single color can otherwise be mistaken for pixel art scaled up by a huge factor.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(2..))]
+    max_stride: Option<u32>,
+
+    /// Percentage (50 to 100) of runs of equal colored pixels that have to be multiples of the
+    /// scaling. A few stray runs, e.g. from anti-aliased edges or a watermark, then don't
+    /// prevent the detection.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "PERCENT", default_value_t = 99, value_parser = clap::value_parser!(u8).range(50..=100))]
+    grid_agreement: u8,
+
+    /// Require every run of equal colored pixels to be a multiple of the scaling, so that a
+    /// single run of one pixel means the image isn't upscaled.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "grid_agreement")]
+    strict_grid: bool,
+
+    /// How the scaling is picked from the lengths of the runs of equal colored pixels.
+    /// gcd is right for clean pixel art. mode helps with pixel art that has many runs that
+    /// don't fit the grid, e.g. from dithering or resampled edges, but is more likely to
+    /// mistake other images for pixel art. min helps with pixel art whose pixels were scaled
+    /// to slightly different sizes. --grid-agreement limits the runs of a single pixel for
+    /// min, mode ignores it. With --strict-grid a run of a single pixel always means the
+    /// image isn't upscaled.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "STRATEGY", value_enum, default_value_t = DetectStrategy::Gcd)]
+    detect: DetectStrategy,
+
+    /// Treat a detected scaling as a failed detection if its confidence is below T (0 to 1).
+    /// The confidence is the fraction of runs of exactly equal colors that fit the scaling,
+    /// so it is only below 1 with --tolerance. --only-analyze prints it.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "T", value_parser = parse_confidence)]
+    min_confidence: Option<f64>,
+
+    /// If no scaling is detected, estimate the scale from the lengths of the runs of
+    /// pixels and print it. Helps to understand why the detection failed for pixel art
+    /// that was scaled by a factor that isn't an integer, like 2.5.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    estimate_scale: bool,
+
+    /// Write the first frame of the input with the detected pixel grid drawn over it to PATH.
+    /// The border that was ignored for the detection is darkened and transparent areas are shown
+    /// over a checkerboard. Helps to see why a detection went wrong.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["force_scale", "upscale", "upscale_to", "recursive"])]
+    debug_grid: Option<PathBuf>,
+
+    /// Only count runs of pixels with an alpha value (0 to 255) greater than N for the
+    /// detection. With 0 every run that isn't fully transparent counts.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    alpha_threshold: u8,
+
+    /// Count runs of fully transparent pixels for the detection too.
+    /// Helps if the transparent background is aligned to the pixel grid.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    include_transparent: bool,
+
+    /// Ignore the alpha channel when comparing colors for the detection, e.g. for a
+    /// background whose alpha value differs slightly from the foreground.
+    /// The output keeps the alpha channel.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    ignore_alpha: bool,
+
+    /// If no scaling is detected, check if the pixels are 2x2 checkerboards of two colors, as
+    /// made by ordered dithering, and detect the scaling with each such tile taken as a unit.
+    /// The scaling then includes the size of the tiles. --only-analyze reports the tiles,
+    /// with --json as "dither_tile". The output takes one of the colors of each tile,
+    /// use --filter to blend them instead. Only applies to still images.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "detect_phase")]
+    dither_aware: bool,
+
+    /// Don't rotate and flip images as given by their EXIF orientation.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    no_auto_orient: bool,
+
+    /// Skip the detection and scale down by the given factor instead.
+    /// Either N for both axes or WxH for a different factor per axis.
+    /// The factor has to evenly divide the size of the image.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N|WxH", value_parser = parse_scale)]
+    force_scale: Option<Scale>,
+
+    /// Use a scale given in the file name, like in sprite@3x.png, icon_x2.gif, or tile_4x.png.
+    /// With scale it is used instead of detecting the scaling, with check a warning is
+    /// printed if the detected scaling differs. Files without such a name are detected as usual.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "MODE", value_enum, conflicts_with = "force_scale")]
+    use_name_hint: Option<NameHint>,
+
+    /// Drop a scale given at the end of the file name, like @3x, from generated output names,
+    /// so sprite@3x.png is written to sprite.png instead of sprite@3x.scaled.png.
+    /// Explicit output file names are used as they are.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    rename_downscaled: bool,
+
+    /// Scale native pixel art up by the factor N instead of scaling it down.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["force_scale", "detect_phase", "ignore_border"])]
+    upscale: Option<u32>,
+
+    /// After scaling the image down, also write copies scaled up by each factor N, M, ... with
+    /// nearest neighbor, e.g. for several display densities. They are named after the output
+    /// with @Nx before the extension, e.g. sprite@2x.png. Only applies to still images.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N[,M...]", value_delimiter = ',', value_parser = clap::value_parser!(u32).range(2..),
+        conflicts_with_all = ["stdout", "upscale", "upscale_to", "split"])]
+    also_scale: Vec<u32>,
+
+    /// Scale native pixel art up by the largest integer factor that fits into WxH.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "WxH", value_parser = parse_size,
+        conflicts_with_all = ["upscale", "force_scale", "detect_phase", "ignore_border"])]
+    upscale_to: Option<(u32, u32)>,
+
+    /// Detect where the pixel grid starts, for images that were cropped in the middle of a pixel.
+    /// The partial pixels at the edges are cropped (or completed with --pad).
+    /// --only-analyze then also prints the offset of the grid: {width}x{height}+{x}+{y}
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "force_scale")]
+    detect_phase: bool,
+
+    /// Start the pixel grid at X,Y instead of at the top left corner, for images that are known
+    /// to be cropped in the middle of a pixel, when --detect-phase can't tell where. Pixels are
+    /// then sampled from X + k * stride. X and Y have to be smaller than the stride. The partial
+    /// pixels at the edges are cropped (or completed with --pad), also with --force-scale.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "X,Y", value_parser = parse_origin,
+        conflicts_with_all = ["detect_phase", "ignore_border", "split", "upscale", "upscale_to"])]
+    origin: Option<(u32, u32)>,
+
+    /// If the size of the image isn't a multiple of the scaling complete the partial
+    /// pixels at the edges instead of cropping them.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    pad: bool,
+
+    /// Crop the output to the bounding box of the pixels that aren't fully transparent.
+    /// For animations the box covers all frames, so they stay in place relative to each other.
+    /// Has no effect on --only-analyze.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    trim: bool,
+
+    /// Split a sprite sheet with a transparent background into its sprites. Each connected area
+    /// of pixels that aren't transparent (see --alpha-threshold) is a sprite. The scaling of each
+    /// sprite is detected on its own, and the sprites are written as numbered files in reading
+    /// order, e.g. sheet.scaled.01.png, sheet.scaled.02.png, and so on.
+    /// Sprites without detected scaling are written unchanged. Only applies to still images.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false,
+        conflicts_with_all = ["in_place", "stdout", "only_analyze", "sidecar", "debug_grid", "region", "upscale", "upscale_to"])]
+    split: bool,
+
+    /// Write the frames of an animation scaled down as separate PNG images to DIR instead,
+    /// e.g. for editing them: frame_0000.png, frame_0001.png, and so on. All frames are scaled
+    /// by the scale detected across the animation. With --sidecar the delays of the frames are
+    /// written to DIR/frames.json, so the animation can be put back together, e.g.:
+    /// {"input":"a.gif","stride_x":3,"stride_y":3,"frames":[{"file":"frame_0000.png","delay":100}]}
+    /// "delay" is in milliseconds. Only applies to animations.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "DIR",
+        conflicts_with_all = ["output", "in_place", "stdout", "out_dir", "split", "dry_run", "no_clobber", "interactive", "files_from", "recursive", "frames_glob"])]
+    explode: Option<OsString>,
+
+    /// How to pick the color of each block when scaling down.
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = SampleMode::Center)]
+    sample: SampleMode,
+
+    /// Filter to scale down with. Anything but nearest smooths the result instead of
+    /// picking the color of each block as given by --sample.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_enum, default_value_t = Filter::Nearest)]
+    filter: Filter,
+
+    /// Scale the output back up and compare it to the input. If they differ a warning with
+    /// their PSNR is printed, since then the detected scaling is likely wrong or detail was lost.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    verify: bool,
+
+    /// Only scale animations if every frame on its own yields the same scaling.
+    /// Frames that don't contain anything to detect the scaling from are skipped.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    require_frame_agreement: bool,
+
+    /// Fail if the frames of an animation disagree on the scaling. Without this only a
+    /// warning is printed and the scale all frames are consistent with is used, if any.
+    /// Also fail if the output format can't store all of a still image, e.g. JPEG drops the
+    /// alpha channel and formats other than PNG reduce 16 bits per channel to 8.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "require_frame_agreement")]
+    strict: bool,
+
+    /// Analyze the frames of animations, the rows and columns of still images, and the files
+    /// of --recursive in parallel using N threads (0 uses all cores). With 1 everything runs
+    /// on the main thread. With --only-analyze-first-frame there are no frames to spread over
+    /// the threads, only the rows and columns of the first frame are scanned in parallel.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N")]
+    threads: Option<usize>,
+
+    /// Pixel aspect ratio of the native image, e.g. 8:7 for the SNES.
+    /// It is stored as metadata in the output (PNG only, other formats can't carry it).
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "W:H", value_parser = parse_pixel_aspect)]
+    pixel_aspect: Option<PixelAspect>,
+
+    /// Instead of storing the pixel aspect ratio as metadata stretch the
+    /// native image using nearest neighbor so it has square pixels.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, requires = "pixel_aspect")]
+    stretch_aspect: bool,
+
+    /// Format to write the output in, regardless of the extension of OUTPUT. Also gives the
+    /// extension of generated output file names. Any extension of a writable format, like
+    /// png, gif, webp, jpeg, bmp, tiff, or qoi.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "FORMAT", value_parser = parse_format)]
+    format: Option<ImageFormat>,
+
+    /// With --format, write animations as a still image of their first frame if the format
+    /// doesn't support animations, instead of failing.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, requires = "format")]
+    allow_downgrade: bool,
+
+    /// Fail with exit code 2 instead of writing an animation as a still image of its first
+    /// frame because the output format doesn't support animations, e.g. JPEG. By default that
+    /// only happens with --format, otherwise a warning is printed.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "allow_downgrade")]
+    no_downgrade: bool,
+
+    /// Maximum number of colors per frame of GIF output (2 to 256).
+    /// Frames with more colors are quantized and a warning is printed.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N", default_value_t = 256, value_parser = clap::value_parser!(u16).range(2..=256))]
+    gif_colors: u16,
+
+    /// Minimum delay of the frames of GIF output in milliseconds. GIF stores delays in
+    /// centiseconds, so delays are rounded to multiples of 10 ms anyway, and a delay that
+    /// isn't zero is at least 10 ms. Some viewers play frames with very short delays slower.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    min_delay: u32,
+
+    /// Quality of JPEG output (1 to 100). Ignored for all other formats.
+    /// JPEG is a poor choice for pixel art, since it smudges the edges of the pixels,
+    /// but sometimes it is needed for compatibility. WebP output is always lossless,
+    /// even if the input was a lossy WebP, see --lossy.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N", default_value_t = 75, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+
+    /// Write lossy WebP output. Not supported, the image crate can only encode lossless
+    /// WebPs, so this fails instead of silently writing a lossless one.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    lossy: bool,
+
+    /// Write the output even if no pixel art scaling is detected.
+    /// The image is then just re-encoded with the given output settings,
+    /// which gives consistent files when processing a whole set of images.
+    /// Has no effect on --only-analyze.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    normalize: bool,
+
+    /// If no scaling is detected copy the input unchanged to the output and exit with 0
+    /// instead of 2. If the output is in a different format it is re-encoded at its native
+    /// resolution like with --normalize. Useful for processing mixed content.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    lenient: bool,
+
+    /// If the image is already at its native resolution copy it unchanged to the output and
+    /// exit with 0, just noting that nothing was done. Like --lenient, but for batches that
+    /// mix upscaled and native images. With --only-analyze a scale of 1x1 is reported.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    copy_if_native: bool,
+
+    /// Fail if the width or height of the output would still exceed N pixels.
+    /// This catches images where detection found only a fraction of the real scaling.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N")]
+    max_output_dim: Option<u32>,
+
+    /// Fail if the output would still have more than N pixels in total.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "N")]
+    max_pixels: Option<u64>,
+
+    /// Skip the input if it wasn't modified after the given time.
+    /// TIME is either a RFC 3339 timestamp (e.g. 2024-06-01T12:00:00+02:00)
+    /// or @FILE to use the modification time of FILE.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "TIME", value_parser = parse_since)]
+    since: Option<SystemTime>,
+
+    /// If the input is a directory process all images in it and its sub-directories.
+    /// The output then has to be a directory too, in which the directory structure is
+    /// mirrored. Files that aren't images are skipped. With --threads the files are
+    /// processed in parallel, so their messages may be interleaved.
+    #[clap(verbatim_doc_comment)]
+    #[arg(short, long, default_value_t = false)]
+    recursive: bool,
+
+    /// Only process files matching PATTERN with --recursive, e.g. "*.png".
+    /// In PATTERN * matches any number of characters and ? a single character.
+    /// If PATTERN contains a / it is matched against the path relative to the input
+    /// directory, otherwise against the file name.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "PATTERN", requires = "recursive")]
+    glob: Option<String>,
+
+    /// Process the images listed in PATH, one path per line, or - to read the list from stdin.
+    /// Blank lines and lines starting with # are skipped. The output then has to be a
+    /// directory, which all images are written to. With --threads the images are processed
+    /// in parallel.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["recursive", "stdout"])]
+    files_from: Option<OsString>,
+
+    /// Read the frames of an animation from the images matching PATTERN instead of INPUT,
+    /// e.g. 'frames/frame_*.png', in the order of their file names. In PATTERN * matches any
+    /// number of characters and ? a single character, only in the file name. All frames have
+    /// to be the same size. They are scaled down by the scale detected across all of them and
+    /// written as an animated GIF to the file given with --assemble.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "PATTERN", requires = "assemble",
+        conflicts_with_all = ["files_from", "recursive", "in_place", "stdout", "out_dir", "split", "spool_dir", "lenient", "copy_if_native", "preserve_timestamps"])]
+    frames_glob: Option<String>,
+
+    /// Where to write the animated GIF assembled from the frames of --frames-glob.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "FILE", requires = "frames_glob")]
+    assemble: Option<OsString>,
+
+    /// Delay of each frame of --frames-glob in milliseconds.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "MS", default_value_t = 100, requires = "frames_glob")]
+    delay: u32,
+
+    /// Process the images inside the ZIP archive ARCHIVE instead of INPUT, like a directory
+    /// with --recursive. They are written to the directory given with --out-dir, mirroring
+    /// the directories of the archive, or into a new archive with --zip-out. The images are
+    /// read from memory, nothing is extracted.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "ARCHIVE",
+        conflicts_with_all = ["files_from", "recursive", "frames_glob", "in_place", "stdout", "output", "since", "preserve_timestamps"])]
+    zip: Option<OsString>,
+
+    /// Write the images of --zip scaled down into the new ZIP archive FILE. All other entries
+    /// are copied unchanged, as are images that weren't scaled down, e.g. because no scaling
+    /// was detected.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "FILE", requires = "zip", conflicts_with_all = ["out_dir", "split"])]
+    zip_out: Option<OsString>,
+
+    /// After a batch (--recursive or --files-from) print a table of all files to stderr:
+    /// which were scaled down and by which scale, which were left at their native
+    /// resolution, which were skipped, and which failed, with the sizes of the written files
+    /// and their total. With --json print it to stdout as a JSON array instead, e.g.:
+    /// [{"input":"a.png","result":"scaled","stride_x":3,"stride_y":3,"input_size":1200,"output_size":300,"saved":75.0},{"input":"b.png","result":"native","message":"failed to detect pixel art scaling"}]
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    summary: bool,
+
+    /// Does nothing, going on with the other files of a batch if one fails is the default.
+    /// Only there to be the opposite of --fail-fast.
+    #[arg(long, default_value_t = false, hide = true)]
+    keep_going: bool,
+
+    /// In a batch (--recursive or --files-from), stop at the first file that fails.
+    /// Files that are being processed in parallel are still finished. Without it the
+    /// batch goes on with the other files and lists the failed files at the end. Only
+    /// errors that would hit every further file too, like a full disk, stop it anyway.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with = "keep_going")]
+    fail_fast: bool,
+
+    /// Set when processing a file of a directory given with --recursive.
+    #[arg(skip)]
+    in_batch: bool,
+
+    /// Number of frames of the input animation if it is known up front, for --progress.
+    #[arg(skip)]
+    frame_count: Option<usize>,
+
+    /// The input is a lossy WebP, so a failed detection may be due to compression artifacts.
+    #[arg(skip)]
+    lossy_input: bool,
+
+    /// The entries of the --zip archive by their path, which is the path of the archive
+    /// joined with their name. INPUT is one of them when processing the archive.
+    #[arg(skip)]
+    zip_entries: Option<Arc<HashMap<PathBuf, Arc<[u8]>>>>,
+
+    /// Where the outputs for --zip-out are kept until they are put into the new archive.
+    #[arg(skip)]
+    zip_outputs: Option<Arc<MemoryOutputs>>,
+
+    /// Write the output to stdout as PNG, or as given by --format. Same as passing - as OUTPUT.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false, conflicts_with_all = ["output", "in_place", "recursive"])]
+    stdout: bool,
+
+    /// Don't print status messages like the sizes of the input and output.
+    /// Warnings and errors are still printed.
+    #[clap(verbatim_doc_comment)]
+    #[arg(short, long, default_value_t = false, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also print how long scaling each frame took.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    verbose: bool,
+
+    /// Color warnings yellow, errors red, and written output green.
+    /// auto colors them if stderr is a terminal.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, value_name = "WHEN", value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Print how long decoding, detecting the scaling, scaling and encoding took to stderr.
+    /// For animations decoding, scaling and encoding are timed per frame.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    timings: bool,
+
+    /// Show how many frames of an animation, or files of a directory, are processed so far.
+    /// Only shown if stderr is a terminal and --quiet isn't given.
+    #[clap(verbatim_doc_comment)]
+    #[arg(long, default_value_t = false)]
+    progress: bool,
+
+    /// Image to resize, or - to read it from stdin.
+    /// The output then defaults to stdout.
+    #[clap(verbatim_doc_comment)]
+    #[arg(required_unless_present_any = ["files_from", "frames_glob", "zip"], default_value = "", hide_default_value = true)]
+    input: OsString,
+
+    /// Where to write the output.
+    /// Can be a file name, a directory, or - for stdout.
+    /// [default: "{basename}.scaled.{ext}"]
+    #[clap(verbatim_doc_comment)]
+    #[arg(default_value = None)]
+    output: Option<OsString>,
+}
+
+impl Args {
+    /// Whether to show the progress of the frames of an animation. A batch
+    /// shows the progress of its files instead.
+    fn frame_progress(&self) -> bool {
+        self.progress && !self.quiet && !self.in_batch
+    }
+
+    /// The data of `path` if it is an entry of the --zip archive.
+    fn zip_entry(&self, path: &Path) -> Option<&Arc<[u8]>> {
+        self.zip_entries.as_ref()?.get(path)
+    }
+
+    /// Creates the writer of an output, which is kept in memory for --zip-out.
+    fn output_writer(&self, output: &OsStr) -> std::io::Result<OutputWriter> {
+        match &self.zip_outputs {
+            Some(outputs) => Ok(OutputWriter::in_memory(outputs, &self.input, output)),
+            None => OutputWriter::create(output),
+        }
+    }
+
+    /// Whether to use the rayon thread pool, which is only sized by --threads.
+    fn parallel(&self) -> bool {
+        self.threads.is_some_and(|threads| threads != 1)
+    }
+
+    fn detect_options(&self) -> DetectOptions {
+        DetectOptions {
+            ignore_border: self.ignore_border_simple,
+            tolerance: self.tolerance,
+            alpha_threshold: self.alpha_threshold,
+            include_transparent: self.include_transparent,
+            ignore_alpha: self.ignore_alpha,
+            // the thread pool is configured in main()
+            threads: self.parallel().then_some(0),
+            // like with --detect-phase the partial pixels at the edges say nothing about the scale
+            detect_phase: self.detect_phase || self.origin.is_some(),
+            min_stride: Some(self.min_stride),
+            max_stride: self.max_stride,
+            grid_agreement: (!self.strict_grid).then_some(self.grid_agreement),
+            strategy: self.detect.strategy(),
+            mat_color: match self.mat_color {
+                Some(MatColor::Rgb(color)) => Some(color),
+                Some(MatColor::Auto) | None => None,
+            },
+        }
+    }
+
+    /// The options to detect the scaling of `img` with, for --mat-color auto
+    /// with the color inferred from its corners.
+    fn detect_options_for(&self, img: &DynamicImage) -> DetectOptions {
+        let mut options = self.detect_options();
+        if self.mat_color == Some(MatColor::Auto) {
+            options.mat_color = detect_mat_color(img);
+            match options.mat_color {
+                Some([r, g, b]) => info!("mat color {r},{g},{b} inferred from the corners"),
+                None => warn!("the corners don't share an opaque color, not ignoring a mat"),
+            }
+        }
+        options
+    }
+
+    /// The pixel aspect ratio if it shall be applied by stretching the image.
+    fn stretch(&self) -> Option<PixelAspect> {
+        if self.stretch_aspect { self.pixel_aspect } else { None }
+    }
+
+    /// How many frames of an animation are analyzed, `None` for all of them.
+    fn analyzed_frames(&self) -> Option<usize> {
+        if self.only_analyze_first_frame {
+            Some(1)
+        } else {
+            self.analyze_frames.map(|count| count as usize)
+        }
+    }
+
+    fn upscaling(&self) -> bool {
+        self.upscale.is_some() || self.upscale_to.is_some()
+    }
+
+    /// Factor to scale an image of the given size up by, 1 if not upscaling.
+    /// 0 if the image doesn't fit into the size of --upscale-to.
+    fn upscale_factor(&self, width: u32, height: u32) -> u32 {
+        match (self.upscale, self.upscale_to) {
+            (Some(factor), _) => factor,
+            (None, Some((max_width, max_height))) => (max_width / width.max(1)).min(max_height / height.max(1)),
+            (None, None) => 1,
+        }
+    }
+}
+
+/// Factor by which the pixel art is scaled up, per axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Scale {
+    x: u32,
+    y: u32,
+}
+
+impl Scale {
+    fn square(scale: u32) -> Self {
+        Self { x: scale, y: scale }
+    }
+}
+
+impl std::fmt::Display for Scale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{}", self.x, self.y)
+    }
+}
+
+/// Pixel grid of upscaled pixel art.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Grid {
+    scale: Scale,
+    /// Offset of the first complete pixel, smaller than the scale.
+    x: u32,
+    y: u32,
+    /// Complete the partial pixels at the edges instead of cropping them.
+    pad: bool,
+    /// Each pixel is made of 2x2 dither tiles, see --dither-aware.
+    dither: bool,
+}
+
+impl Grid {
+    /// Number of pixels cropped from (or added to, if padding) the left and top edge.
+    fn leading(self) -> (u32, u32) {
+        if self.pad {
+            ((self.scale.x - self.x) % self.scale.x, (self.scale.y - self.y) % self.scale.y)
+        } else {
+            (self.x, self.y)
+        }
+    }
+
+    /// Position of a pixel scaled back down.
+    fn shrink(self, x: u32, y: u32) -> (u32, u32) {
+        let (left, top) = self.leading();
+        if self.pad {
+            ((x + left) / self.scale.x, (y + top) / self.scale.y)
+        } else {
+            (x.saturating_sub(left) / self.scale.x, y.saturating_sub(top) / self.scale.y)
+        }
+    }
+
+    /// Size of an image of the given size scaled back down.
+    fn shrink_size(self, width: u32, height: u32) -> (u32, u32) {
+        let (left, top) = self.leading();
+        let (width, height) = if self.pad {
+            ((width + left).div_ceil(self.scale.x), (height + top).div_ceil(self.scale.y))
+        } else {
+            self.shrink(width, height)
+        };
+        (width.max(1), height.max(1))
+    }
+}
+
+/// Rectangle of the image to detect the scaling in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Region {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Region {
+    /// The parts of an image of the given size outside of the region, `None`
+    /// if the region is out of bounds.
+    fn outside(self, width: u32, height: u32) -> Option<Border> {
+        let right = width.checked_sub(self.x.checked_add(self.width)?)?;
+        let bottom = height.checked_sub(self.y.checked_add(self.height)?)?;
+        Some(Border { left: self.x, top: self.y, right, bottom })
+    }
+}
+
+/// Color of the mat to ignore, see --mat-color.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatColor {
+    /// Inferred from the corners of each image.
+    Auto,
+    Rgb([u8; 3]),
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+fn parse_region(value: &str) -> Result<Region, String> {
+    let numbers: Vec<&str> = value.split(',').collect();
+    let &[x, y, width, height] = numbers.as_slice() else {
+        return Err(format!("illegal region {value:?}, expected X,Y,W,H"));
+    };
+    let x: u32 = x.trim().parse().map_err(|err| format!("illegal x {x:?}: {err}"))?;
+    let y: u32 = y.trim().parse().map_err(|err| format!("illegal y {y:?}: {err}"))?;
+    let width: u32 = width.trim().parse().map_err(|err| format!("illegal width {width:?}: {err}"))?;
+    let height: u32 = height.trim().parse().map_err(|err| format!("illegal height {height:?}: {err}"))?;
+    if width == 0 || height == 0 {
+        return Err(format!("region may not be empty: {value:?}"));
+    }
+    Ok(Region { x, y, width, height })
+}
+
+fn parse_origin(value: &str) -> Result<(u32, u32), String> {
+    let Some((x, y)) = value.split_once(',') else {
+        return Err(format!("illegal origin {value:?}, expected X,Y"));
+    };
+    let x: u32 = x.trim().parse().map_err(|err| format!("illegal x {x:?}: {err}"))?;
+    let y: u32 = y.trim().parse().map_err(|err| format!("illegal y {y:?}: {err}"))?;
+    Ok((x, y))
+}
+
+fn parse_mat_color(value: &str) -> Result<MatColor, String> {
+    if value.trim().eq_ignore_ascii_case("auto") {
+        return Ok(MatColor::Auto);
+    }
+    let channels: Vec<&str> = value.split(',').collect();
+    let &[r, g, b] = channels.as_slice() else {
+        return Err(format!("illegal mat color {value:?}, expected R,G,B or auto"));
+    };
+    let r: u8 = r.trim().parse().map_err(|err| format!("illegal red {r:?}: {err}"))?;
+    let g: u8 = g.trim().parse().map_err(|err| format!("illegal green {g:?}: {err}"))?;
+    let b: u8 = b.trim().parse().map_err(|err| format!("illegal blue {b:?}: {err}"))?;
+    Ok(MatColor::Rgb([r, g, b]))
+}
+
+fn parse_size(value: &str) -> Result<(u32, u32), String> {
+    let Some((width, height)) = value.split_once(['x', 'X']) else {
+        return Err(format!("illegal size {value:?}, expected WxH"));
+    };
+    let width: u32 = width.trim().parse().map_err(|err| format!("illegal width {width:?}: {err}"))?;
+    let height: u32 = height.trim().parse().map_err(|err| format!("illegal height {height:?}: {err}"))?;
+    Ok((width, height))
+}
+
+fn parse_scale(value: &str) -> Result<Scale, String> {
+    let (x, y) = value.split_once(['x', 'X']).unwrap_or((value, value));
+    let x: u32 = x.trim().parse().map_err(|err| format!("illegal scale {x:?}: {err}"))?;
+    let y: u32 = y.trim().parse().map_err(|err| format!("illegal scale {y:?}: {err}"))?;
+    if x == 0 || y == 0 {
+        return Err(format!("scale may not be zero: {value:?}"));
+    }
+    Ok(Scale { x, y })
+}
+
+/// What --use-name-hint does with a scale given in the file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum NameHint {
+    /// Use the scale instead of detecting it.
+    Scale,
+    /// Warn if the detected scale differs.
+    Check,
+}
+
+/// When --color colors the messages on stderr.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => std::io::stderr().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// How --detect picks the scaling from the lengths of the runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum DetectStrategy {
+    /// The greatest common divisor of the lengths.
+    #[default]
+    Gcd,
+    /// The most common length.
+    Mode,
+    /// The shortest length.
+    Min,
+}
+
+impl DetectStrategy {
+    fn strategy(self) -> Strategy {
+        match self {
+            DetectStrategy::Gcd => Strategy::Gcd,
+            DetectStrategy::Mode => Strategy::Mode,
+            DetectStrategy::Min => Strategy::Min,
+        }
+    }
+}
+
+/// How --sample picks the color of each block.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum SampleMode {
+    /// Resize with a nearest neighbor filter. This picks pixels by a ratio,
+    /// which can land on the edge of a block.
+    Nearest,
+
+    /// Take the pixel at the center of each block.
+    #[default]
+    Center,
+
+    /// Take the most frequent color of each block. Ties are resolved in favor
+    /// of the center pixel. Useful for scanned or anti-aliased pixel art.
+    Majority,
+}
+
+impl SampleMode {
+    fn sample(self) -> Sample {
+        match self {
+            SampleMode::Nearest => Sample::Nearest,
+            SampleMode::Center => Sample::Center,
+            SampleMode::Majority => Sample::Majority,
+        }
+    }
+}
+
+/// Filter to scale down with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum Filter {
+    #[default]
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl Filter {
+    /// The filter to resize with, `None` for nearest, which samples each block instead.
+    fn filter_type(self) -> Option<FilterType> {
+        match self {
+            Filter::Nearest => None,
+            Filter::Triangle => Some(FilterType::Triangle),
+            Filter::CatmullRom => Some(FilterType::CatmullRom),
+            Filter::Gaussian => Some(FilterType::Gaussian),
+            Filter::Lanczos3 => Some(FilterType::Lanczos3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PixelAspect {
+    width: u32,
+    height: u32,
+}
+
+impl PixelAspect {
+    /// PNG pHYs stores pixels per unit, so a wide pixel means fewer pixels horizontally.
+    fn pixel_dims(self) -> png::PixelDimensions {
+        png::PixelDimensions {
+            xppu: self.height,
+            yppu: self.width,
+            unit: png::Unit::Unspecified,
+        }
+    }
+
+    /// Size that displays an image of the given size with square pixels.
+    fn stretch(&self, width: u32, height: u32) -> (u32, u32) {
+        if self.width >= self.height {
+            (((width as u64 * self.width as u64 + self.height as u64 / 2) / self.height as u64) as u32, height)
+        } else {
+            (width, ((height as u64 * self.height as u64 + self.width as u64 / 2) / self.width as u64) as u32)
+        }
+    }
+}
+
+fn parse_pixel_aspect(value: &str) -> Result<PixelAspect, String> {
+    let Some((width, height)) = value.split_once(':') else {
+        return Err(format!("expected W:H, got {value:?}"));
+    };
+    let width: u32 = width.trim().parse().map_err(|err| format!("illegal width {width:?}: {err}"))?;
+    let height: u32 = height.trim().parse().map_err(|err| format!("illegal height {height:?}: {err}"))?;
+    if width == 0 || height == 0 {
+        return Err(format!("pixel aspect ratio may not be zero: {value:?}"));
+    }
+    Ok(PixelAspect { width, height })
+}
+
+fn parse_format(value: &str) -> Result<ImageFormat, String> {
+    let format = ImageFormat::from_extension(value.trim()).ok_or_else(|| format!("unknown image format: {value:?}"))?;
+    if !format.writing_enabled() {
+        return Err(format!("writing {} images isn't supported", format.extensions_str()[0].to_ascii_uppercase()));
+    }
+    Ok(format)
+}
+
+fn parse_confidence(value: &str) -> Result<f64, String> {
+    let confidence: f64 = value.trim().parse().map_err(|err| format!("illegal confidence {value:?}: {err}"))?;
+    if !(0.0..=1.0).contains(&confidence) {
+        return Err(format!("confidence has to be between 0 and 1: {value:?}"));
+    }
+    Ok(confidence)
+}
+
+fn parse_since(value: &str) -> Result<SystemTime, String> {
+    if let Some(path) = value.strip_prefix('@') {
+        return std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .map_err(|err| format!("{path}: {err}"));
+    }
+    parse_rfc3339(value).ok_or_else(|| format!("illegal RFC 3339 timestamp: {value:?}"))
+}
+
+fn parse_rfc3339(value: &str) -> Option<SystemTime> {
+    fn number(value: &str, digits: usize, range: std::ops::RangeInclusive<i64>) -> Option<i64> {
+        if value.len() != digits || !value.bytes().all(|ch| ch.is_ascii_digit()) {
+            return None;
+        }
+        let number = value.parse().ok()?;
+        range.contains(&number).then_some(number)
+    }
+
+    // the date is YYYY-MM-DD, whatever follows is the time
+    let (date, time) = match (value.get(..10), value.get(10..)) {
+        (Some(date), Some(time)) => (date, time),
+        _ => (value, ""),
+    };
+    let mut date = date.split('-');
+    let year  = number(date.next()?, 4, 0..=9999)?;
+    let month = number(date.next()?, 2, 1..=12)?;
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 => if leap_year { 29 } else { 28 },
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    let day   = number(date.next()?, 2, 1..=days_in_month)?;
+    if date.next().is_some() {
+        return None;
+    }
+
+    let mut seconds = 0;
+    if !time.is_empty() {
+        let time = time.strip_prefix(['T', 't', ' '])?;
+        let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+            (time, 0)
+        } else {
+            let index = time.rfind(['+', '-'])?;
+            let (time, offset) = time.split_at(index);
+            let (offset_hours, offset_minutes) = offset[1..].split_once(':')?;
+            let offset_seconds = number(offset_hours, 2, 0..=23)? * 3600 + number(offset_minutes, 2, 0..=59)? * 60;
+            (time, if offset.starts_with('-') { -offset_seconds } else { offset_seconds })
+        };
+        // fractions of a second are irrelevant for comparing modification times
+        let time = time.split_once('.').map(|(time, _)| time).unwrap_or(time);
+        let mut time = time.split(':');
+        let hour   = number(time.next()?, 2, 0..=23)?;
+        let minute = number(time.next()?, 2, 0..=59)?;
+        let second = number(time.next()?, 2, 0..=60)?;
+        if time.next().is_some() {
+            return None;
+        }
+        seconds = hour * 3600 + minute * 60 + second - offset;
+    }
+
+    // days since the epoch, see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    seconds += days * 86400;
+    if seconds >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds as u64))
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(Duration::from_secs(seconds.unsigned_abs()))
+    }
+}
+
+/// Exit code when no pixel art scaling was detected.
+const EXIT_NOT_DETECTED: i32 = 2;
+
+/// Reasons the processing of a file ends early.
+#[derive(Debug)]
+enum FixPixelartError {
+    /// No pixel art scaling was detected.
+    DetectionFailed,
+    /// The image is smaller than 2 x 2 pixels, so there is nothing to detect.
+    TooSmall { width: u32, height: u32 },
+    /// The forced scale doesn't evenly divide the image size.
+    NotDivisible { scale: Scale, width: u32, height: u32 },
+    /// The image doesn't fit into the size of --upscale-to.
+    UpscaleDoesNotFit { width: u32, height: u32, max_width: u32, max_height: u32 },
+    /// --region doesn't lie within the image.
+    RegionOutOfBounds { region: Region, width: u32, height: u32 },
+    /// --origin doesn't lie within the first pixel of the grid.
+    OriginOutOfRange { x: u32, y: u32, scale: Scale },
+    /// Scaling down would enlarge the image without --upscale or --upscale-to.
+    WouldEnlarge { width: u32, height: u32, new_width: u32, new_height: u32 },
+    MaxOutputDimExceeded { width: u32, height: u32, max_output_dim: u32 },
+    MaxPixelsExceeded { width: u32, height: u32, max_pixels: u64 },
+    NoFrames,
+    /// --split found nothing but transparent pixels.
+    NoSprites,
+    /// The frames of an animation disagree on the scaling with --strict.
+    FramesDisagree(String),
+    /// --format doesn't support animations and --allow-downgrade isn't given.
+    AnimationDowngrade(ImageFormat),
+    /// The output format doesn't support animations and --no-downgrade is given.
+    DowngradeRefused(ImageFormat),
+    /// A frame of --frames-glob isn't the size of the first frame.
+    FrameSizeMismatch { path: PathBuf, width: u32, height: u32, expected: (u32, u32) },
+    /// The output format can't store all of the image and --strict is given.
+    LossyOutput(String),
+    /// The arguments can't be used together with this input.
+    InvalidArgs(&'static str),
+    Io(std::io::Error),
+    Image(ImageError),
+}
+
+impl FixPixelartError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            FixPixelartError::DetectionFailed | FixPixelartError::TooSmall { .. } |
+            FixPixelartError::DowngradeRefused(_) => EXIT_NOT_DETECTED,
+            _ => 1,
+        }
+    }
+
+    /// Whether the error would hit every further file of a batch too, like a
+    /// full disk, so the batch stops even without --fail-fast.
+    fn is_fatal(&self) -> bool {
+        let err = match self {
+            FixPixelartError::Io(err) | FixPixelartError::Image(ImageError::IoError(err)) => err,
+            _ => return false,
+        };
+        matches!(err.kind(), ErrorKind::StorageFull | ErrorKind::ReadOnlyFilesystem | ErrorKind::QuotaExceeded)
+    }
+}
+
+impl std::fmt::Display for FixPixelartError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FixPixelartError::DetectionFailed => write!(f, "failed to detect pixel art scaling"),
+            FixPixelartError::TooSmall { width, height } =>
+                write!(f, "image size {width} x {height} is too small to detect pixel art scaling"),
+            FixPixelartError::NotDivisible { scale, width, height } =>
+                write!(f, "forced scale {scale} doesn't evenly divide the image size {width} x {height}"),
+            FixPixelartError::UpscaleDoesNotFit { width, height, max_width, max_height } =>
+                write!(f, "image size {width} x {height} doesn't fit into --upscale-to {max_width}x{max_height}"),
+            FixPixelartError::RegionOutOfBounds { region, width, height } =>
+                write!(f, "region {region} lies outside of the image size {width} x {height}"),
+            FixPixelartError::OriginOutOfRange { x, y, scale } =>
+                write!(f, "origin {x},{y} doesn't lie within the first pixel of scale {scale}, it has to be smaller than the stride"),
+            FixPixelartError::WouldEnlarge { width, height, new_width, new_height } =>
+                write!(f, "scaling {width} x {height} down to {new_width} x {new_height} would enlarge the image, only --upscale and --upscale-to may do that"),
+            FixPixelartError::MaxOutputDimExceeded { width, height, max_output_dim } =>
+                write!(f, "output size {width} x {height} exceeds --max-output-dim {max_output_dim}"),
+            FixPixelartError::MaxPixelsExceeded { width, height, max_pixels } =>
+                write!(f, "output size {width} x {height} ({} pixels) exceeds --max-pixels {max_pixels}", *width as u64 * *height as u64),
+            FixPixelartError::NoFrames => write!(f, "animation has no frames"),
+            FixPixelartError::NoSprites => write!(f, "no sprites found, the image is fully transparent"),
+            FixPixelartError::FramesDisagree(list) => write!(f, "frames disagree on the pixel art scaling: {list}"),
+            FixPixelartError::AnimationDowngrade(format) => write!(
+                f, "animated {} images are not supported, use --format gif, png, or webp, or --allow-downgrade to write the first frame",
+                format.extensions_str()[0].to_ascii_uppercase(),
+            ),
+            FixPixelartError::DowngradeRefused(format) => write!(
+                f, "animated {} images are not supported and --no-downgrade is given, write a GIF, APNG (.png), or WebP instead",
+                format.extensions_str()[0].to_ascii_uppercase(),
+            ),
+            FixPixelartError::FrameSizeMismatch { path, width, height, expected: (expected_width, expected_height) } =>
+                write!(f, "{path:?}: frame size {width} x {height} differs from the size {expected_width} x {expected_height} of the first frame"),
+            FixPixelartError::LossyOutput(message) => write!(f, "{message}, not writing it because of --strict"),
+            FixPixelartError::InvalidArgs(message) => write!(f, "{message}"),
+            FixPixelartError::Io(err) => write!(f, "Error: {err:?}"),
+            FixPixelartError::Image(err) => write!(f, "Error: {err:?}"),
+        }
+    }
+}
+
+impl From<ImageError> for FixPixelartError {
+    fn from(err: ImageError) -> Self {
+        FixPixelartError::Image(err)
+    }
+}
+
+impl From<std::io::Error> for FixPixelartError {
+    fn from(err: std::io::Error) -> Self {
+        FixPixelartError::Io(err)
+    }
+}
+
+type ProcessResult<T> = Result<T, FixPixelartError>;
+
+/// What became of a file that was processed without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Processed {
+    /// Scaled down by the scale, which is 1x1 if it was re-encoded at its
+    /// native resolution. With --only-analyze and --dry-run it would have been.
+    /// The sizes are those of a written file, if they are known.
+    Scaled(Scale, Option<ByteSizes>),
+    /// Split into this many sprites with --split.
+    Split(usize),
+    /// No pixel art scaling was detected, so --lenient copied the input
+    /// unchanged.
+    Copied,
+    /// Already at its native resolution, so --copy-if-native passed it
+    /// through. With --only-analyze it would have.
+    Native,
+    /// Skipped because of --since, --no-clobber, or --interactive.
+    Skipped,
+}
+
+/// Sizes of the input and the output file in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ByteSizes {
+    input: u64,
+    output: u64,
+}
+
+impl ByteSizes {
+    /// How much smaller the output is in percent of the input, `None` for an
+    /// empty input.
+    fn saved(self) -> Option<f64> {
+        (self.input > 0).then(|| 100.0 * (self.input as f64 - self.output as f64) / self.input as f64)
+    }
+}
+
+impl std::ops::Add for ByteSizes {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        ByteSizes { input: self.input + other.input, output: self.output + other.output }
+    }
+}
+
+impl std::fmt::Display for ByteSizes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} bytes", self.input, self.output)?;
+        if let Some(saved) = self.saved() {
+            write!(f, " ({saved:.1}% saved)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reports the error, unless it was reported already, and returns the exit code for it.
+fn report_error(err: &FixPixelartError, input: &OsStr, in_batch: bool) -> i32 {
+    progress::clear();
+    if in_batch {
+        error!("{input:?}: {err}");
+    } else {
+        error!("{err}");
+    }
+    err.exit_code()
+}
+
+/// Scales a still image down. If given, the output is written as an indexed PNG
+/// with the `palette` of the input.
+fn resize_still_image(img: &DynamicImage, output_format: ImageFormat, palette: Option<&Palette>, icc_profile: Option<Vec<u8>>, args: Args) -> ProcessResult<Processed> {
+    if !args.only_analyze {
+        check_output_losses(img, output_format, &args)?;
+    }
+    if args.split {
+        return split_sprite_sheet(img, output_format, palette, icc_profile, args);
+    }
+    if args.explode.is_some() {
+        warn!("--explode only applies to animations, scaling the still image as usual");
+    }
+    let output = output_from(&args, output_format)?;
+    let (width, height) = img.dimensions();
+    let mut analysis = Analysis::default();
+    let grid = if let Some(scale) = args.force_scale {
+        forced_grid(scale, width, height, &args)?
+    } else if args.upscaling() {
+        Grid { scale: Scale::square(1), x: 0, y: 0, pad: false, dither: false }
+    } else {
+        let start = Instant::now();
+        let outside = check_region(width, height, &args)?;
+        let region = crop_border(img, outside);
+        let options = args.detect_options_for(&region);
+        analysis.mat_color = options.mat_color;
+        let solid_border = if args.ignore_border { detect_border([region.as_ref()], &options) } else { Border::default() };
+        let interior = crop_border(&region, solid_border);
+        let mut scale = detect_scale_with(&interior, &options);
+        let mut dither = false;
+        if scale.is_none() && args.dither_aware {
+            scale = detect_dithered_scale(&interior, &options);
+            dither = scale.is_some();
+        }
+        if dither {
+            // the runs of the dither pattern are a single pixel, so the confidence would be 0
+            info!("dithered with {DITHER_TILE_SIZE}x{DITHER_TILE_SIZE} tiles, taking each tile as a unit");
+        } else {
+            (scale, analysis.confidence) = confident_scale(scale, [interior.as_ref()], &options, &args);
+        }
+        if scale.is_none() {
+            print_chroma_warning(&interior, &options);
+        }
+        if scale.is_none() && args.estimate_scale {
+            print_scale_estimate([interior.as_ref()], &options);
+        }
+        if args.only_analyze && args.json {
+            (analysis.runs_x, analysis.runs_y) = count_runs(&interior, &options);
+        }
+        let scale = match scale_or_fail(scale, width, height, FrameInfo::STILL, &analysis, &args)? {
+            ControlFlow::Continue(scale) => scale,
+            ControlFlow::Break(processed) => return Ok(processed),
+        };
+        check_name_hint(scale, &args);
+        let (x, y) = if let Some(origin) = check_origin(Scale::square(scale), &args)? {
+            origin
+        } else if args.detect_phase {
+            let (x, y) = detect_phase(&region, scale, &options);
+            ((outside.left + x) % scale, (outside.top + y) % scale)
+        } else {
+            let remainder = add_borders(outside, solid_border).remainder(scale);
+            analysis.border = args.ignore_border.then_some(remainder);
+            (remainder.left, remainder.top)
+        };
+        (analysis.scale, analysis.phase) = (Some(scale), (x, y));
+        let grid = Grid { scale: Scale::square(scale), x, y, pad: args.pad, dither };
+        print_timing("detected", start.elapsed(), &args);
+        if let Some(path) = &args.debug_grid {
+            write_debug_grid(img, grid, add_borders(outside, solid_border), path)?;
+        }
+        grid
+    };
+    let upscale = check_upscale(width, height, &args)?;
+    let (new_width, new_height) = grid.shrink_size(width, height);
+    let (new_width, new_height) = (new_width * upscale, new_height * upscale);
+    check_downscale((width, height), (new_width, new_height), &args)?;
+    check_output_size(&args, new_width, new_height)?;
+    print_remainder_warning(width, height, grid);
+    if args.sidecar {
+        write_sidecar(&output, (width, height), grid, &analysis, FrameInfo::STILL, &args)?;
+    }
+    if args.only_analyze {
+        print_analysis(new_width, new_height, Some(grid), &analysis, FrameInfo::STILL, &args);
+        return Ok(Processed::Scaled(grid.scale, None));
+    }
+    if args.dry_run {
+        print_dry_run(&output, (width, height), (new_width, new_height), grid, &args);
+        return Ok(Processed::Scaled(grid.scale, None));
+    }
+    info!("resizing {width} x {height} -> {new_width} x {new_height}");
+    let input_metadata = input_metadata(&args)?;
+    let start = Instant::now();
+    let fitted = fit_to_grid(img, grid);
+    let mut img = downscale_still(&fitted, grid, output_format, &args);
+    if args.verify {
+        match verify_downscale(&fitted, &img) {
+            None => info!("verified, scaled back up the output is identical to the input"),
+            Some(psnr) => warn!("scaled back up the output differs from the input, PSNR {psnr:.2} dB, the detected scaling might be wrong"),
+        }
+    }
+    if upscale > 1 {
+        img = img.resize_exact(new_width, new_height, FilterType::Nearest);
+    }
+    verbose!("scaled in {:.1?}", start.elapsed());
+    print_timing("scaled", start.elapsed(), &args);
+    let mut pixel_aspect = args.pixel_aspect;
+    if let Some(aspect) = pixel_aspect {
+        if args.stretch_aspect {
+            let (width, height) = aspect.stretch(new_width, new_height);
+            info!("stretching {new_width} x {new_height} -> {width} x {height}");
+            img = img.resize_exact(width, height, FilterType::Nearest);
+            pixel_aspect = None;
+        } else if output_format != ImageFormat::Png {
+            print_pixel_aspect_warning(output_format);
+        }
+    }
+    if args.trim {
+        if let Some(trim) = TrimBox::of(&img.to_rgba16(), 0, 0) {
+            info!("trimming {} x {} -> {} x {}", img.width(), img.height(), trim.width(), trim.height());
+            img = img.crop_imm(trim.left, trim.top, trim.width(), trim.height());
+        } else {
+            warn!("image is fully transparent, not trimming");
+        }
+    }
+    if icc_profile.is_some() && !matches!(output_format, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP) {
+        print_icc_profile_warning(output_format);
+    }
+    let native = (!args.also_scale.is_empty()).then(|| img.clone());
+    write_still_image(img, &output, output_format, pixel_aspect, palette, icc_profile.clone(), &args)?;
+    let sizes = finish_output(input_metadata.as_ref(), &output, &args)?;
+    if let Some(native) = native {
+        write_also_scaled(&native, &output, output_format, pixel_aspect, palette, icc_profile, &args)?;
+    }
+    Ok(Processed::Scaled(grid.scale, sizes))
+}
+
+/// Writes the copies of --also-scale, scaled up from the written output.
+fn write_also_scaled(img: &DynamicImage, output: &OsStr, output_format: ImageFormat, pixel_aspect: Option<PixelAspect>, palette: Option<&Palette>, icc_profile: Option<Vec<u8>>, args: &Args) -> ProcessResult<()> {
+    for &factor in &args.also_scale {
+        let output = also_scaled_output(output, factor);
+        let (width, height) = (img.width() * factor, img.height() * factor);
+        check_output_size(args, width, height)?;
+        let scaled = img.resize_exact(width, height, FilterType::Nearest);
+        write_still_image(scaled, &output, output_format, pixel_aspect, palette, icc_profile.clone(), args)?;
+        success!("written {output:?}");
+    }
+    Ok(())
+}
+
+/// Inserts @Nx before the extension of the output for --also-scale.
+fn also_scaled_output(output: &OsStr, factor: u32) -> OsString {
+    let path = Path::new(output);
+    let mut name = path.file_stem().unwrap_or(output).to_owned();
+    name.push(format!("@{factor}x"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name).into_os_string()
+}
+
+/// Scales a still image that is already fitted to the grid down to its native resolution.
+fn downscale_still(fitted: &DynamicImage, grid: Grid, output_format: ImageFormat, args: &Args) -> DynamicImage {
+    let (native_width, native_height) = ((fitted.width() / grid.scale.x).max(1), (fitted.height() / grid.scale.y).max(1));
+    if let Some(filter) = args.filter.filter_type() {
+        if output_format == ImageFormat::Png {
+            fitted.resize_exact(native_width, native_height, filter)
+        } else {
+            DynamicImage::ImageRgba8(imageops::resize(fitted, native_width, native_height, filter))
+        }
+    } else if output_format == ImageFormat::Png {
+        // keeps 16 bits per channel, which only PNG output preserves
+        downscale_dynamic_by_stride(fitted, grid.scale.x, grid.scale.y, args.sample.sample())
+    } else {
+        DynamicImage::ImageRgba8(downscale_by_stride(fitted, grid.scale.x, grid.scale.y, args.sample.sample()))
+    }
+}
+
+/// Scales each sprite of a sprite sheet down on its own for --split and writes
+/// them as numbered files.
+fn split_sprite_sheet(img: &DynamicImage, output_format: ImageFormat, palette: Option<&Palette>, icc_profile: Option<Vec<u8>>, args: Args) -> ProcessResult<Processed> {
+    let output = output_from(&args, output_format)?;
+    let options = args.detect_options();
+    let start = Instant::now();
+    let sprites = find_sprites(img, &options);
+    print_timing("found sprites", start.elapsed(), &args);
+    if sprites.is_empty() {
+        return Err(FixPixelartError::NoSprites);
+    }
+    info!("found {} sprites", sprites.len());
+    if args.pixel_aspect.is_some() && output_format != ImageFormat::Png && !args.dry_run {
+        print_pixel_aspect_warning(output_format);
+    }
+    if icc_profile.is_some() && !matches!(output_format, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP) && !args.dry_run {
+        print_icc_profile_warning(output_format);
+    }
+    let input_metadata = input_metadata(&args)?;
+    let digits = sprites.len().to_string().len();
+    for (index, sprite) in sprites.iter().enumerate() {
+        let number = index + 1;
+        let output = numbered_output(&output, number, digits);
+        let (width, height) = (sprite.width, sprite.height);
+        let cropped = img.crop_imm(sprite.x, sprite.y, width, height);
+        let scale = if let Some(scale) = args.force_scale {
+            check_forced_scale(scale, width, height)?
+        } else {
+            let (scale, _) = confident_scale(detect_scale_with(&cropped, &options), [&cropped], &options, &args);
+            scale.map(Scale::square).unwrap_or_else(|| {
+                info!("sprite {number} at {},{}: no pixel art scaling detected, keeping it unchanged", sprite.x, sprite.y);
+                Scale::square(1)
+            })
+        };
+        // the bounding box of a sprite starts right at its first pixel
+        let grid = Grid { scale, x: 0, y: 0, pad: args.pad, dither: false };
+        let (new_width, new_height) = grid.shrink_size(width, height);
+        check_downscale((width, height), (new_width, new_height), &args)?;
+        check_output_size(&args, new_width, new_height)?;
+        if args.dry_run {
+            print_dry_run(&output, (width, height), (new_width, new_height), grid, &args);
+            continue;
+        }
+        info!("sprite {number} at {},{}: resizing {width} x {height} -> {new_width} x {new_height}", sprite.x, sprite.y);
+        let fitted = fit_to_grid(&cropped, grid);
+        let scaled = downscale_still(&fitted, grid, output_format, &args);
+        if args.verify && verify_downscale(&fitted, &scaled).is_some() {
+            warn!("sprite {number}: scaled back up the output differs from the input, the detected scaling might be wrong");
+        }
+        write_still_image(scaled, &output, output_format, args.pixel_aspect, palette, icc_profile.clone(), &args)?;
+        // the size of a single sprite can't be compared to the size of the sheet
+        if let Some(input_metadata) = &input_metadata {
+            preserve_timestamp(input_metadata, &output, &args)?;
+        }
+        success!("written {output:?}");
+    }
+    Ok(Processed::Split(sprites.len()))
+}
+
+/// Inserts the number of a sprite before the extension of the output, padded
+/// with zeros to `digits` so the files sort in order.
+fn numbered_output(output: &OsStr, number: usize, digits: usize) -> OsString {
+    let path = Path::new(output);
+    let mut name = path.file_stem().unwrap_or(output).to_owned();
+    name.push(format!(".{number:0digits$}"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name).into_os_string()
+}
+
+/// Encodes a scaled down still image into the output file.
+fn write_still_image(mut img: DynamicImage, output: &OsStr, output_format: ImageFormat, pixel_aspect: Option<PixelAspect>, palette: Option<&Palette>, icc_profile: Option<Vec<u8>>, args: &Args) -> ImageResult<()> {
+    if output_format == ImageFormat::Gif {
+        let mut rgba = img.into_rgba8();
+        if quantize(&mut rgba, args.gif_colors as usize) {
+            warn!("image has more than {} colors, quantized to fit into a GIF palette", args.gif_colors);
+        }
+        img = DynamicImage::ImageRgba8(rgba);
+    }
+    let start = Instant::now();
+    let mut writer = args.output_writer(output)?;
+    if output_format == ImageFormat::Png && (pixel_aspect.is_some() || palette.is_some() || icc_profile.is_some()) {
+        write_png(&img, pixel_aspect, palette, icc_profile.as_deref(), &mut writer)?;
+    } else if output_format == ImageFormat::Jpeg {
+        let mut encoder = JpegEncoder::new_with_quality(&mut writer, args.quality);
+        if let Some(icc_profile) = icc_profile {
+            encoder.set_icc_profile(icc_profile).map_err(ImageError::Unsupported)?;
+        }
+        // JPEG has no alpha channel
+        encoder.encode_image(&img.to_rgb8())?;
+    } else if output_format == ImageFormat::WebP {
+        // Lossy compression would smudge the pixels, so always encode lossless,
+        // no matter what the default of the image crate is.
+        let mut encoder = WebPEncoder::new_lossless(&mut writer);
+        if let Some(icc_profile) = icc_profile {
+            encoder.set_icc_profile(icc_profile).map_err(ImageError::Unsupported)?;
+        }
+        let rgba = img.into_rgba8();
+        encoder.write_image(rgba.as_raw(), rgba.width(), rgba.height(), ExtendedColorType::Rgba8)?;
+    } else if output_format == ImageFormat::Pnm {
+        write_pnm(&img.into_rgba8(), output, &mut writer)?;
+    } else {
+        img.write_to(&mut writer, output_format)?;
+    }
+    writer.finish()?;
+    print_timing("encoded", start.elapsed(), args);
+    Ok(())
+}
+
+fn detect_agreed_scale<'a>(width: u32, height: u32, frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) -> Option<u32> {
+    let frame_scales = detect_scale_of_each_frame(width, height, frames, options)?;
+    let &(_, scale) = frame_scales.first()?;
+
+    if let Some(list) = frame_disagreement(&frame_scales) {
+        warn!("frames disagree on the pixel art scaling: {list}");
+        return None;
+    }
+
+    Some(scale)
+}
+
+/// Warns if the frames disagree on the scaling, which hints at a broken
+/// animation. Fails instead with --strict.
+fn check_frame_agreement(frame_scales: &[(usize, u32)], args: &Args) -> ProcessResult<()> {
+    if let Some(list) = frame_disagreement(frame_scales) {
+        if args.strict {
+            return Err(FixPixelartError::FramesDisagree(list));
+        }
+        warn!("frames disagree on the pixel art scaling: {list}");
+    }
+    Ok(())
+}
+
+/// Lists the scale of each frame if they aren't all the same.
+fn frame_disagreement(frame_scales: &[(usize, u32)]) -> Option<String> {
+    let &(_, scale) = frame_scales.first()?;
+    if frame_scales.iter().all(|&(_, frame_scale)| frame_scale == scale) {
+        return None;
+    }
+    let list = frame_scales.iter()
+        .map(|(index, frame_scale)| format!("frame {index}: {frame_scale}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Some(list)
+}
+
+/// What is known about the frames of the input, for the output of --only-analyze.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameInfo {
+    animated: bool,
+    /// `None` if not all frames were decoded.
+    count: Option<usize>,
+}
+
+impl FrameInfo {
+    const STILL: Self = Self { animated: false, count: Some(1) };
+}
+
+/// Computes the confidence of the detected scale if it is printed or checked,
+/// and drops a scale below --min-confidence.
+fn confident_scale<'a>(scale: Option<u32>, frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions, args: &Args) -> (Option<u32>, Option<f64>) {
+    let Some(scale) = scale else {
+        return (None, None);
+    };
+    if !args.only_analyze && args.min_confidence.is_none() {
+        return (Some(scale), None);
+    }
+    let confidence = detect_confidence(frames, scale, options);
+    if let Some(min_confidence) = args.min_confidence.filter(|&min_confidence| confidence < min_confidence) {
+        warn!("confidence {confidence:.3} of scale {scale} is below --min-confidence {min_confidence}");
+        return (None, Some(confidence));
+    }
+    (Some(scale), Some(confidence))
+}
+
+/// Reports the apparent scale of an image that isn't pixel art scaled up by
+/// an integer, for --estimate-scale.
+fn print_scale_estimate<'a>(frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) {
+    if let Some(estimate) = estimate_scale(frames, options) {
+        note!("apparent scale is about {estimate:.2}, which isn't an integer, try --force-scale or --filter with a nearby factor");
+    } else {
+        note!("no apparent scale found");
+    }
+}
+
+/// Explains a failed detection if the colors of the image look like they were
+/// chroma subsampled.
+fn print_chroma_warning(img: &DynamicImage, options: &DetectOptions) {
+    if looks_chroma_subsampled(img, options) {
+        warn!("the input appears chroma subsampled (e.g. a 4:2:0 JPEG or video frame), so colors bleed over the edges of pixels, try --tolerance or a source without subsampling");
+    }
+}
+
+/// Handles a failed detection according to the arguments. Continues with 1
+/// if the image shall be re-encoded at its native resolution, and breaks with
+/// the result if there is nothing left to do, e.g. because it was copied
+/// unchanged. `analysis` is what was found out so far, for --only-analyze.
+fn scale_or_fail(scale: Option<u32>, width: u32, height: u32, frames: FrameInfo, analysis: &Analysis, args: &Args) -> ProcessResult<ControlFlow<Processed, u32>> {
+    if let Some(scale) = scale {
+        return Ok(ControlFlow::Continue(scale));
+    }
+    if !args.normalize || args.only_analyze {
+        return detection_failed(width, height, frames, analysis, args).map(ControlFlow::Break);
+    }
+    info!("no pixel art scaling detected, re-encoding at native resolution");
+    Ok(ControlFlow::Continue(1))
+}
+
+/// The grid of --force-scale, which starts at --origin if given. Otherwise
+/// the scale has to evenly divide the size of the image.
+fn forced_grid(scale: Scale, width: u32, height: u32, args: &Args) -> ProcessResult<Grid> {
+    let Some((x, y)) = check_origin(scale, args)? else {
+        return Ok(Grid { scale: check_forced_scale(scale, width, height)?, x: 0, y: 0, pad: args.pad, dither: false });
+    };
+    Ok(Grid { scale, x, y, pad: args.pad, dither: false })
+}
+
+/// The start of the grid given with --origin. Fails if it doesn't lie within
+/// the first pixel of the grid.
+fn check_origin(scale: Scale, args: &Args) -> ProcessResult<Option<(u32, u32)>> {
+    match args.origin {
+        Some((x, y)) if x >= scale.x || y >= scale.y => Err(FixPixelartError::OriginOutOfRange { x, y, scale }),
+        origin => Ok(origin),
+    }
+}
+
+/// Fails if the forced scale doesn't evenly divide the size of the image.
+fn check_forced_scale(scale: Scale, width: u32, height: u32) -> ProcessResult<Scale> {
+    if !width.is_multiple_of(scale.x) || !height.is_multiple_of(scale.y) {
+        return Err(FixPixelartError::NotDivisible { scale, width, height });
+    }
+    Ok(scale)
+}
+
+/// The parts of the image outside of --region, which are cropped for the
+/// detection. Fails if the region doesn't lie within the image.
+fn check_region(width: u32, height: u32, args: &Args) -> ProcessResult<Border> {
+    let Some(region) = args.region else {
+        return Ok(Border::default());
+    };
+    region.outside(width, height).ok_or(FixPixelartError::RegionOutOfBounds { region, width, height })
+}
+
+/// Fails if the image doesn't fit into the size of --upscale-to. Returns the
+/// factor to scale up by, 1 if not upscaling.
+fn check_upscale(width: u32, height: u32, args: &Args) -> ProcessResult<u32> {
+    let factor = args.upscale_factor(width, height);
+    if factor == 0 {
+        let (max_width, max_height) = args.upscale_to.unwrap_or_default();
+        return Err(FixPixelartError::UpscaleDoesNotFit { width, height, max_width, max_height });
+    }
+    Ok(factor)
+}
+
+/// Fails if the image would come out larger than it is, which only --upscale
+/// and --upscale-to may do. No detected or forced scale does that, but this
+/// keeps any new source of a scale from enlarging images by mistake.
+fn check_downscale((width, height): (u32, u32), (new_width, new_height): (u32, u32), args: &Args) -> ProcessResult<()> {
+    if !args.upscaling() && (new_width > width || new_height > height) {
+        return Err(FixPixelartError::WouldEnlarge { width, height, new_width, new_height });
+    }
+    Ok(())
+}
+
+fn check_output_size(args: &Args, width: u32, height: u32) -> ProcessResult<()> {
+    if let Some(max_output_dim) = args.max_output_dim {
+        if width > max_output_dim || height > max_output_dim {
+            return Err(FixPixelartError::MaxOutputDimExceeded { width, height, max_output_dim });
+        }
+    }
+    if let Some(max_pixels) = args.max_pixels {
+        if width as u64 * height as u64 > max_pixels {
+            return Err(FixPixelartError::MaxPixelsExceeded { width, height, max_pixels });
+        }
+    }
+    Ok(())
+}
+
+/// Fails because no scaling was detected, unless --copy-if-native or
+/// --lenient copy the input unchanged instead.
+fn detection_failed(width: u32, height: u32, frames: FrameInfo, analysis: &Analysis, args: &Args) -> ProcessResult<Processed> {
+    let failure = if too_small_to_detect(width, height) {
+        FixPixelartError::TooSmall { width, height }
+    } else {
+        FixPixelartError::DetectionFailed
+    };
+    if args.lossy_input && args.tolerance == 0 && !args.only_analyze {
+        warn!("the input is a lossy WebP, try --tolerance if compression artifacts spoil the detection");
+    }
+    if args.only_analyze && args.copy_if_native {
+        let grid = Grid { scale: Scale::square(1), x: 0, y: 0, pad: false, dither: false };
+        print_analysis(width, height, Some(grid), analysis, frames, args);
+        info!("already at native resolution, scale 1x1");
+        return Ok(Processed::Native);
+    } else if args.only_analyze {
+        // still print the size so scripts always get a well formed answer
+        print_analysis(width, height, None, analysis, frames, args);
+    } else if args.copy_if_native {
+        info!("already at native resolution, nothing to scale");
+        copy_unchanged(args)?;
+        return Ok(Processed::Native);
+    } else if args.lenient {
+        report_error(&failure, &args.input, args.in_batch);
+        copy_unchanged(args)?;
+        return Ok(Processed::Copied);
+    }
+    Err(failure)
+}
+
+/// Copies the input as is to the output for --lenient and --copy-if-native.
+fn copy_unchanged(args: &Args) -> ImageResult<()> {
+    let format = ImageFormat::from_path(&args.input).unwrap_or(ImageFormat::Png);
+    let output = output_from(args, format)?;
+    if output == args.input {
+        info!("leaving {output:?} unchanged");
+        return Ok(());
+    }
+    if args.dry_run {
+        println!("{:?} -> {output:?}: copied unchanged", args.input);
+        return Ok(());
+    }
+    if let Some(data) = args.zip_entry(Path::new(&args.input)) {
+        let mut writer = args.output_writer(&output)?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        info!("copied unchanged to {output:?}");
+        return Ok(());
+    }
+    if is_stdout(&output) {
+        std::io::copy(&mut File::open(&args.input)?, &mut std::io::stdout().lock())?;
+        info!("copied unchanged to stdout");
+        return Ok(());
+    }
+    let input_metadata = std::fs::metadata(&args.input)?;
+    std::fs::copy(&args.input, &output)?;
+    preserve_timestamp(&input_metadata, &output, args)?;
+    info!("copied unchanged to {output:?}");
+    Ok(())
+}
+
+/// The path to write the output to. Unless OUTPUT is a file, the name is
+/// generated from the name of the input and the extension of `format`.
+fn output_from(args: &Args, format: ImageFormat) -> ImageResult<OsString> {
+    let input = args.input.as_os_str();
+    if args.in_place {
+        // Write to the target of a symbolic link, so the link isn't replaced by a file.
+        if Path::new(input).is_symlink() {
+            return Ok(std::fs::canonicalize(input)?.into_os_string());
+        }
+        return Ok(input.to_owned());
+    }
+
+    let input_path = Path::new(input);
+    // Keep the extension of the input if it belongs to the same format, since
+    // e.g. the PNM subtypes all share one format.
+    let extension = input_path.extension()
+        .filter(|extension| ImageFormat::from_extension(extension) == Some(format))
+        .unwrap_or(OsStr::new(format.extensions_str()[0]));
+
+    if let Some(out_dir) = &args.out_dir {
+        let mut name = OsString::new();
+        if let Some((stem, _)) = split_scale_suffix(input).filter(|_| args.rename_downscaled) {
+            name.push(stem);
+        } else {
+            let stem = input_path.file_stem().filter(|_| !is_stdin(input));
+            name.push(stem.unwrap_or(OsStr::new("pixelart")));
+        }
+        name.push(".");
+        name.push(extension);
+        return Ok(Path::new(out_dir).join(name).into_os_string());
+    }
+
+    let mut parent_dir = None;
+    if let Some(output) = args.output.as_deref() {
+        if is_stdout(output) {
+            return Ok(output.to_owned());
+        }
+        let path = Path::new(&output);
+        let meta = path.metadata();
+        match meta {
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+                // A path that doesn't exist yet is a directory, unless it looks like an image file.
+                if ImageFormat::from_path(path).is_ok() {
+                    return Ok(output.to_owned());
+                }
+            }
+            Ok(meta) => {
+                if !meta.is_dir() {
+                    return Ok(output.to_owned());
+                }
+            }
+        }
+        parent_dir = Some(PathBuf::from(output));
+    }
+
+    let mut output = OsString::new();
+
+    if let Some(parent) = parent_dir.as_deref().or_else(|| input_path.parent()) {
+        let parent = parent.as_os_str();
+        if !parent.is_empty() {
+            output.push(parent);
+            output.push(std::path::MAIN_SEPARATOR.to_string());
+        }
+    }
+
+    if let Some((stem, _)) = split_scale_suffix(input).filter(|_| args.rename_downscaled) {
+        // without the scale the name differs from the input anyway
+        output.push(stem);
+        output.push(".");
+    } else {
+        if let Some(stem) = input_path.file_stem() {
+            output.push(stem);
+        } else {
+            output.push("pixelart");
+        }
+        output.push(".scaled.");
+    }
+    output.push(extension);
+
+    Ok(output)
+}
+
+/// Whether both paths exist and lead to the same file.
+fn is_same_file(a: &OsStr, b: &OsStr) -> bool {
+    match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Splits a scale given at the end of the name of a file, like `@3x`, `_x3`,
+/// or `_3x`, off its stem. Returns the stem without it and the scale.
+fn split_scale_suffix(path: &OsStr) -> Option<(&str, u32)> {
+    let stem = Path::new(path).file_stem()?.to_str()?;
+    let parse = |digits: &str| -> Option<u32> {
+        if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok().filter(|&scale| scale > 0)
+    };
+    let (base, scale) = if let Some((base, suffix)) = stem.rsplit_once('@') {
+        (base, suffix.strip_suffix(['x', 'X']).and_then(parse)?)
+    } else {
+        let (base, suffix) = stem.rsplit_once('_')?;
+        let scale = suffix.strip_prefix(['x', 'X']).and_then(parse)
+            .or_else(|| suffix.strip_suffix(['x', 'X']).and_then(parse))?;
+        (base, scale)
+    };
+    (!base.is_empty()).then_some((base, scale))
+}
+
+/// Warns if the scale given in the file name differs from the detected one,
+/// for --use-name-hint check.
+fn check_name_hint(scale: u32, args: &Args) {
+    if args.use_name_hint != Some(NameHint::Check) {
+        return;
+    }
+    if let Some((_, hint)) = split_scale_suffix(&args.input).filter(|&(_, hint)| hint != scale) {
+        warn!("the file name gives a scale of {hint}, but a scale of {scale} was detected");
+    }
+}
+
+/// A decoded frame of an animation: delay, left, top, and the frame itself.
+type DecodedFrame = (Delay, u32, u32, DynamicImage);
+
+fn decode_frame(frame: Frame) -> DecodedFrame {
+    (frame.delay(), frame.left(), frame.top(), DynamicImage::from(frame.into_buffer()))
+}
+
+/// Whether a frame is a single color or fully transparent, so it says nothing
+/// about the scaling.
+fn is_blank(img: &DynamicImage) -> bool {
+    let mut pixels = img.pixels().map(|(_, _, pixel)| pixel);
+    let Some(first) = pixels.next() else {
+        return true;
+    };
+    pixels.all(|pixel| pixel == first || (pixel[3] == 0 && first[3] == 0))
+}
+
+/// Frames of an animation whose scaling is already detected. The frames that
+/// were needed for the detection are kept, the rest is decoded on demand.
+struct DetectedFrames<'a> {
+    decoded: std::vec::IntoIter<DecodedFrame>,
+    /// The frames that were spooled to a file with --spool-dir.
+    spooled: Option<SpooledFrames>,
+    remaining: Option<Frames<'a>>,
+    /// Index of the next remaining frame and the arguments, for --timings.
+    index: usize,
+    timings: Option<&'a Args>,
+}
+
+impl Iterator for DetectedFrames<'_> {
+    type Item = ImageResult<DecodedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(frame) = self.decoded.next() {
+            return Some(Ok(frame));
+        }
+        if let Some(spooled) = &mut self.spooled {
+            return spooled.next().map(|frame| {
+                let (delay, left, top, img) = frame?;
+                Ok((delay, left, top, DynamicImage::ImageRgba8(img)))
+            });
+        }
+        let start = Instant::now();
+        let frame = self.remaining.as_mut()?.next()?;
+        if let Some(args) = self.timings {
+            print_timing(format_args!("frame {}: decoded", self.index), start.elapsed(), args);
+        }
+        self.index += 1;
+        Some(frame.map(decode_frame))
+    }
+}
+
+/// Decodes the frames of an animation and detects their scaling. Breaks with
+/// the result if there is nothing more to do, e.g. because only the analysis
+/// was requested.
+///
+/// Only the frames needed for the detection are decoded up front. With
+/// `--only-analyze-first-frame` that is just the first one, with
+/// `--analyze-frames` the first ones that aren't blank, and with
+/// `--force-scale` none at all, so the rest can be decoded one at a time
+/// while writing. With `--spool-dir` all frames are decoded, but spooled to
+/// a file instead of being kept.
+fn decode_and_detect<'a>(width: u32, height: u32, mut input_frames: Frames<'a>, output_format: ImageFormat, args: &'a Args) -> ProcessResult<ControlFlow<Processed, (DetectedFrames<'a>, Grid)>> {
+    if args.split {
+        warn!("--split only applies to still images, scaling the animation as a whole");
+    }
+    if !args.also_scale.is_empty() {
+        warn!("--also-scale only applies to still images, writing only the scaled down animation");
+    }
+    let mut frames = Vec::new();
+    let mut spool = None;
+    let mut analysis = Analysis::default();
+    let grid = if let Some(scale) = args.force_scale {
+        forced_grid(scale, width, height, args)?
+    } else if args.upscaling() {
+        Grid { scale: Scale::square(1), x: 0, y: 0, pad: false, dither: false }
+    } else if let Some(dir) = &args.spool_dir {
+        let outside = check_region(width, height, args)?;
+        let (spooled, detector, first_canvas) = spool_and_detect(width, height, &mut input_frames, Path::new(dir), outside, args)?;
+        analysis.mat_color = detector.options().mat_color;
+        let (scale, frame_scales) = detector.finish();
+        let scale = if args.require_frame_agreement {
+            frame_disagreement(&frame_scales).map_or(scale, |list| {
+                warn!("frames disagree on the pixel art scaling: {list}");
+                None
+            })
+        } else {
+            check_frame_agreement(&frame_scales, args)?;
+            scale
+        };
+        let scale = match scale_or_fail(scale, width, height, frame_info(spooled.len(), args), &analysis, args)? {
+            ControlFlow::Continue(scale) => scale,
+            ControlFlow::Break(processed) => return Ok(ControlFlow::Break(processed)),
+        };
+        check_name_hint(scale, args);
+        let remainder = outside.remainder(scale);
+        let (x, y) = check_origin(Scale::square(scale), args)?.unwrap_or((remainder.left, remainder.top));
+        let grid = Grid { scale: Scale::square(scale), x, y, pad: args.pad, dither: false };
+        if let (Some(path), Some(canvas)) = (&args.debug_grid, first_canvas) {
+            write_debug_grid(&canvas, grid, outside, path)?;
+        }
+        spool = Some(spooled);
+        grid
+    } else {
+        let count = args.analyzed_frames().unwrap_or(usize::MAX);
+        let mut analyzed = 0;
+        while analyzed < count {
+            let start = Instant::now();
+            let Some(frame) = input_frames.next() else {
+                break;
+            };
+            let frame = decode_frame(frame?);
+            if args.analyze_frames.is_some() && is_blank(&frame.3) {
+                info!("frame {} is blank, not counting it towards --analyze-frames", frames.len());
+            } else {
+                analyzed += 1;
+            }
+            frames.push(frame);
+            print_timing(format_args!("frame {}: decoded", frames.len() - 1), start.elapsed(), args);
+        }
+        let start = Instant::now();
+        let outside = check_region(width, height, args)?;
+        let canvases = composite_frames(width, height, &frames);
+        let regions: Vec<_> = canvases.iter().map(|img| crop_border(img, outside)).collect();
+        let options = match regions.first() {
+            Some(first) => args.detect_options_for(first),
+            None => args.detect_options(),
+        };
+        analysis.mat_color = options.mat_color;
+        let solid_border = if args.ignore_border {
+            detect_border(regions.iter().map(|img| img.as_ref()), &options)
+        } else {
+            Border::default()
+        };
+        let interiors: Vec<_> = regions.iter().map(|img| crop_border(img, solid_border)).collect();
+        let cropped = add_borders(outside, solid_border);
+        let (interior_width, interior_height) = (
+            width - cropped.left - cropped.right,
+            height - cropped.top - cropped.bottom,
+        );
+        let images = interiors.iter().map(|img| img.as_ref());
+        let scale = if args.only_analyze_first_frame {
+            interiors.first().and_then(|img| detect_scale_with(img, &options))
+        } else if args.require_frame_agreement {
+            detect_agreed_scale(interior_width, interior_height, images, &options)
+        } else if frames.len() > 1 {
+            let (scale, frame_scales) = detect_scale_and_frame_scales(interior_width, interior_height, images, &options);
+            check_frame_agreement(&frame_scales, args)?;
+            scale
+        } else {
+            detect_scale_from_frames_with(interior_width, interior_height, images, &options)
+        };
+        let (scale, confidence) = confident_scale(scale, interiors.iter().map(|img| img.as_ref()), &options, args);
+        analysis.confidence = confidence;
+        if let Some(first) = interiors.first().filter(|_| scale.is_none()) {
+            print_chroma_warning(first, &options);
+        }
+        if scale.is_none() && args.estimate_scale {
+            print_scale_estimate(interiors.iter().map(|img| img.as_ref()), &options);
+        }
+        if args.only_analyze && args.json {
+            (analysis.runs_x, analysis.runs_y) = count_frame_runs(interiors.iter().map(|img| img.as_ref()), &options);
+        }
+        let scale = match scale_or_fail(scale, width, height, frame_info(frames.len(), args), &analysis, args)? {
+            ControlFlow::Continue(scale) => scale,
+            ControlFlow::Break(processed) => return Ok(ControlFlow::Break(processed)),
+        };
+        check_name_hint(scale, args);
+        let (x, y) = if let Some(origin) = check_origin(Scale::square(scale), args)? {
+            origin
+        } else if args.detect_phase {
+            let (x, y) = detect_phase_from_frames(regions.iter().map(|img| img.as_ref()), scale, &options);
+            ((outside.left + x) % scale, (outside.top + y) % scale)
+        } else {
+            let remainder = cropped.remainder(scale);
+            analysis.border = args.ignore_border.then_some(remainder);
+            (remainder.left, remainder.top)
+        };
+        (analysis.scale, analysis.phase) = (Some(scale), (x, y));
+        let grid = Grid { scale: Scale::square(scale), x, y, pad: args.pad, dither: false };
+        print_timing("detected", start.elapsed(), args);
+        if let (Some(path), Some(canvas)) = (&args.debug_grid, canvases.first()) {
+            write_debug_grid(canvas, grid, cropped, path)?;
+        }
+        grid
+    };
+
+    let upscale = check_upscale(width, height, args)?;
+    let (new_width, new_height) = grid.shrink_size(width, height);
+    let (new_width, new_height) = (new_width * upscale, new_height * upscale);
+    check_downscale((width, height), (new_width, new_height), args)?;
+    check_output_size(args, new_width, new_height)?;
+    print_remainder_warning(width, height, grid);
+    let frame_count = frames.len() + spool.as_ref().map_or(0, Spool::len);
+    // with --explode the delays are written to the directory of the frames instead
+    if args.sidecar && args.explode.is_none() {
+        let output = output_from(args, output_format)?;
+        write_sidecar(&output, (width, height), grid, &analysis, frame_info(frame_count, args), args)?;
+    }
+    if args.only_analyze {
+        print_analysis(new_width, new_height, Some(grid), &analysis, frame_info(frame_count, args), args);
+        return Ok(ControlFlow::Break(Processed::Scaled(grid.scale, None)));
+    }
+    if args.dry_run {
+        let output = output_from(args, output_format)?;
+        print_dry_run(&output, (width, height), (new_width, new_height), grid, args);
+        return Ok(ControlFlow::Break(Processed::Scaled(grid.scale, None)));
+    }
+
+    info!("resizing {width} x {height} -> {new_width} x {new_height}");
+    if let Some(aspect) = args.stretch() {
+        let (width, height) = aspect.stretch(new_width, new_height);
+        info!("stretching {new_width} x {new_height} -> {width} x {height}");
+    }
+
+    let remaining = (args.force_scale.is_some() || args.upscaling() || args.analyzed_frames().is_some()).then_some(input_frames);
+    let index = frames.len();
+    let spooled = spool.map(Spool::into_frames).transpose()?;
+    Ok(ControlFlow::Continue((DetectedFrames { decoded: frames.into_iter(), spooled, remaining, index, timings: args.timings.then_some(args) }, grid)))
+}
+
+/// How many decoded frames may wait to be written to the spool file.
+const SPOOL_QUEUE: usize = 4;
+
+/// Decodes all frames and detects their scaling for --spool-dir, while a
+/// second thread writes the frames to a spool file in `dir`. Only the frame
+/// being analyzed, the canvas it is drawn on, and the frames waiting to be
+/// written are in memory at once. Also returns the first canvas for --debug-grid.
+fn spool_and_detect(width: u32, height: u32, input_frames: &mut Frames, dir: &Path, outside: Border, args: &Args) -> ProcessResult<(Spool, FrameDetector, Option<DynamicImage>)> {
+    let mut spool = Spool::create(dir)?;
+    let (interior_width, interior_height) = (width - outside.left - outside.right, height - outside.top - outside.bottom);
+    // created for the first frame, --mat-color auto takes the color from it
+    let mut detector = None;
+    let mut first_canvas = None;
+    let (sender, receiver) = mpsc::sync_channel::<(Delay, u32, u32, Arc<DynamicImage>)>(SPOOL_QUEUE);
+    let (decoded, written) = thread::scope(|scope| {
+        let spool = &mut spool;
+        let writer = scope.spawn(move || -> std::io::Result<()> {
+            for (delay, left, top, frame) in receiver {
+                match frame.as_rgba8() {
+                    Some(rgba) => spool.push(delay, left, top, rgba)?,
+                    None => spool.push(delay, left, top, &frame.to_rgba8())?,
+                }
+            }
+            Ok(())
+        });
+        let mut decode = || -> ProcessResult<()> {
+            let mut previous: Option<Arc<DynamicImage>> = None;
+            for (index, frame) in input_frames.enumerate() {
+                let start = Instant::now();
+                let (delay, left, top, img) = decode_frame(frame?);
+                print_timing(format_args!("frame {index}: decoded"), start.elapsed(), args);
+                let img = Arc::new(img);
+                let canvas = match composite_frame(width, height, previous.as_deref(), left, top, &img) {
+                    Some(canvas) => Arc::new(DynamicImage::ImageRgba8(canvas)),
+                    None => Arc::clone(&img),
+                };
+                let region = crop_border(&canvas, outside);
+                detector.get_or_insert_with(|| FrameDetector::new(interior_width, interior_height, args.detect_options_for(&region)))
+                    .feed_frame(&region);
+                if args.debug_grid.is_some() && first_canvas.is_none() {
+                    first_canvas = Some(DynamicImage::clone(&canvas));
+                }
+                previous = Some(canvas);
+                if sender.send((delay, left, top, img)).is_err() {
+                    // writing failed, the error is returned by the writer
+                    break;
+                }
+            }
+            Ok(())
+        };
+        let decoded = decode();
+        drop(sender);
+        (decoded, writer.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+    });
+    decoded?;
+    written?;
+    let detector = detector.unwrap_or_else(|| FrameDetector::new(interior_width, interior_height, args.detect_options()));
+    Ok((spool, detector, first_canvas))
+}
+
+fn frame_info(count: usize, args: &Args) -> FrameInfo {
+    let decoded_all = args.force_scale.is_none() && !args.upscaling() && args.analyzed_frames().is_none();
+    FrameInfo {
+        animated: true,
+        count: decoded_all.then_some(count),
+    }
+}
+
+/// Prints what would be written for --dry-run.
+fn print_dry_run(output: &OsStr, (width, height): (u32, u32), (new_width, new_height): (u32, u32), grid: Grid, args: &Args) {
+    println!("{:?} -> {output:?}: {width} x {height} -> {new_width} x {new_height}, scale {}", args.input, grid.scale);
+}
+
+/// Prints how long a step took for --timings.
+fn print_timing(step: impl std::fmt::Display, duration: Duration, args: &Args) {
+    if args.timings && args.in_batch {
+        note!("{:?}: {step} in {duration:.1?}", args.input);
+    } else if args.timings {
+        note!("{step} in {duration:.1?}");
+    }
+}
+
+/// Counts the runs of each length of all frames for --only-analyze --json.
+fn count_frame_runs<'a>(frames: impl IntoIterator<Item=&'a DynamicImage>, options: &DetectOptions) -> (Vec<u64>, Vec<u64>) {
+    let (mut runs_x, mut runs_y): (Vec<u64>, Vec<u64>) = (Vec::new(), Vec::new());
+    for frame in frames {
+        let (frame_x, frame_y) = count_runs(frame, options);
+        if runs_x.is_empty() {
+            (runs_x, runs_y) = (frame_x, frame_y);
+            continue;
+        }
+        runs_x.iter_mut().zip(frame_x).for_each(|(count, frame_count)| *count += frame_count);
+        runs_y.iter_mut().zip(frame_y).for_each(|(count, frame_count)| *count += frame_count);
+    }
+    (runs_x, runs_y)
+}
+
+/// Prints the result of --only-analyze. `grid` is `None` if no scaling was
+/// detected, the rest of what was detected is taken from `analysis`.
+fn print_analysis(width: u32, height: u32, grid: Option<Grid>, analysis: &Analysis, frames: FrameInfo, args: &Args) {
+    if args.json {
+        let mut json = format!("{{\"input\":{},\"detected\":{}", json_string(&args.input.to_string_lossy()), grid.is_some());
+        if let Some(grid) = grid {
+            json.push_str(&format!(",\"stride_x\":{},\"stride_y\":{}", grid.scale.x, grid.scale.y));
+            if args.dither_aware {
+                let tile = if grid.dither { DITHER_TILE_SIZE.to_string() } else { "null".to_owned() };
+                json.push_str(&format!(",\"dither_tile\":{tile}"));
+            }
+            if args.detect_phase || args.origin.is_some() {
+                json.push_str(&format!(",\"offset_x\":{},\"offset_y\":{}", grid.x, grid.y));
+            }
+            if let Some(border) = analysis.border {
+                json.push_str(&format!(
+                    ",\"offset_x\":{},\"offset_y\":{},\"border\":{{\"left\":{},\"top\":{},\"right\":{},\"bottom\":{}}}",
+                    grid.x, grid.y, border.left, border.top, border.right, border.bottom,
+                ));
+            }
+        } else {
+            json.push_str(",\"stride_x\":null,\"stride_y\":null");
+        }
+        let confidence_json = analysis.confidence.map_or_else(|| "null".to_owned(), |confidence| format!("{confidence:.3}"));
+        json.push_str(&format!(",\"confidence\":{confidence_json}"));
+        let count = frames.count.map_or_else(|| "null".to_owned(), |count| count.to_string());
+        json.push_str(&format!(",\"width\":{width},\"height\":{height},\"frames\":{count},\"animated\":{}", frames.animated));
+        if let Some([r, g, b]) = analysis.mat_color {
+            json.push_str(&format!(",\"mat_color\":[{r},{g},{b}]"));
+        }
+        json.push_str(&format!(",\"runs_x\":{},\"runs_y\":{}}}", runs_json(&analysis.runs_x), runs_json(&analysis.runs_y)));
+        println!("{json}");
+        return;
+    }
+
+    // keep stdout to the size, scripts read it
+    if let Some(confidence) = analysis.confidence {
+        note!("confidence {confidence:.3}");
+    }
+    if let Some([r, g, b]) = analysis.mat_color.filter(|_| args.mat_color == Some(MatColor::Auto)) {
+        note!("mat color {r},{g},{b}");
+    }
+    if let Some(grid) = grid.filter(|_| args.detect_phase || args.ignore_border || args.origin.is_some()) {
+        if args.in_batch {
+            print!("{}: ", args.input.to_string_lossy());
+        }
+        println!("{width}x{height}+{}+{}", grid.x, grid.y);
+    } else if args.in_batch {
+        println!("{}: {width}x{height}", args.input.to_string_lossy());
+    } else {
+        println!("{width}x{height}");
+    }
+}
+
+/// Writes the result of the detection of an image of the given size next to
+/// the output for --sidecar.
+fn write_sidecar(output: &OsStr, (source_width, source_height): (u32, u32), grid: Grid, analysis: &Analysis, frames: FrameInfo, args: &Args) -> std::io::Result<()> {
+    if is_stdout(output) {
+        warn!("output is stdout, not writing a sidecar file");
+        return Ok(());
+    }
+    let upscale = args.upscale_factor(source_width, source_height);
+    let (width, height) = grid.shrink_size(source_width, source_height);
+    let (width, height) = (width * upscale, height * upscale);
+    let mut json = format!(
+        "{{\"input\":{},\"source_width\":{source_width},\"source_height\":{source_height},\"stride_x\":{},\"stride_y\":{},\"offset_x\":{},\"offset_y\":{}",
+        json_string(&args.input.to_string_lossy()), grid.scale.x, grid.scale.y, grid.x, grid.y,
+    );
+    if let Some(border) = analysis.border {
+        json.push_str(&format!(
+            ",\"border\":{{\"left\":{},\"top\":{},\"right\":{},\"bottom\":{}}}",
+            border.left, border.top, border.right, border.bottom,
+        ));
+    } else {
+        json.push_str(",\"border\":null");
+    }
+    let confidence_json = analysis.confidence.map_or_else(|| "null".to_owned(), |confidence| format!("{confidence:.3}"));
+    let count = frames.count.map_or_else(|| "null".to_owned(), |count| count.to_string());
+    json.push_str(&format!(
+        ",\"confidence\":{confidence_json},\"width\":{width},\"height\":{height},\"frames\":{count},\"animated\":{}}}\n",
+        frames.animated,
+    ));
+    let mut path = output.to_owned();
+    path.push(".fixpixelart.json");
+    let mut writer = args.output_writer(&path)?;
+    writer.write_all(json.as_bytes())?;
+    writer.finish()
+}
+
+/// The counts of runs of each length as a JSON object of the lengths that
+/// occur, e.g. {"3":120,"6":14}.
+fn runs_json(runs: &[u64]) -> String {
+    let counts: Vec<_> = runs.iter().enumerate()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(len, count)| format!("\"{len}\":{count}"))
+        .collect();
+    format!("{{{}}}", counts.join(","))
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"'  => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            ch if ch.is_control() => json.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Warns about the partial pixels at the edges if the image doesn't fit the grid.
+fn print_remainder_warning(width: u32, height: u32, grid: Grid) {
+    let (left, top) = grid.leading();
+    let (new_width, new_height) = grid.shrink_size(width, height);
+    let action = if grid.pad { "padding" } else { "cropping" };
+    let axes = [
+        ("width",  width,  grid.scale.x, grid.x, left, new_width),
+        ("height", height, grid.scale.y, grid.y, top,  new_height),
+    ];
+    for (name, size, stride, offset, leading, new_size) in axes {
+        // a forced scale may be larger than the image, which still yields a pixel
+        let trailing = if grid.pad {
+            (new_size * stride).saturating_sub(size + leading)
+        } else {
+            size.saturating_sub(leading + new_size * stride)
+        };
+        if offset != 0 {
+            warn!("{name} {size} with the grid starting at {offset} and stride {stride}, {action} {leading}px at the start and {trailing}px at the end");
+        } else if trailing != 0 {
+            warn!("{name} {size} not divisible by stride {stride}, {action} {trailing}px");
+        }
+    }
+}
+
+/// Frames as they are shown on the canvas, for the detection. A frame that
+/// doesn't cover the canvas would otherwise yield runs that end at its own
+/// edges instead of at the edges of pixels.
+///
+/// The image crate already composites the frames of GIF, APNG and WebP
+/// animations, so these are borrowed as they are. Other frames are drawn over
+/// the previous ones. Frames don't tell their disposal method, so nothing is
+/// disposed.
+fn composite_frames(width: u32, height: u32, frames: &[DecodedFrame]) -> Vec<Cow<'_, DynamicImage>> {
+    let mut canvases: Vec<Cow<DynamicImage>> = Vec::with_capacity(frames.len());
+    for (_, left, top, img) in frames {
+        let canvas = match composite_frame(width, height, canvases.last().map(|canvas| canvas.as_ref()), *left, *top, img) {
+            Some(canvas) => Cow::Owned(DynamicImage::ImageRgba8(canvas)),
+            None => Cow::Borrowed(img),
+        };
+        canvases.push(canvas);
+    }
+    canvases
+}
+
+/// Draws a frame at its position over the previous canvas. Returns `None` if
+/// the frame covers the whole canvas, so it is a canvas of its own.
+fn composite_frame(width: u32, height: u32, previous: Option<&DynamicImage>, left: u32, top: u32, img: &DynamicImage) -> Option<RgbaImage> {
+    if left == 0 && top == 0 && img.dimensions() == (width, height) {
+        return None;
+    }
+    let mut canvas = match previous {
+        Some(previous) => previous.to_rgba8(),
+        None => RgbaImage::new(width, height),
+    };
+    imageops::overlay(&mut canvas, &img.to_rgba8(), left as i64, top as i64);
+    Some(canvas)
+}
+
+/// Writes the image with the lines of the pixel grid drawn in the inverted
+/// color of the pixels they cover. The border that was cropped for the
+/// detection is darkened. Transparent areas are shown over a checkerboard.
+fn write_debug_grid(img: &DynamicImage, grid: Grid, cropped: Border, path: &Path) -> ImageResult<()> {
+    let (width, height) = img.dimensions();
+    let mut overlay = composite_over_checkerboard(img);
+    for (x, y, pixel) in overlay.enumerate_pixels_mut() {
+        let Rgba([red, green, blue, alpha]) = *pixel;
+        let on_line =
+            (grid.scale.x > 1 && x % grid.scale.x == grid.x) ||
+            (grid.scale.y > 1 && y % grid.scale.y == grid.y);
+        let in_border =
+            x < cropped.left || x >= width - cropped.right ||
+            y < cropped.top || y >= height - cropped.bottom;
+        if on_line {
+            *pixel = Rgba([255 - red, 255 - green, 255 - blue, 255]);
+        } else if in_border {
+            *pixel = Rgba([red / 2, green / 2, blue / 2, alpha]);
+        }
+    }
+    overlay.save(path)?;
+    success!("written debug grid to {path:?}");
+    Ok(())
+}
+
+/// Size of the squares of the checkerboard behind transparent areas of previews.
+const CHECKERBOARD_SQUARE: u32 = 8;
+
+/// Blends the image over a checkerboard of light and dark gray, so transparent
+/// areas of a preview don't look black. The result is opaque.
+fn composite_over_checkerboard(img: &DynamicImage) -> RgbaImage {
+    let mut rgba = img.to_rgba8();
+    for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+        let dark = (x / CHECKERBOARD_SQUARE + y / CHECKERBOARD_SQUARE) % 2 == 1;
+        let background: u32 = if dark { 153 } else { 204 };
+        let Rgba([red, green, blue, alpha]) = *pixel;
+        let alpha = u32::from(alpha);
+        let blend = |channel: u8| ((u32::from(channel) * alpha + background * (255 - alpha) + 127) / 255) as u8;
+        *pixel = Rgba([blend(red), blend(green), blend(blue), 255]);
+    }
+    rgba
+}
+
+/// Border of a border cropped from an image and then a border cropped from what remains.
+fn add_borders(outer: Border, inner: Border) -> Border {
+    Border {
+        left: outer.left + inner.left,
+        top: outer.top + inner.top,
+        right: outer.right + inner.right,
+        bottom: outer.bottom + inner.bottom,
+    }
+}
+
+/// Crops the border off the image, for detecting the scaling of what is inside.
+fn crop_border(img: &DynamicImage, border: Border) -> Cow<'_, DynamicImage> {
+    if border.is_empty() {
+        return Cow::Borrowed(img);
+    }
+    let (width, height) = img.dimensions();
+    Cow::Owned(img.crop_imm(
+        border.left,
+        border.top,
+        width - border.left - border.right,
+        height - border.top - border.bottom,
+    ))
+}
+
+/// Makes the image fit the grid, so the grid isn't shifted when scaling it
+/// down. Either crops the partial pixels at the edges or completes them by
+/// repeating the edge pixels.
+fn fit_to_grid(img: &DynamicImage, grid: Grid) -> Cow<'_, DynamicImage> {
+    let (width, height) = img.dimensions();
+    let (left, top) = grid.leading();
+    let (new_width, new_height) = grid.shrink_size(width, height);
+    let (fitted_width, fitted_height) = (new_width * grid.scale.x, new_height * grid.scale.y);
+    if left == 0 && top == 0 && fitted_width == width && fitted_height == height {
+        return Cow::Borrowed(img);
+    }
+    if !grid.pad {
+        return Cow::Owned(img.crop_imm(left, top, fitted_width, fitted_height));
+    }
+    let padded = RgbaImage::from_fn(fitted_width, fitted_height, |x, y| {
+        img.get_pixel(x.saturating_sub(left).min(width - 1), y.saturating_sub(top).min(height - 1))
+    });
+    Cow::Owned(DynamicImage::ImageRgba8(padded))
+}
+
+/// Scales a frame that is positioned at `left`, `top` down to the grid, up by
+/// `upscale`, and applies the pixel aspect ratio if it shall be stretched.
+fn resize_frame(img: &DynamicImage, left: u32, top: u32, grid: Grid, upscale: u32, args: &Args) -> (RgbaImage, u32, u32) {
+    let mut buffer = downscale_frame(&fit_to_grid(img, grid), grid, args);
+    let (mut left, mut top) = grid.shrink(left, top);
+    if upscale > 1 {
+        buffer = imageops::resize(&buffer, buffer.width() * upscale, buffer.height() * upscale, FilterType::Nearest);
+        (left, top) = (left * upscale, top * upscale);
+    }
+    if let Some(aspect) = args.stretch() {
+        let (width, height) = aspect.stretch(buffer.width(), buffer.height());
+        buffer = imageops::resize(&buffer, width, height, FilterType::Nearest);
+        (left, top) = aspect.stretch(left, top);
+    }
+    (buffer, left, top)
+}
+
+/// Scales a frame that is already fitted to the grid down to its native resolution.
+fn downscale_frame(img: &DynamicImage, grid: Grid, args: &Args) -> RgbaImage {
+    if let Some(filter) = args.filter.filter_type() {
+        imageops::resize(img, (img.width() / grid.scale.x).max(1), (img.height() / grid.scale.y).max(1), filter)
+    } else {
+        downscale_by_stride(img, grid.scale.x, grid.scale.y, args.sample.sample())
+    }
+}
+
+/// Scales the image scaled down to the grid back up and compares it to the
+/// image fitted to the grid, for --verify. Returns `None` if they are equal,
+/// otherwise their PSNR in dB.
+fn verify_downscale(fitted: &DynamicImage, scaled: &DynamicImage) -> Option<f64> {
+    let upscaled = imageops::resize(&scaled.to_rgba16(), fitted.width(), fitted.height(), FilterType::Nearest);
+    let fitted = fitted.to_rgba16();
+    if upscaled == fitted {
+        return None;
+    }
+    let squared_error: f64 = upscaled.as_raw().iter().zip(fitted.as_raw())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mean_squared_error = squared_error / fitted.as_raw().len() as f64;
+    Some(10.0 * ((u16::MAX as f64).powi(2) / mean_squared_error).log10())
+}
+
+/// A frame scaled down to the grid and its position on the canvas.
+type ResizedFrame = (Delay, RgbaImage, u32, u32);
+
+type ResizedFrames<'a> = Box<dyn Iterator<Item=ImageResult<ResizedFrame>> + 'a>;
+
+/// Scales the frames down to the grid and returns them with the size of the
+/// canvas. Usually the frames are scaled down one at a time while writing, but
+/// with --trim they are all scaled down up front, since the trim box has to
+/// cover all frames. The trim box is returned as well, if the frames are
+/// trimmed.
+fn resize_frames<'a>(frames: DetectedFrames<'a>, width: u32, height: u32, grid: Grid, args: &'a Args) -> ImageResult<(ResizedFrames<'a>, (u32, u32), Option<TrimBox>)> {
+    let stretch = args.stretch();
+    let upscale = args.upscale_factor(width, height);
+    let (mut canvas_width, mut canvas_height) = grid.shrink_size(width, height);
+    (canvas_width, canvas_height) = (canvas_width * upscale, canvas_height * upscale);
+    if let Some(aspect) = stretch {
+        (canvas_width, canvas_height) = aspect.stretch(canvas_width, canvas_height);
+    }
+    let resized = frames.enumerate().map(move |(index, frame)| {
+        let (delay, left, top, img) = frame?;
+        let start = Instant::now();
+        let (buffer, left, top) = resize_frame(&img, left, top, grid, upscale, args);
+        if args.verify {
+            let fitted = fit_to_grid(&img, grid);
+            let scaled = DynamicImage::ImageRgba8(downscale_frame(&fitted, grid, args));
+            if let Some(psnr) = verify_downscale(&fitted, &scaled) {
+                warn!("frame {index}: scaled back up it differs from the input, PSNR {psnr:.2} dB");
+            }
+        }
+        verbose!("frame {index}: scaled in {:.1?}", start.elapsed());
+        print_timing(format_args!("frame {index}: scaled"), start.elapsed(), args);
+        Ok((delay, buffer, left, top))
+    });
+    if !args.trim {
+        return Ok((Box::new(resized), (canvas_width, canvas_height), None));
+    }
+
+    let resized = resized.collect::<ImageResult<Vec<_>>>()?;
+    let trim = resized.iter()
+        .filter_map(|(_, buffer, left, top)| TrimBox::of(buffer, *left, *top))
+        .reduce(TrimBox::union);
+    let Some(trim) = trim else {
+        warn!("all frames are fully transparent, not trimming");
+        return Ok((Box::new(resized.into_iter().map(Ok)), (canvas_width, canvas_height), None));
+    };
+    info!("trimming {canvas_width} x {canvas_height} -> {} x {}", trim.width(), trim.height());
+    let trimmed = resized.into_iter().map(move |(delay, buffer, left, top)| {
+        let (buffer, left, top) = trim.apply(&buffer, left, top);
+        Ok((delay, buffer, left, top))
+    });
+    Ok((Box::new(trimmed), (trim.width(), trim.height()), Some(trim)))
+}
+
+/// Area of the canvas that is kept by --trim. `right` and `bottom` are exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TrimBox {
+    left: u32,
+    top: u32,
+    right: u32,
+    bottom: u32,
+}
+
+impl TrimBox {
+    /// Bounding box of the pixels that aren't fully transparent of an image
+    /// at the given position. `None` if the image is fully transparent.
+    fn of<T: Primitive>(img: &ImageBuffer<Rgba<T>, Vec<T>>, left: u32, top: u32) -> Option<TrimBox>
+    where
+        Rgba<T>: Pixel<Subpixel = T>,
+    {
+        let mut trim: Option<TrimBox> = None;
+        for (x, y, pixel) in img.enumerate_pixels() {
+            if pixel[3] == T::DEFAULT_MIN_VALUE {
+                continue;
+            }
+            let (x, y) = (left + x, top + y);
+            let pixel_box = TrimBox { left: x, top: y, right: x + 1, bottom: y + 1 };
+            trim = Some(trim.map_or(pixel_box, |trim| trim.union(pixel_box)));
+        }
+        trim
+    }
+
+    fn union(self, other: TrimBox) -> TrimBox {
+        TrimBox {
+            left: self.left.min(other.left),
+            top: self.top.min(other.top),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+
+    fn width(self) -> u32 {
+        self.right - self.left
+    }
+
+    fn height(self) -> u32 {
+        self.bottom - self.top
+    }
+
+    /// Crops an image at the given position to the box. Returns the cropped
+    /// image and its position relative to the box. An image that lies
+    /// completely outside of the box becomes a single transparent pixel.
+    fn apply(self, img: &RgbaImage, left: u32, top: u32) -> (RgbaImage, u32, u32) {
+        let (x0, y0) = (left.max(self.left), top.max(self.top));
+        let (x1, y1) = ((left + img.width()).min(self.right), (top + img.height()).min(self.bottom));
+        if x0 >= x1 || y0 >= y1 {
+            return (RgbaImage::new(1, 1), 0, 0);
+        }
+        let cropped = imageops::crop_imm(img, x0 - left, y0 - top, x1 - x0, y1 - y0).to_image();
+        (cropped, x0 - self.left, y0 - self.top)
+    }
+}
+
+/// Writes an animated GIF. If given, `background_color` is set as the
+/// background color of the logical screen.
+fn resize_as_animated_gif(width: u32, height: u32, input_frames: Frames, repeat: Repeat, background_color: Option<[u8; 3]>, icc_profile: Option<Vec<u8>>, args: Args) -> ProcessResult<Processed> {
+    let (frames, grid) = match decode_and_detect(width, height, input_frames, ImageFormat::Gif, &args)? {
+        ControlFlow::Continue(detected) => detected,
+        ControlFlow::Break(processed) => return Ok(processed),
+    };
+
+    let output = output_from(&args, ImageFormat::Gif)?;
+    let input_metadata = input_metadata(&args)?;
+    let stretch = args.stretch();
+    if stretch.is_none() && args.pixel_aspect.is_some() {
+        print_pixel_aspect_warning(ImageFormat::Gif);
+    }
+    if icc_profile.is_some() {
+        print_icc_profile_warning(ImageFormat::Gif);
+    }
+    let (frames, _, _) = resize_frames(frames, width, height, grid, &args)?;
+    let mut writer = args.output_writer(&output)?;
+    let mut encoder = GifEncoder::new(&mut writer);
+    let mut frames = frames.peekable();
+    let mut frame_count = 0;
+    let mut quantized_count = 0;
+    let progress = Progress::new("frames", args.frame_count, args.frame_progress());
+    while let Some(frame) = frames.next() {
+        let (delay, mut buffer, left, top) = frame?;
+        if quantize(&mut buffer, args.gif_colors as usize) {
+            quantized_count += 1;
+        }
+        // Only animations loop. Whether there is more than one frame is only
+        // known once the next frame is decoded.
+        if frame_count == 0 && frames.peek().is_some() {
+            encoder.set_repeat(repeat)?;
+        }
+        // The decoder already applied the disposal methods of the input, every
+        // frame covers the whole canvas. So the encoder disposing each frame to
+        // the background before the next one doesn't change what is displayed.
+        let start = Instant::now();
+        encoder.encode_frame(Frame::from_parts(buffer, left, top, gif_delay(delay, args.min_delay)))?;
+        print_timing(format_args!("frame {frame_count}: encoded"), start.elapsed(), &args);
+        frame_count += 1;
+        progress.inc();
+    }
+    drop(progress);
+    drop(encoder);
+    if let Some(color) = background_color.filter(|_| frame_count > 0) {
+        write_background_color(&mut writer, color)?;
+    }
+    writer.finish()?;
+    if quantized_count > 0 {
+        warn!("{quantized_count} of {frame_count} frames have more than {} colors, quantized to fit into a GIF palette", args.gif_colors);
+    }
+    let sizes = finish_output(input_metadata.as_ref(), &output, &args)?;
+    Ok(Processed::Scaled(grid.scale, sizes))
+}
+
+/// Writes an animated PNG. If given, `default_image` is written as the default
+/// image that isn't part of the animation, which viewers without APNG support
+/// display instead.
+fn resize_as_apng(width: u32, height: u32, input_frames: Frames, repeat: Repeat, default_image: Option<DynamicImage>, icc_profile: Option<Vec<u8>>, args: Args) -> ProcessResult<Processed> {
+    let (frames, grid) = match decode_and_detect(width, height, input_frames, ImageFormat::Png, &args)? {
+        ControlFlow::Continue(detected) => detected,
+        ControlFlow::Break(processed) => return Ok(processed),
+    };
+    let (frames, (canvas_width, canvas_height), trim) = resize_frames(frames, width, height, grid, &args)?;
+    // The number of frames has to be written before the first frame.
+    let frames = frames.collect::<ImageResult<Vec<_>>>()?;
+    if frames.is_empty() {
+        return Err(FixPixelartError::NoFrames);
+    }
+
+    let output = output_from(&args, ImageFormat::Png)?;
+    let input_metadata = input_metadata(&args)?;
+    let stretch = args.stretch();
+
+    let mut output_writer = args.output_writer(&output)?;
+    let mut encoder = png_encoder(&mut output_writer, canvas_width, canvas_height, icc_profile.as_deref())?;
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    if stretch.is_none() {
+        encoder.set_pixel_dims(args.pixel_aspect.map(PixelAspect::pixel_dims));
+    }
+    let num_plays = match repeat {
+        Repeat::Infinite => 0,
+        Repeat::Finite(count) => count as u32,
+    };
+    encoder.set_animated(frames.len() as u32, num_plays).map_err(png_error)?;
+    encoder.set_sep_def_img(default_image.is_some()).map_err(png_error)?;
+    let mut writer = encoder.write_header().map_err(png_error)?;
+
+    if let Some(img) = default_image {
+        let (mut buffer, _, _) = resize_frame(&img, 0, 0, grid, args.upscale_factor(width, height), &args);
+        if let Some(trim) = trim {
+            // the default image has to cover the whole canvas
+            buffer = imageops::crop_imm(&buffer, trim.left, trim.top, canvas_width, canvas_height).to_image();
+        }
+        writer.write_image_data(buffer.as_raw()).map_err(png_error)?;
+    }
+
+    let progress = Progress::new("frames", Some(frames.len()), args.frame_progress());
+    for (index, (delay, buffer, left, top)) in frames.into_iter().enumerate() {
+        let start = Instant::now();
+        if buffer.dimensions() != (canvas_width, canvas_height) || left != 0 || top != 0 {
+            writer.set_frame_dimension(buffer.width(), buffer.height()).map_err(png_error)?;
+            writer.set_frame_position(left, top).map_err(png_error)?;
+        } else {
+            writer.reset_frame_dimension().map_err(png_error)?;
+            writer.reset_frame_position().map_err(png_error)?;
+        }
+        let (numer, denom) = apng_delay(delay);
+        writer.set_frame_delay(numer, denom).map_err(png_error)?;
+        writer.write_image_data(buffer.as_raw()).map_err(png_error)?;
+        print_timing(format_args!("frame {index}: encoded"), start.elapsed(), &args);
+        progress.inc();
+    }
+    drop(progress);
+    writer.finish().map_err(png_error)?;
+    output_writer.finish()?;
+
+    let sizes = finish_output(input_metadata.as_ref(), &output, &args)?;
+    Ok(Processed::Scaled(grid.scale, sizes))
+}
+
+fn resize_as_animated_webp(width: u32, height: u32, input_frames: Frames, repeat: Repeat, icc_profile: Option<Vec<u8>>, args: Args) -> ProcessResult<Processed> {
+    let (frames, grid) = match decode_and_detect(width, height, input_frames, ImageFormat::WebP, &args)? {
+        ControlFlow::Continue(detected) => detected,
+        ControlFlow::Break(processed) => return Ok(processed),
+    };
+
+    let output = output_from(&args, ImageFormat::WebP)?;
+    let input_metadata = input_metadata(&args)?;
+    let stretch = args.stretch();
+    if stretch.is_none() && args.pixel_aspect.is_some() {
+        print_pixel_aspect_warning(ImageFormat::WebP);
+    }
+    let (frames, (canvas_width, canvas_height), _) = resize_frames(frames, width, height, grid, &args)?;
+    let loop_count = match repeat {
+        Repeat::Infinite => 0,
+        Repeat::Finite(count) => count,
+    };
+
+    let mut writer = args.output_writer(&output)?;
+    let mut encoder = AnimatedWebPEncoder::new(&mut writer, canvas_width, canvas_height, loop_count);
+    if let Some(icc_profile) = icc_profile {
+        encoder.set_icc_profile(icc_profile);
+    }
+    let progress = Progress::new("frames", args.frame_count, args.frame_progress());
+    for (index, frame) in frames.enumerate() {
+        let (delay, buffer, left, top) = frame?;
+        let start = Instant::now();
+        encoder.add_frame(&buffer, left, top, delay)?;
+        print_timing(format_args!("frame {index}: encoded"), start.elapsed(), &args);
+        progress.inc();
+    }
+    drop(progress);
+    encoder.finish()?;
+    writer.finish()?;
+
+    let sizes = finish_output(input_metadata.as_ref(), &output, &args)?;
+    Ok(Processed::Scaled(grid.scale, sizes))
+}
+
+/// Rounds a delay to the centiseconds used by GIF, which the encoder would
+/// truncate. A delay that isn't zero doesn't become zero, which would make
+/// the frame play as fast as the viewer allows. Delays are at least
+/// `min_delay` milliseconds.
+fn gif_delay(delay: Delay, min_delay: u32) -> Delay {
+    let (numer, denom) = delay.numer_denom_ms();
+    let (numer, denom) = (numer as u64, denom as u64 * 10);
+    let mut centis = (numer + denom / 2) / denom;
+    if numer > 0 {
+        centis = centis.max(1);
+    }
+    let centis = centis.max(min_delay.div_ceil(10) as u64).min(u16::MAX as u64) as u32;
+    Delay::from_numer_denom_ms(centis * 10, 1)
+}
+
+/// Converts a delay to the fraction of seconds used by APNG.
+fn apng_delay(delay: Delay) -> (u16, u16) {
+    let (numer, denom) = delay.numer_denom_ms();
+    let (numer, denom) = (numer as u64, denom as u64 * 1000);
+    let divisor = gcd(numer, denom).max(1);
+    let (numer, denom) = (numer / divisor, denom / divisor);
+    if let (Ok(numer), Ok(denom)) = (u16::try_from(numer), u16::try_from(denom)) {
+        return (numer, denom);
+    }
+    let millis = (numer * 1000 + denom / 2) / denom;
+    (millis.min(u16::MAX as u64) as u16, 1000)
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Reduces the image to at most `max_colors` colors if it has more.
+/// All fully transparent pixels count as one color, just like in a GIF palette.
+/// Returns whether the image was quantized.
+fn quantize(img: &mut RgbaImage, max_colors: usize) -> bool {
+    let mut colors = HashSet::new();
+    let mut has_transparency = false;
+    for pixel in img.pixels() {
+        if pixel[3] == 0 {
+            has_transparency = true;
+        } else {
+            colors.insert([pixel[0], pixel[1], pixel[2]]);
+        }
+    }
+
+    let max_opaque_colors = if has_transparency { max_colors - 1 } else { max_colors };
+    if colors.len() <= max_opaque_colors {
+        return false;
+    }
+
+    let opaque = img.pixels()
+        .filter(|pixel| pixel[3] != 0)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+        .collect::<Vec<_>>();
+    let quantizer = NeuQuant::new(10, max_opaque_colors.max(1), &opaque);
+    for pixel in img.pixels_mut() {
+        if pixel[3] != 0 {
+            pixel[3] = 255;
+            quantizer.map_pixel(&mut pixel.0);
+        }
+    }
+
+    true
+}
+
+fn print_pixel_aspect_warning(output_format: ImageFormat) {
+    let format_name = output_format.extensions_str()[0].to_ascii_uppercase();
+    warn!("{format_name} images can't store the pixel aspect ratio, use --stretch-aspect to apply it to the image instead");
+}
+
+fn print_icc_profile_warning(output_format: ImageFormat) {
+    let format_name = output_format.extensions_str()[0].to_ascii_uppercase();
+    warn!("{format_name} images can't store the ICC color profile of the input, dropping it");
+}
+
+/// Warns about what writing a still image as `output_format` loses of it, see
+/// [`output_losses()`]. Fails instead with --strict.
+fn check_output_losses(img: &DynamicImage, output_format: ImageFormat, args: &Args) -> ProcessResult<()> {
+    let output = output_from(args, output_format)?;
+    let losses = output_losses(img, output_format, &output);
+    if losses.is_empty() {
+        return Ok(());
+    }
+    // the PNM subtypes differ in what they can store
+    let format_name = match Path::new(&output).extension().filter(|_| output_format == ImageFormat::Pnm) {
+        Some(extension) => extension.to_string_lossy().to_ascii_uppercase(),
+        None => output_format.extensions_str()[0].to_ascii_uppercase(),
+    };
+    let message = format!("{format_name} output {}", losses.join(" and "));
+    if args.strict {
+        return Err(FixPixelartError::LossyOutput(message));
+    }
+    warn!("{message}");
+    Ok(())
+}
+
+/// What writing an image as `output_format` loses of it, the way images are
+/// written here, e.g. "drops the alpha channel". Only what the image makes use
+/// of counts, so an alpha channel that is opaque everywhere isn't lost. For PNM
+/// it depends on the subtype given by the extension of `output`.
+fn output_losses(img: &DynamicImage, output_format: ImageFormat, output: &OsStr) -> Vec<&'static str> {
+    let pnm_subtype = Path::new(output).extension()
+        .filter(|_| output_format == ImageFormat::Pnm)
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let pnm_subtype = pnm_subtype.as_deref();
+    // Only PNG is written with 16 bits per channel, and of the others only
+    // GIF, JPEG, and the PNM subtypes but PAM can't store the alpha channel.
+    let drops_alpha = output_format == ImageFormat::Jpeg || matches!(pnm_subtype, Some("pbm" | "pgm" | "ppm"));
+    let binary_alpha = output_format == ImageFormat::Gif;
+    let drops_color = matches!(pnm_subtype, Some("pbm" | "pgm"));
+    let drops_depth = output_format != ImageFormat::Png && img.color().bytes_per_pixel() > img.color().channel_count();
+
+    let mut losses = Vec::new();
+    if !(drops_alpha || binary_alpha || drops_color || drops_depth) {
+        return losses;
+    }
+    let rgba = img.to_rgba16();
+    let opaque = u16::MAX;
+    if drops_alpha && img.color().has_alpha() && rgba.pixels().any(|pixel| pixel[3] != opaque) {
+        losses.push("drops the alpha channel");
+    } else if binary_alpha && img.color().has_alpha() && rgba.pixels().any(|pixel| pixel[3] != 0 && pixel[3] != opaque) {
+        losses.push("keeps only fully transparent and fully opaque pixels");
+    }
+    if pnm_subtype == Some("pbm") && rgba.pixels().any(|pixel| pixel.0[..3] != [0; 3] && pixel.0[..3] != [opaque; 3]) {
+        losses.push("reduces the colors to black and white");
+    } else if drops_color && rgba.pixels().any(|pixel| pixel[0] != pixel[1] || pixel[1] != pixel[2]) {
+        losses.push("drops the colors");
+    }
+    // 8 bit samples scaled up to 16 bits are multiples of 257, those lose nothing
+    let is_float = matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+    if drops_depth && (is_float || rgba.pixels().any(|pixel| pixel.0.iter().any(|&sample| sample % 257 != 0))) {
+        losses.push("reduces the bit depth to 8 bits per channel");
+    }
+    losses
+}
+
+/// A PNG encoder that embeds the ICC color profile, if any, as an `iCCP` chunk.
+fn png_encoder<W: Write>(writer: W, width: u32, height: u32, icc_profile: Option<&[u8]>) -> ImageResult<png::Encoder<'_, W>> {
+    let mut info = png::Info::with_size(width, height);
+    info.icc_profile = icc_profile.map(Cow::Borrowed);
+    png::Encoder::with_info(writer, info).map_err(png_error)
+}
+
+/// Writes a PNG, indexed if a palette is given that has all the colors of the
+/// image. Images with 16 bits per channel are written as such.
+fn write_png(img: &DynamicImage, pixel_aspect: Option<PixelAspect>, palette: Option<&Palette>, icc_profile: Option<&[u8]>, writer: impl Write) -> ImageResult<()> {
+    let mut encoder = png_encoder(writer, img.width(), img.height(), icc_profile)?;
+    let indexed = palette.zip(img.as_rgba8()).and_then(|(palette, rgba)| Some((palette, palette.index(rgba)?)));
+    if palette.is_some() && indexed.is_none() {
+        warn!("image has colors that aren't in the palette of the input, writing a truecolor PNG");
+    }
+    if let Some((palette, _)) = indexed {
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(palette.bit_depth);
+        encoder.set_palette(palette.colors.as_slice());
+        if let Some(alpha) = &palette.alpha {
+            encoder.set_trns(alpha.as_slice());
+        }
+    } else {
+        encoder.set_color(png::ColorType::Rgba);
+    }
+    let data: Cow<[u8]> = match (indexed, img) {
+        (Some((_, data)), _) => Cow::Owned(data),
+        (None, DynamicImage::ImageRgba16(img)) => {
+            encoder.set_depth(png::BitDepth::Sixteen);
+            Cow::Owned(png_samples(img.as_raw()))
+        }
+        (None, DynamicImage::ImageRgba8(img)) => Cow::Borrowed(img.as_raw()),
+        (None, DynamicImage::ImageLuma8(img)) => {
+            encoder.set_color(png::ColorType::Grayscale);
+            Cow::Borrowed(img.as_raw())
+        }
+        (None, DynamicImage::ImageLumaA8(img)) => {
+            encoder.set_color(png::ColorType::GrayscaleAlpha);
+            Cow::Borrowed(img.as_raw())
+        }
+        (None, DynamicImage::ImageLuma16(img)) => {
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Sixteen);
+            Cow::Owned(png_samples(img.as_raw()))
+        }
+        (None, DynamicImage::ImageLumaA16(img)) => {
+            encoder.set_color(png::ColorType::GrayscaleAlpha);
+            encoder.set_depth(png::BitDepth::Sixteen);
+            Cow::Owned(png_samples(img.as_raw()))
+        }
+        (None, img) => Cow::Owned(img.to_rgba8().into_raw()),
+    };
+    encoder.set_pixel_dims(pixel_aspect.map(PixelAspect::pixel_dims));
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&data).map_err(png_error)?;
+    writer.finish().map_err(png_error)?;
+    Ok(())
+}
+
+/// PNG stores 16 bit samples in big endian.
+fn png_samples(samples: &[u16]) -> Vec<u8> {
+    samples.iter().flat_map(|sample| sample.to_be_bytes()).collect()
+}
+
+/// Writes the PNM subtype that belongs to the extension of the output, since
+/// they all share one format. Only PAM can carry transparency, so that is used
+/// for any other extension.
+fn write_pnm(img: &RgbaImage, output: &OsStr, writer: impl Write) -> ImageResult<()> {
+    let extension = Path::new(output).extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    let subtype = match extension.as_deref() {
+        Some("pbm") => PnmSubtype::Bitmap(SampleEncoding::Binary),
+        Some("pgm") => PnmSubtype::Graymap(SampleEncoding::Binary),
+        Some("ppm") => PnmSubtype::Pixmap(SampleEncoding::Binary),
+        _ => return PnmEncoder::new(writer).with_subtype(PnmSubtype::ArbitraryMap).write_image(img.as_raw(), img.width(), img.height(), ExtendedColorType::Rgba8),
+    };
+    let encoder = PnmEncoder::new(writer).with_subtype(subtype);
+    match subtype {
+        PnmSubtype::Bitmap(_) => {
+            // the encoder takes gray levels of 0 and 1, which it inverts for PBM
+            let gray: ImageBuffer<Luma<u8>, Vec<u8>> = img.convert();
+            let bits = ImageBuffer::from_fn(img.width(), img.height(), |x, y| Luma([(gray.get_pixel(x, y)[0] >= 128) as u8]));
+            encoder.write_image(bits.as_raw(), img.width(), img.height(), ExtendedColorType::L8)
+        }
+        PnmSubtype::Graymap(_) => {
+            let gray: ImageBuffer<Luma<u8>, Vec<u8>> = img.convert();
+            encoder.write_image(gray.as_raw(), img.width(), img.height(), ExtendedColorType::L8)
+        }
+        _ => {
+            let rgb: ImageBuffer<Rgb<u8>, Vec<u8>> = img.convert();
+            encoder.write_image(rgb.as_raw(), img.width(), img.height(), ExtendedColorType::Rgb8)
+        }
+    }
+}
+
+fn png_error(err: png::EncodingError) -> ImageError {
+    match err {
+        png::EncodingError::IoError(err) => ImageError::IoError(err),
+        err => ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Png), err)),
+    }
+}
+
+fn png_decoding_error(err: png::DecodingError) -> ImageError {
+    match err {
+        png::DecodingError::IoError(err) => ImageError::IoError(err),
+        err => ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Png), err)),
+    }
+}
+
+/// Asks whether to overwrite the existing output with --interactive.
+fn confirm_overwrite(output: &OsStr, args: &Args) -> std::io::Result<bool> {
+    // files of --recursive may be processed in parallel, so ask one at a time
+    static PROMPT: Mutex<()> = Mutex::new(());
+
+    if !args.interactive || !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    let _lock = PROMPT.lock().unwrap_or_else(|err| err.into_inner());
+    eprint!("overwrite {output:?}? [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Metadata of the input file, None if it was read from stdin or is an entry
+/// of --zip. Has to be read before the output is written, which might replace
+/// the input.
+fn input_metadata(args: &Args) -> std::io::Result<Option<Metadata>> {
+    if is_stdin(&args.input) || args.zip_entry(Path::new(&args.input)).is_some() {
+        return Ok(None);
+    }
+    Ok(Some(std::fs::metadata(&args.input)?))
+}
+
+/// Gives the output the modification time of the input if needed and reports
+/// that it was written.
+fn finish_output(input_metadata: Option<&Metadata>, output: &OsStr, args: &Args) -> ImageResult<Option<ByteSizes>> {
+    if is_stdout(output) {
+        success!("written to stdout");
+        return Ok(None);
+    }
+    // the frames of --frames-glob have no single input to compare the size with
+    let input_metadata = input_metadata.filter(|_| args.frames_glob.is_none());
+    if let Some(input_metadata) = input_metadata {
+        preserve_timestamp(input_metadata, output, args)?;
+    }
+    success!("written {output:?}");
+    let entry_size = args.zip_entry(Path::new(&args.input)).map(|data| data.len() as u64);
+    let Some(input_size) = input_metadata.map(Metadata::len).or(entry_size) else {
+        return Ok(None);
+    };
+    let output_size = match &args.zip_outputs {
+        Some(outputs) => outputs.len_of(output).unwrap_or(0),
+        None => std::fs::metadata(output)?.len(),
+    };
+    let sizes = ByteSizes { input: input_size, output: output_size };
+    info!("size {sizes}");
+    Ok(Some(sizes))
+}
+
+/// Sets the modification time of the input on the output when overwriting the
+/// input or with --preserve-timestamps.
+fn preserve_timestamp(input_metadata: &Metadata, output: &OsStr, args: &Args) -> std::io::Result<()> {
+    if args.preserve_timestamps || output == args.input {
+        File::options().write(true).open(output)?.set_modified(input_metadata.modified()?)?;
+    }
+    Ok(())
+}
+
+/// Animation parameters of an APNG that the image crate doesn't expose.
+struct ApngInfo {
+    /// Number of frames of the animation, without a hidden default image.
+    num_frames: u32,
+    /// Number of times to play the animation, 0 means infinitely.
+    num_plays: u32,
+    /// Whether the default image is not part of the animation.
+    hidden_default_image: bool,
+}
+
+/// Palette of an indexed PNG, which the image crate expands to RGB(A).
+#[derive(Debug, Clone)]
+struct Palette {
+    /// RGB triples.
+    colors: Vec<u8>,
+    /// Alpha of the first entries (tRNS), the rest is opaque.
+    alpha: Option<Vec<u8>>,
+    bit_depth: png::BitDepth,
+}
+
+impl Palette {
+    /// Maps the pixels of the image back to palette indices, packed into rows
+    /// of the bit depth of the palette. `None` if any pixel isn't a palette color.
+    fn index(&self, img: &RgbaImage) -> Option<Vec<u8>> {
+        let mut indices = HashMap::new();
+        for (index, rgb) in self.colors.chunks_exact(3).enumerate() {
+            let alpha = self.alpha.as_ref().and_then(|alpha| alpha.get(index).copied()).unwrap_or(255);
+            indices.entry([rgb[0], rgb[1], rgb[2], alpha]).or_insert(index as u8);
+        }
+
+        let bits = self.bit_depth as usize;
+        let row_len = (img.width() as usize * bits).div_ceil(8);
+        let mut data = vec![0u8; row_len * img.height() as usize];
+        for (x, y, pixel) in img.enumerate_pixels() {
+            let index = *indices.get(&pixel.0)?;
+            let bit = x as usize * bits;
+            data[y as usize * row_len + bit / 8] |= index << (8 - bits - bit % 8);
+        }
+        Some(data)
+    }
+}
+
+/// What the image crate doesn't tell about a PNG.
+struct PngInfo {
+    /// `None` if the PNG isn't animated.
+    apng: Option<ApngInfo>,
+    palette: Option<Palette>,
+}
+
+fn read_png_info(reader: impl Read) -> ImageResult<PngInfo> {
+    let reader = png::Decoder::new(reader).read_info().map_err(png_decoding_error)?;
+    let info = reader.info();
+    let apng = info.animation_control.map(|control| ApngInfo {
+        num_frames: control.num_frames,
+        num_plays: control.num_plays,
+        // If the IDAT has no fcTL it isn't part of the animation.
+        hidden_default_image: info.frame_control.is_none(),
+    });
+    let palette = info.palette.as_ref()
+        .filter(|_| info.color_type == png::ColorType::Indexed)
+        .map(|colors| Palette {
+            colors: colors.to_vec(),
+            alpha: info.trns.as_ref().map(|trns| trns.to_vec()),
+            bit_depth: info.bit_depth,
+        });
+    Ok(PngInfo { apng, palette })
+}
+
+/// Whether an AVIF file is an image sequence, i.e. its file type box lists
+/// the `avis` brand.
+fn is_avif_sequence(mut reader: impl Read) -> std::io::Result<bool> {
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    if &header[4..] != b"ftyp" {
+        return Ok(false);
+    }
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let mut brands = Vec::new();
+    reader.take(size.saturating_sub(8).min(1024)).read_to_end(&mut brands)?;
+    // major brand, minor version, then the compatible brands
+    Ok(brands.chunks_exact(4).enumerate().any(|(index, brand)| index != 1 && brand == b"avis"))
+}
+
+/// Fails if --format explicitly asks for a format that can't store the
+/// animation, unless --allow-downgrade is given. With --no-downgrade it fails
+/// for any such format.
+fn check_animation_downgrade(output_format: ImageFormat, args: &Args) -> ProcessResult<()> {
+    let animated = matches!(output_format, ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP);
+    if args.no_downgrade && !animated {
+        return Err(FixPixelartError::DowngradeRefused(output_format));
+    }
+    if args.format.is_some() && !animated && !args.allow_downgrade {
+        return Err(FixPixelartError::AnimationDowngrade(output_format));
+    }
+    Ok(())
+}
+
+fn print_animation_downgrade_warning_if_needed(output_format: ImageFormat) {
+    match output_format {
+        ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP => {}
+        _ => {
+            // If this happens there is a new animated format that I only handled in some part of the code.
+            let format_name = output_format.extensions_str()[0].to_ascii_uppercase();
+            print_warning(&format_name);
+        }
+    }
+
+    fn print_warning(format_name: &str) {
+        warn!("animated {format_name} images are not supported, writing still image instead");
+    }
+}
+
+/// Decodes a still image, rotated and flipped as given by its EXIF orientation
+/// unless --no-auto-orient is given. The output doesn't carry the orientation.
+/// Also returns the ICC color profile of the image, if any.
+fn decode_still(mut decoder: impl ImageDecoder, args: &Args) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+    let start = Instant::now();
+    let orientation = if args.no_auto_orient {
+        Orientation::NoTransforms
+    } else {
+        decoder.orientation().unwrap_or(Orientation::NoTransforms)
+    };
+    let icc_profile = icc_profile(&mut decoder);
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    print_timing("decoded", start.elapsed(), args);
+    Ok((img, icc_profile))
+}
+
+/// The ICC color profile of the input. A broken one is dropped.
+fn icc_profile(decoder: &mut impl ImageDecoder) -> Option<Vec<u8>> {
+    decoder.icc_profile().ok().flatten()
+}
+
+/// Writes the frames of an animation scaled down as separate PNG images to the
+/// directory of --explode, and with --sidecar their delays to frames.json.
+fn explode_animation(width: u32, height: u32, input_frames: Frames, icc_profile: Option<Vec<u8>>, args: Args) -> ProcessResult<Processed> {
+    let (frames, grid) = match decode_and_detect(width, height, input_frames, ImageFormat::Png, &args)? {
+        ControlFlow::Continue(detected) => detected,
+        ControlFlow::Break(processed) => return Ok(processed),
+    };
+
+    let dir = Path::new(args.explode.as_deref().unwrap_or_default());
+    std::fs::create_dir_all(dir)?;
+    let pixel_aspect = args.pixel_aspect.filter(|_| args.stretch().is_none());
+    let (frames, (canvas_width, canvas_height), _) = resize_frames(frames, width, height, grid, &args)?;
+    let mut written = Vec::new();
+    let progress = Progress::new("frames", args.frame_count, args.frame_progress());
+    for (index, frame) in frames.enumerate() {
+        let (delay, buffer, left, top) = frame?;
+        // a frame only covers part of the canvas if it was trimmed
+        let canvas = if buffer.dimensions() == (canvas_width, canvas_height) {
+            buffer
+        } else {
+            let mut canvas = RgbaImage::new(canvas_width, canvas_height);
+            imageops::overlay(&mut canvas, &buffer, left.into(), top.into());
+            canvas
+        };
+        let name = format!("frame_{index:04}.png");
+        let output = dir.join(&name);
+        let start = Instant::now();
+        write_still_image(DynamicImage::ImageRgba8(canvas), output.as_os_str(), ImageFormat::Png, pixel_aspect, None, icc_profile.clone(), &args)?;
+        print_timing(format_args!("frame {index}: encoded"), start.elapsed(), &args);
+        verbose!("written {output:?}");
+        let (numer, denom) = delay.numer_denom_ms();
+        written.push((name, (numer + denom / 2) / denom.max(1)));
+        progress.inc();
+    }
+    drop(progress);
+    if written.is_empty() {
+        return Err(FixPixelartError::NoFrames);
+    }
+    success!("written {} frames to {dir:?}", written.len());
+    if args.sidecar {
+        write_frame_delays(dir, grid, &written, &args)?;
+    }
+    Ok(Processed::Scaled(grid.scale, None))
+}
+
+/// Writes the file names and delays in milliseconds of the frames written by
+/// --explode to frames.json in their directory.
+fn write_frame_delays(dir: &Path, grid: Grid, frames: &[(String, u32)], args: &Args) -> std::io::Result<()> {
+    let frames = frames.iter()
+        .map(|(name, delay)| format!("{{\"file\":{},\"delay\":{delay}}}", json_string(name)))
+        .collect::<Vec<_>>();
+    let json = format!(
+        "{{\"input\":{},\"stride_x\":{},\"stride_y\":{},\"frames\":[{}]}}\n",
+        json_string(&args.input.to_string_lossy()), grid.scale.x, grid.scale.y, frames.join(","),
+    );
+    std::fs::write(dir.join("frames.json"), json)
+}
+
+fn resize_animation<'a>(mut decoder: impl AnimationDecoder<'a> + ImageDecoder, repeat: Repeat, background_color: Option<[u8; 3]>, output_format: ImageFormat, args: Args) -> ProcessResult<Processed> {
+    let (width, height) = decoder.dimensions();
+    let icc_profile = icc_profile(&mut decoder);
+    if args.explode.is_some() {
+        explode_animation(width, height, decoder.into_frames(), icc_profile, args)
+    } else if output_format == ImageFormat::Gif {
+        resize_as_animated_gif(width, height, decoder.into_frames(), repeat, background_color, icc_profile, args)
+    } else if output_format == ImageFormat::Png {
+        resize_as_apng(width, height, decoder.into_frames(), repeat, None, icc_profile, args)
+    } else if output_format == ImageFormat::WebP {
+        resize_as_animated_webp(width, height, decoder.into_frames(), repeat, icc_profile, args)
+    } else {
+        if !args.only_analyze {
+            check_animation_downgrade(output_format, &args)?;
+            print_animation_downgrade_warning_if_needed(output_format);
+        }
+        resize_still_image(&DynamicImage::from_decoder(decoder)?, output_format, None, icc_profile, args)
+    }
+}
+
+fn main() {
+    let mut args = Args::parse();
+    log::set_verbosity(if args.quiet {
+        Verbosity::Quiet
+    } else if args.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    });
+    style::set_enabled(args.color.enabled());
+    if args.stdout {
+        args.output = Some(output::STDOUT.into());
+    }
+    if let Some(threads) = args.threads.filter(|_| args.parallel()) {
+        // 0 threads means rayon picks the number of cores
+        if let Err(err) = rayon::ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            error!("Error: {err}");
+            std::process::exit(1);
+        }
+    }
+    let code = if let Some(pattern) = args.frames_glob.clone() {
+        match process_sequence(&pattern, args) {
+            Ok(_) => 0,
+            Err(err) => report_error(&err, pattern.as_ref(), false),
+        }
+    } else if let Some(list) = &args.files_from {
+        process_list(list, &args)
+    } else if let Some(archive) = args.zip.clone() {
+        process_zip(&archive, args)
+    } else if args.recursive && Path::new(&args.input).is_dir() {
+        process_directory(args)
+    } else {
+        let input = args.input.clone();
+        match process(args) {
+            Ok(_) => 0,
+            Err(err) => report_error(&err, &input, false),
+        }
+    };
+    std::process::exit(code);
+}
+
+/// Processes all images listed in a file for --files-from. Returns the exit
+/// code like [`process_files()`].
+fn process_list(list: &OsStr, args: &Args) -> i32 {
+    // There is no INPUT with --files-from, so a single path is taken for INPUT.
+    let mut args = args.clone();
+    if (args.output.is_some() || args.out_dir.is_some()) && !args.input.is_empty() {
+        error!("only the output directory can be given with --files-from");
+        return 1;
+    }
+    if !args.input.is_empty() {
+        args.output = Some(std::mem::take(&mut args.input));
+    }
+    if let Some(output) = args.out_dir.as_ref().or(args.output.as_ref()) {
+        if is_stdout(output) || Path::new(output).is_file() {
+            error!("{output:?}: output has to be a directory with --files-from");
+            return 1;
+        }
+        let created = if args.dry_run { Ok(()) } else { std::fs::create_dir_all(output) };
+        if let Err(err) = created {
+            error!("Error: {output:?}: {err:?}");
+            return 1;
+        }
+    }
+
+    let text = if is_stdin(list) {
+        let mut text = String::new();
+        std::io::stdin().lock().read_to_string(&mut text).map(|_| text)
+    } else {
+        std::fs::read_to_string(list)
+    };
+    let text = match text {
+        Ok(text) => text,
+        Err(err) => {
+            error!("Error: {list:?}: {err:?}");
+            return 1;
+        }
+    };
+    let files = text.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
 
-    /// Ignore pixels at the border of the image.
-    /// Sometimes pixel art is framed in a border that doesn't fit the pixel grid.
-    /// This uses an extremely primitive strategy to ignore those.
-    #[clap(verbatim_doc_comment)]
-    #[arg(short = 'b', long, default_value_t = false)]
-    ignore_border: bool,
+    process_files(&files, None, &args)
+}
 
-    /// Image to resize.
-    #[arg()]
-    input: OsString,
+/// Scales down the frames of --frames-glob by the scale detected across all of
+/// them and writes them as an animated GIF to the file of --assemble.
+fn process_sequence(pattern: &str, mut args: Args) -> ProcessResult<Processed> {
+    if !args.input.is_empty() {
+        return Err(FixPixelartError::InvalidArgs("INPUT can't be given with --frames-glob, the frames are read from the matching files"));
+    }
+    let (dir, name_pattern) = match pattern.rsplit_once('/') {
+        Some(("", name_pattern)) => (Path::new("/"), name_pattern),
+        Some((dir, name_pattern)) => (Path::new(dir), name_pattern),
+        None => (Path::new("."), pattern),
+    };
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if glob_match(name_pattern, &entry.file_name().to_string_lossy()) && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    let Some(first) = files.first() else {
+        return Err(FixPixelartError::InvalidArgs("--frames-glob doesn't match any files"));
+    };
 
-    /// Where to write the output.
-    /// Can be a file name or a directory.
-    /// [default: "{basename}.scaled.{ext}"]
-    #[clap(verbatim_doc_comment)]
-    #[arg(default_value = None)]
-    output: Option<OsString>,
-}
+    // check the sizes up front, reading just the headers, so a mismatch is found before decoding
+    let (width, height) = image::image_dimensions(first)?;
+    for path in &files[1..] {
+        let (frame_width, frame_height) = image::image_dimensions(path)?;
+        if (frame_width, frame_height) != (width, height) {
+            return Err(FixPixelartError::FrameSizeMismatch { path: path.clone(), width: frame_width, height: frame_height, expected: (width, height) });
+        }
+    }
 
-struct CurrentStride {
-    color: Rgba<u8>,
-    stride: u32,
+    info!("assembling {} frames matching {pattern:?}", files.len());
+    // stands in for the input in messages
+    args.input = first.clone().into_os_string();
+    args.output = args.assemble.clone();
+    args.frame_count = Some(files.len());
+    let delay = Delay::from_numer_denom_ms(args.delay, 1);
+    let frames = files.into_iter().map(move |path| {
+        let img = image::open(&path)?.into_rgba8();
+        Ok(Frame::from_parts(img, 0, 0, delay))
+    });
+    resize_as_animated_gif(width, height, Frames::new(Box::new(frames)), Repeat::Infinite, None, None, args)
 }
 
-#[inline]
-fn get_smallest_stride(img: &DynamicImage, ignore_border: bool) -> u32 {
-    let mut strides = vec![false; img.width().max(img.height()) as usize + 1];
-    if !get_smallest_stride_phase1(img, &mut strides, ignore_border) {
+/// Processes all images in a directory for --recursive. Returns the exit code
+/// like [`process_files()`].
+fn process_directory(args: Args) -> i32 {
+    let root = PathBuf::from(&args.input);
+    if let Some(output) = args.out_dir.as_ref().or(args.output.as_ref()) {
+        if is_stdout(output) || Path::new(output).is_file() {
+            error!("{output:?}: output has to be a directory if the input is a directory");
+            return 1;
+        }
+    }
+
+    let mut files = Vec::new();
+    if let Err(err) = collect_files(&root, &mut files) {
+        error!("Error: {:?}: {err:?}", args.input);
         return 1;
     }
-    get_smallest_stride_phase2(&strides)
+    files.sort();
+    if let Some(pattern) = &args.glob {
+        files.retain(|path| {
+            let relative = path.strip_prefix(&root).unwrap_or(path);
+            if pattern.contains('/') {
+                let relative = relative.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+                glob_match(pattern, &relative)
+            } else {
+                relative.file_name().is_some_and(|name| glob_match(pattern, &name.to_string_lossy()))
+            }
+        });
+    }
+
+    process_files(&files, Some(&root), &args)
 }
 
-fn get_smallest_stride_phase1(img: &DynamicImage, strides: &mut [bool], ignore_border: bool) -> bool {
-    let mut curr_y = (0..img.width()).map(|_| CurrentStride {
-        color: Rgba([0, 0, 0, 0]),
-        stride: 0,
-    }).collect::<Vec<_>>();
+/// Processes a batch of files. If they were found in the directory `root`,
+/// files that aren't images are skipped and the directory structure is
+/// mirrored in the output directory. Otherwise they are all written to the
+/// output directory. Returns the exit code, which is 1 if any file failed,
+/// otherwise 2 if no scaling was detected for any file, otherwise 0.
+///
+/// A failed file stops the batch with --fail-fast or if the error is fatal.
+/// The files that weren't started yet are left out then.
+fn process_files(files: &[PathBuf], root: Option<&Path>, args: &Args) -> i32 {
+    let stopped = AtomicBool::new(false);
+    let not_started = AtomicUsize::new(0);
+    // returns `None` for files that aren't images or weren't started
+    let process_file = |path: &PathBuf| -> Option<ProcessResult<Processed>> {
+        if stopped.load(Ordering::Relaxed) {
+            not_started.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        if root.is_some() && image_format(path, args).is_none() {
+            return None;
+        }
 
-    for y in 0..img.height() {
-        let mut curr_x = CurrentStride {
-            color: Rgba([0, 0, 0, 0]),
-            stride: 0,
-        };
-        for x in 0..img.width() {
-            let color = img.get_pixel(x, y);
-            if color == curr_x.color {
-                curr_x.stride += 1;
-            } else {
-                if !ignore_border || x > curr_x.stride {
-                    if curr_x.stride == 1 {
-                        return false;
-                    }
-                    if curr_x.stride > 0 && curr_x.color[3] > 0 {
-                        strides[curr_x.stride as usize] = true;
-                    }
-                }
-                curr_x.stride = 1;
-                curr_x.color  = color;
+        let mut file_args = args.clone();
+        file_args.input = path.into();
+        file_args.in_batch = true;
+        if let Some((output, root)) = args.out_dir.as_ref().or(args.output.as_ref()).zip(root) {
+            let relative = path.strip_prefix(root).unwrap_or(path);
+            let mut dir = PathBuf::from(output);
+            if let Some(parent) = relative.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                dir.push(parent);
             }
-
-            let curr_y = &mut curr_y[x as usize];
-            if curr_y.color == color {
-                curr_y.stride += 1;
+            // output_from() takes a path that doesn't exist for a directory too
+            let created = if args.dry_run || args.zip_outputs.is_some() { Ok(()) } else { std::fs::create_dir_all(&dir) };
+            if let Err(err) = created {
+                error!("Error: {dir:?}: {err:?}");
+                return Some(Err(err.into()));
+            }
+            if args.out_dir.is_some() {
+                file_args.out_dir = Some(dir.into());
             } else {
-                if !ignore_border || y > curr_y.stride {
-                    if curr_y.stride == 1 {
-                        return false;
-                    }
-                    if curr_y.stride > 0 && curr_y.color[3] > 0 {
-                        strides[curr_y.stride as usize] = true;
-                    }
-                }
-                curr_y.stride = 1;
-                curr_y.color  = color;
+                file_args.output = Some(dir.into());
             }
         }
-        if !ignore_border {
-            if curr_x.stride == 1 {
-                return false;
-            }
-            if curr_x.stride > 0 && curr_x.color[3] > 0 {
-                strides[curr_x.stride as usize] = true;
+
+        let result = process(file_args);
+        if let Err(err) = &result {
+            report_error(err, path.as_os_str(), true);
+            if err.is_fatal() || (args.fail_fast && err.exit_code() == 1) {
+                stopped.store(true, Ordering::Relaxed);
             }
         }
-    }
+        Some(result)
+    };
 
-    if !ignore_border {
-        for curr_y in &curr_y {
-            if curr_y.stride == 1 {
-                return false;
-            }
-            if curr_y.stride > 0 && curr_y.color[3] > 0 {
-                strides[curr_y.stride as usize] = true;
-            }
+    let progress = Progress::new("files", Some(files.len()), args.progress && !args.quiet);
+    let process_file = |path| {
+        let result = process_file(path);
+        progress.inc();
+        result.map(|result| (path, result))
+    };
+    let results = if args.parallel() {
+        files.par_iter().filter_map(process_file).collect::<Vec<_>>()
+    } else {
+        files.iter().filter_map(process_file).collect()
+    };
+    drop(progress);
+
+    if args.summary {
+        if args.json {
+            print_summary_json(&results);
+        } else {
+            print_summary(&results);
         }
     }
-
-    return true;
+    if !args.summary || args.json {
+        // the summary table ends with the total
+        if let Some(total) = total_sizes(&results) {
+            info!("total size {total}");
+        }
+    }
+    let codes = results.iter()
+        .map(|(_, result)| result.as_ref().map_or_else(FixPixelartError::exit_code, |_| 0))
+        .collect::<Vec<_>>();
+    let failed = codes.iter().filter(|&&code| code != 0).count();
+    if stopped.load(Ordering::Relaxed) {
+        warn!("stopped the batch, {} files weren't processed", not_started.load(Ordering::Relaxed));
+    }
+    // the summary already has the numbers
+    if failed > 0 && !args.summary {
+        warn!("{failed} of {} images failed:", codes.len());
+        for (path, _) in results.iter().filter(|(_, result)| result.is_err()) {
+            warn!("  {path:?}");
+        }
+    }
+    if codes.contains(&1) {
+        1
+    } else if codes.contains(&EXIT_NOT_DETECTED) {
+        EXIT_NOT_DETECTED
+    } else {
+        0
+    }
 }
 
-fn get_smallest_stride_phase2(strides: &[bool]) -> u32 {
-    let Some(min_stride) = strides[1..].iter().cloned().position(|found| found).map(|pos| pos + 1) else {
-        return 1;
-    };
-    let offset = min_stride + 1;
-    let min_stride = min_stride as u32;
-
-    if min_stride == 1 {
-        return 1;
+/// How a file of a batch ended for --summary: the result, the scale, if any,
+/// and why it wasn't scaled, if it wasn't.
+fn summarize(result: &ProcessResult<Processed>) -> (&'static str, Option<Scale>, Option<String>) {
+    match result {
+        Ok(Processed::Scaled(scale, _)) if *scale == Scale::square(1) => ("native", None, Some("re-encoded at native resolution".to_owned())),
+        Ok(Processed::Scaled(scale, _)) => ("scaled", Some(*scale), None),
+        Ok(Processed::Split(1)) => ("split", None, Some("1 sprite".to_owned())),
+        Ok(Processed::Split(count)) => ("split", None, Some(format!("{count} sprites"))),
+        Ok(Processed::Skipped) => ("skipped", None, None),
+        Ok(Processed::Copied) => ("native", None, Some("failed to detect pixel art scaling, copied unchanged".to_owned())),
+        Ok(Processed::Native) => ("native", None, Some("image is already at its native resolution".to_owned())),
+        // not detected
+        Err(err) if err.exit_code() != 1 => ("native", None, Some(err.to_string())),
+        Err(err) => ("failed", None, Some(err.to_string())),
     }
+}
 
-    for (stride, found) in strides[offset..].iter().cloned().enumerate() {
-        let stride = (stride + offset) as u32;
-        if found && stride % min_stride != 0 {
-            return 1;
-        }
+/// The sizes of the file written for a result, if any.
+fn written_sizes(result: &ProcessResult<Processed>) -> Option<ByteSizes> {
+    match result {
+        Ok(Processed::Scaled(_, sizes)) => *sizes,
+        _ => None,
     }
+}
 
-    min_stride
+/// The sum of the sizes of all files of a batch that were written, `None` if
+/// none were.
+fn total_sizes(results: &[(&PathBuf, ProcessResult<Processed>)]) -> Option<ByteSizes> {
+    results.iter()
+        .filter_map(|(_, result)| written_sizes(result))
+        .reduce(|total, sizes| total + sizes)
 }
 
-fn get_smallest_stride_from_animation<'a>(width: u32, height: u32, frames: impl Iterator<Item=&'a DynamicImage>, ignore_border: bool) -> ImageResult<u32> {
-    let mut strides = vec![false; width.max(height) as usize + 1];
-    for frame in frames {
-        if !get_smallest_stride_phase1(frame, &mut strides, ignore_border) {
-            return Ok(1);
+/// Prints a table of the results of a batch to stderr for --summary.
+fn print_summary(results: &[(&PathBuf, ProcessResult<Processed>)]) {
+    let mut counts: Vec<(&str, usize)> = Vec::new();
+    note!("summary:");
+    for (path, result) in results {
+        let (outcome, scale, message) = summarize(result);
+        let scale = scale.map_or_else(|| "-".to_owned(), |scale| scale.to_string());
+        let message = match (message, written_sizes(result)) {
+            (Some(message), Some(sizes)) => Some(format!("{message}, {sizes}")),
+            (message, sizes) => message.or(sizes.map(|sizes| sizes.to_string())),
+        };
+        match message {
+            Some(message) => note!("{outcome:<8} {scale:<8} {path:?}: {message}"),
+            None => note!("{outcome:<8} {scale:<8} {path:?}"),
+        }
+        match counts.iter_mut().find(|(other, _)| *other == outcome) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((outcome, 1)),
         }
     }
+    let counts = counts.iter().map(|(outcome, count)| format!("{count} {outcome}")).collect::<Vec<_>>();
+    note!("{} of {} images", counts.join(", "), results.len());
+    if let Some(total) = total_sizes(results) {
+        note!("total size {total}");
+    }
+}
 
-    let min_stride = get_smallest_stride_phase2(&strides);
-
-    Ok(min_stride)
+/// Prints the results of a batch as a JSON array to stdout for --summary --json.
+fn print_summary_json(results: &[(&PathBuf, ProcessResult<Processed>)]) {
+    let entries = results.iter().map(|(path, result)| {
+        let (outcome, scale, message) = summarize(result);
+        let mut json = format!("{{\"input\":{},\"result\":\"{outcome}\"", json_string(&path.to_string_lossy()));
+        if let Some(scale) = scale {
+            json.push_str(&format!(",\"stride_x\":{},\"stride_y\":{}", scale.x, scale.y));
+        }
+        if let Some(message) = message {
+            json.push_str(&format!(",\"message\":{}", json_string(&message)));
+        }
+        if let Some(sizes) = written_sizes(result) {
+            let saved = sizes.saved().map_or_else(|| "null".to_owned(), |saved| format!("{saved:.1}"));
+            json.push_str(&format!(",\"input_size\":{},\"output_size\":{},\"saved\":{saved}", sizes.input, sizes.output));
+        }
+        json.push('}');
+        json
+    }).collect::<Vec<_>>();
+    println!("[{}]", entries.join(","));
 }
 
-fn resize_still_image(img: &DynamicImage, output_format: ImageFormat, args: Args) -> ImageResult<()> {
-    let output = output_from(args.output, args.input.as_os_str(), args.in_place, output_format)?;
-    let min_stride = get_smallest_stride(&img, args.ignore_border);
-    if min_stride <= 1 {
-        eprintln!("failed to detect pixel art scaling");
-        std::process::exit(1);
-    }
-    let (width, height) = img.dimensions();
-    let new_width  = width  / min_stride;
-    let new_height = height / min_stride;
-    if args.only_analyze {
-        println!("{new_width}x{new_height}");
-        return Ok(());
+/// The format of the image file at `path`, or of the --zip entry of that
+/// path, guessed from its content, `None` if it isn't an image.
+fn image_format(path: &Path, args: &Args) -> Option<ImageFormat> {
+    let Some(data) = args.zip_entry(path) else {
+        return ImageReader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .ok()
+            .and_then(|reader| reader.format());
+    };
+    let mut reader = ImageReader::new(std::io::Cursor::new(data.as_ref()));
+    if let Ok(format) = ImageFormat::from_path(path) {
+        reader.set_format(format);
     }
-    println!("resizing {width} x {height} -> {new_width} x {new_height}");
-    let img = imageops::resize(img, new_width, new_height, FilterType::Nearest);
-    img.write_to(&mut BufWriter::new(File::options().write(true).create(true).open(&output)?), output_format)?;
-    println!("written {output:?}");
-    Ok(())
+    reader.with_guessed_format().ok()?.format()
 }
 
-fn output_from(output: Option<OsString>, input: &OsStr, in_place: bool, format: ImageFormat) -> ImageResult<OsString> {
-    if in_place {
-        return Ok(input.to_owned());
+/// Processes the images inside a ZIP archive for --zip like a directory with
+/// --recursive. Nothing is extracted: the images are decoded from memory, and
+/// the outputs for --zip-out are kept in memory until the new archive is
+/// written. Returns the exit code like [`process_files()`].
+fn process_zip(archive: &OsStr, mut args: Args) -> i32 {
+    if !args.input.is_empty() {
+        error!("INPUT can't be given with --zip, the images are read from the archive");
+        return 1;
+    }
+    if args.out_dir.is_none() && args.zip_out.is_none() && !args.only_analyze && !args.dry_run {
+        error!("--zip needs --out-dir or --zip-out to write the images to");
+        return 1;
     }
+    let entries = match std::fs::read(archive).and_then(|data| zip::read_archive(&data)) {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Error: {archive:?}: {err}");
+            return 1;
+        }
+    };
 
-    let mut parent_dir = None;
-    if let Some(output) = output {
-        let path = Path::new(&output);
-        let meta = path.metadata();
-        match meta {
-            Err(err) => {
-                if err.kind() != std::io::ErrorKind::NotFound {
-                    return Err(err.into());
-                }
+    // entries are named like the archive joined with their name, so the
+    // messages about them show where they are from
+    let root = PathBuf::from(archive);
+    let mut files = Vec::new();
+    let mut data = HashMap::new();
+    // the name, time, and path of each entry, the path is `None` for directories
+    let mut kept = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let path = entry.relative_path().filter(|_| !entry.is_dir()).map(|relative| root.join(relative));
+        match &path {
+            Some(path) if data.contains_key(path) => {
+                warn!("{archive:?}: skipping entry {:?}, there is an earlier entry of that name", entry.name);
+                continue;
             }
-            Ok(meta) => {
-                if !meta.is_dir() {
-                    return Ok(output);
-                }
+            Some(path) => {
+                files.push(path.clone());
+                data.insert(path.clone(), Arc::<[u8]>::from(entry.data));
+            }
+            None if !entry.is_dir() => {
+                warn!("{archive:?}: skipping entry {:?}, its name points outside of the archive", entry.name);
+                continue;
             }
+            None => {}
         }
-        parent_dir = Some(PathBuf::from(output));
+        kept.push((entry.name, entry.modified, path));
     }
+    let data = Arc::new(data);
+    args.zip_entries = Some(Arc::clone(&data));
+    let outputs = Arc::new(MemoryOutputs::default());
+    if let Some(zip_out) = &args.zip_out {
+        // the outputs are named as if the new archive was a directory
+        args.out_dir = Some(zip_out.clone());
+        args.zip_outputs = Some(Arc::clone(&outputs));
+    }
+    let code = process_files(&files, Some(&root), &args);
+    let Some(zip_out) = args.zip_out.as_ref().filter(|_| !args.only_analyze && !args.dry_run) else {
+        return code;
+    };
 
-    let input_path = Path::new(input);
-    let mut output = OsString::new();
-
-    if let Some(parent) = parent_dir.as_deref().or_else(|| input_path.parent()) {
-        let parent = parent.as_os_str();
-        if !parent.is_empty() {
-            output.push(parent);
-            output.push(std::path::MAIN_SEPARATOR.to_string());
+    // entries in their original order, images replaced by their outputs if there are any
+    let written = OutputWriter::create(zip_out).and_then(|file| {
+        let mut writer = ZipWriter::new(file);
+        for (name, modified, path) in &kept {
+            let Some(path) = path else {
+                writer.add(name, *modified, &[])?;
+                continue;
+            };
+            let path_outputs = outputs.take(path.as_os_str());
+            if path_outputs.is_empty() {
+                writer.add(name, *modified, &data[path])?;
+            }
+            for (output, output_data) in path_outputs {
+                let relative = Path::new(&output).strip_prefix(zip_out).unwrap_or(Path::new(&output));
+                let output_name = relative.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+                writer.add(&output_name, *modified, &output_data)?;
+            }
         }
+        writer.finish()?.finish()
+    });
+    if let Err(err) = written {
+        error!("Error: {zip_out:?}: {err}");
+        return 1;
     }
+    success!("written {zip_out:?}");
+    code
+}
 
-    if let Some(stem) = input_path.file_stem() {
-        output.push(stem);
-    } else {
-        output.push("pixelart");
+/// Collects all files in `dir` and its sub-directories. Symbolic links to
+/// directories aren't followed.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
     }
-    output.push(".scaled.");
-    output.push(format.extensions_str()[0]);
+    Ok(())
+}
 
-    Ok(output)
+/// Matches `text` against a pattern where * matches any number of characters
+/// and ? a single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // position of the last * and the text position it is matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&ch| ch == '*')
 }
 
-fn resize_as_animated_gif(width: u32, height: u32, input_frames: Frames, args: Args) -> ImageResult<()> {
-    let mut frames = Vec::new();
-    for frame in input_frames {
-        let frame: Frame = frame?;
-        frames.push((frame.delay(), frame.left(), frame.top(), DynamicImage::from(frame.into_buffer())));
+fn process(mut args: Args) -> ProcessResult<Processed> {
+    if is_stdin(&args.input) && args.output.is_none() && args.out_dir.is_none() {
+        args.output = Some(output::STDOUT.into());
     }
-    let min_stride = if args.only_analyze_first_frame {
-        if let Some((_, _, _, img)) = frames.iter().next() {
-            get_smallest_stride(img, args.ignore_border)
+    let output_format = if args.format.is_some() {
+        args.format
+    } else if let Some(output) = &args.output {
+        if is_stdout(output) {
+            // there is no file name to infer the format from
+            Some(ImageFormat::Png)
         } else {
-            0
+            ImageFormat::from_path(output).ok()
         }
     } else {
-        get_smallest_stride_from_animation(width, height, frames.iter().map(|(_, _, _, img)| img), args.ignore_border)?
+        None
     };
-    if min_stride <= 1 {
-        eprintln!("failed to detect pixel art scaling");
-        std::process::exit(1);
-    }
-
-    let new_width = width / min_stride;
-    let new_height = height / min_stride;
-    if args.only_analyze {
-        println!("{new_width}x{new_height}");
-        return Ok(());
-    }
-
-    println!("resizing {width} x {height} -> {new_width} x {new_height}");
-    let output = output_from(args.output, args.input.as_os_str(), args.in_place, ImageFormat::Gif)?;
-    let writer = BufWriter::new(File::options().write(true).create(true).open(&output)?);
-    let mut encoder = GifEncoder::new(writer);
-    if frames.len() > 1 {
-        // XXX: the image crate doesn't support reading the repeat and speed parameters of animated GIFs!
-        encoder.set_repeat(Repeat::Infinite)?;
-    }
-    for (delay, left, top, img) in frames {
-        let buffer = imageops::resize(&img, img.width() / min_stride, img.height() / min_stride, FilterType::Nearest);
-        encoder.encode_frame(Frame::from_parts(buffer, left / min_stride, top / min_stride, delay))?;
-    }
-    println!("written {output:?}");
-    Ok(())
-}
 
-fn print_animation_downgrade_warning_if_needed(output_format: ImageFormat) {
-    match output_format {
-        ImageFormat::Png => {
-            print_warning("PNG");
+    if let Some(since) = args.since {
+        if is_stdin(&args.input) {
+            return Err(FixPixelartError::InvalidArgs("--since can't be used when reading from stdin"));
         }
-        ImageFormat::WebP => {
-            print_warning("WebP");
-        }
-        ImageFormat::Gif => {}
-        _ => {
-            // If this happens there is a new animated format that I only handled in some part of the code.
-            let format_name = output_format.extensions_str()[0].to_ascii_uppercase();
-            print_warning(&format_name);
+        let modified = std::fs::metadata(&args.input)?.modified()?;
+        if modified <= since {
+            verbose!("skipping {:?}, not modified since the given time", args.input);
+            return Ok(Processed::Skipped);
         }
     }
 
-    fn print_warning(format_name: &str) {
-        eprintln!("animated {format_name} images are not supported, writing still image instead");
-    }
-}
-
-fn resize_animation<'a>(decoder: impl AnimationDecoder<'a> + ImageDecoder, output_format: ImageFormat, args: Args) -> ImageResult<()> {
-    let (width, height) = decoder.dimensions();
-    if output_format == ImageFormat::Gif {
-        resize_as_animated_gif(width, height, decoder.into_frames(), args)?;
-    } else {
-        if !args.only_analyze {
-            print_animation_downgrade_warning_if_needed(output_format);
+    if args.use_name_hint == Some(NameHint::Scale) {
+        if let Some((_, scale)) = split_scale_suffix(&args.input) {
+            info!("using the scale {scale} given in the file name");
+            args.force_scale = Some(Scale::square(scale));
         }
-        resize_still_image(&DynamicImage::from_decoder(decoder)?, output_format, args)?;
     }
-    Ok(())
-}
 
-fn main() -> ImageResult<()> {
-    let args = Args::parse();
+    if args.no_follow_symlinks && Path::new(&args.input).is_symlink() {
+        return Err(FixPixelartError::InvalidArgs("the input is a symbolic link, not overwriting it because of --no-follow-symlinks"));
+    }
 
-    let output_format = if let Some(output) = &args.output {
-        ImageFormat::from_path(output).ok()
+    // the first frame of an animation piped in is analyzed without waiting for the rest
+    let streaming = is_stdin(&args.input) && args.only_analyze_first_frame;
+    let input = if streaming {
+        InputReader::open_streaming()
+    } else if let Some(data) = args.zip_entry(Path::new(&args.input)) {
+        InputReader::from_memory(Arc::clone(data))
     } else {
-        None
+        InputReader::open(&args.input)?
     };
-
-    let reader = ImageReader::open(&args.input)?.with_guessed_format()?;
+    let mut reader = ImageReader::new(input).with_guessed_format()?;
+    if reader.format().is_none() {
+        // The image crate only recognizes AVIF files with the brand of still images.
+        let mut input = reader.into_inner();
+        let avif_sequence = is_avif_sequence(&mut input).unwrap_or(false);
+        input.rewind()?;
+        reader = ImageReader::new(input);
+        if avif_sequence {
+            reader.set_format(ImageFormat::Avif);
+        } else if let Ok(format) = ImageFormat::from_path(&args.input) {
+            // formats without a signature, like TGA, are only known by their extension
+            reader.set_format(format);
+        }
+    }
     let maybe_format = reader.format();
+    if streaming && !matches!(maybe_format, Some(ImageFormat::Gif | ImageFormat::Png)) {
+        // only GIF and PNG are decoded without seeking around
+        reader = ImageReader::new(reader.into_inner().into_buffered()?);
+        if let Some(format) = maybe_format {
+            reader.set_format(format);
+        }
+    }
     let output_format = output_format.unwrap_or(maybe_format.unwrap_or(ImageFormat::Png));
+    if args.lossy && output_format == ImageFormat::WebP {
+        return Err(FixPixelartError::InvalidArgs("lossy WebP encoding isn't supported by the image crate, leave out --lossy to write a lossless WebP"));
+    }
+    if let Some(out_dir) = &args.out_dir {
+        let output = output_from(&args, output_format)?;
+        if is_same_file(&args.input, &output) {
+            return Err(FixPixelartError::InvalidArgs("--out-dir would overwrite the input, use --in-place for that"));
+        }
+        // the outputs for --zip-out are kept in memory
+        if !args.only_analyze && !args.dry_run && args.zip_outputs.is_none() {
+            std::fs::create_dir_all(out_dir)?;
+        }
+    }
+    if !args.only_analyze && (args.no_clobber || args.interactive) {
+        let output = output_from(&args, output_format)?;
+        // overwriting the input is the point of --in-place
+        if output != args.input && !is_stdout(&output) && Path::new(&output).exists() && !confirm_overwrite(&output, &args)? {
+            info!("skipping {:?}, output {output:?} already exists", args.input);
+            return Ok(Processed::Skipped);
+        }
+    }
+    if (args.lenient || args.copy_if_native) && (maybe_format != Some(output_format) || is_stdin(&args.input)) {
+        // copying the input would give a file in the wrong format, or stdin is already consumed
+        args.normalize = true;
+    }
 
-    match maybe_format {
+    let processed = match maybe_format {
         Some(ImageFormat::Gif) => {
-            let decoder = GifDecoder::new(reader.into_inner())?;
-            resize_animation(decoder, output_format, args)?;
+            // The image crate doesn't expose the loop count and background color of GIFs, so read them ourselves.
+            let mut reader = reader.into_inner();
+            let meta = if reader.is_stream() { read_gif_meta_head(&mut reader) } else { read_gif_meta(&mut reader) };
+            let meta = meta.unwrap_or_default();
+            reader.rewind()?;
+            let repeat = match meta.loop_count {
+                None | Some(0) => Repeat::Infinite,
+                Some(count) => Repeat::Finite(count),
+            };
+            args.frame_count = Some(meta.frame_count).filter(|&count| count > 0);
+            let decoder = GifDecoder::new(reader)?;
+            resize_animation(decoder, repeat, meta.background_color, output_format, args)?
         }
         Some(ImageFormat::WebP) => {
-            let decoder = WebPDecoder::new(reader.into_inner())?;
+            // The image crate doesn't expose the loop count of animated WebPs.
+            let mut reader = reader.into_inner();
+            let (repeat, frame_count, lossy) = match image_webp::WebPDecoder::new(&mut reader).map(|mut decoder| (decoder.loop_count(), decoder.num_frames(), decoder.is_lossy())) {
+                Ok((LoopCount::Times(count), frame_count, lossy)) => (Repeat::Finite(count.get()), Some(frame_count as usize), lossy),
+                Ok((LoopCount::Forever, frame_count, lossy)) => (Repeat::Infinite, Some(frame_count as usize), lossy),
+                Err(_) => (Repeat::Infinite, None, false),
+            };
+            args.frame_count = frame_count;
+            args.lossy_input = lossy;
+            reader.rewind()?;
+            let decoder = WebPDecoder::new(reader)?;
             if decoder.has_animation() {
-                resize_animation(decoder, output_format, args)?;
+                resize_animation(decoder, repeat, None, output_format, args)?
             } else {
-                resize_still_image(&DynamicImage::from_decoder(decoder)?, output_format, args)?;
+                let (img, icc_profile) = decode_still(decoder, &args)?;
+                resize_still_image(&img, output_format, None, icc_profile, args)?
             }
         }
         Some(ImageFormat::Png) => {
-            let decoder = PngDecoder::new(reader.into_inner())?;
-            if decoder.is_apng()? {
-                let (width, height) = decoder.dimensions();
-                if output_format == ImageFormat::Gif {
-                    resize_as_animated_gif(width, height, decoder.apng()?.into_frames(), args)?;
+            let mut reader = reader.into_inner();
+            let png = read_png_info(&mut reader)?;
+            reader.rewind()?;
+            if let Some(apng) = png.apng {
+                args.frame_count = Some(apng.num_frames as usize);
+                let repeat = match apng.num_plays {
+                    0 => Repeat::Infinite,
+                    count => Repeat::Finite(count.min(u16::MAX as u32) as u16),
+                };
+                if args.explode.is_some() {
+                    let mut decoder = PngDecoder::new(reader)?;
+                    let (width, height) = decoder.dimensions();
+                    let icc_profile = icc_profile(&mut decoder);
+                    explode_animation(width, height, decoder.apng()?.into_frames(), icc_profile, args)?
+                } else if output_format == ImageFormat::Gif {
+                    // The frames iterator skips a default image that isn't part of the animation.
+                    let mut decoder = PngDecoder::new(reader)?;
+                    let (width, height) = decoder.dimensions();
+                    let icc_profile = icc_profile(&mut decoder);
+                    resize_as_animated_gif(width, height, decoder.apng()?.into_frames(), repeat, None, icc_profile, args)?
+                } else if output_format == ImageFormat::Png {
+                    // Decoding into a still image yields the default image, but consumes the
+                    // decoder, so the stream is decoded twice if the default image is hidden.
+                    let default_image = if apng.hidden_default_image {
+                        let img = DynamicImage::from_decoder(PngDecoder::new(&mut reader)?)?;
+                        reader.rewind()?;
+                        Some(img)
+                    } else {
+                        None
+                    };
+                    let mut decoder = PngDecoder::new(reader)?;
+                    let (width, height) = decoder.dimensions();
+                    let icc_profile = icc_profile(&mut decoder);
+                    resize_as_apng(width, height, decoder.apng()?.into_frames(), repeat, default_image, icc_profile, args)?
+                } else if output_format == ImageFormat::WebP {
+                    let mut decoder = PngDecoder::new(reader)?;
+                    let (width, height) = decoder.dimensions();
+                    let icc_profile = icc_profile(&mut decoder);
+                    resize_as_animated_webp(width, height, decoder.apng()?.into_frames(), repeat, icc_profile, args)?
                 } else {
                     if !args.only_analyze {
+                        check_animation_downgrade(output_format, &args)?;
                         print_animation_downgrade_warning_if_needed(output_format);
                     }
-                    resize_still_image(&DynamicImage::from_decoder(decoder)?, output_format, args)?;
+                    // This is the default image, which is the intended fallback for a still image.
+                    let (img, icc_profile) = decode_still(PngDecoder::new(reader)?, &args)?;
+                    resize_still_image(&img, output_format, None, icc_profile, args)?
                 }
             } else {
-                resize_still_image(&DynamicImage::from_decoder(decoder)?, output_format, args)?;
+                // Nearest neighbor scaling only picks colors of the palette, so it can be kept.
+                let palette = png.palette.filter(|_| output_format == ImageFormat::Png && args.filter == Filter::Nearest);
+                let (img, icc_profile) = decode_still(PngDecoder::new(reader)?, &args)?;
+                resize_still_image(&img, output_format, palette.as_ref(), icc_profile, args)?
             }
         }
+        Some(ImageFormat::Avif) => {
+            // The image crate can't decode AVIF image sequences, only their first frame.
+            let mut reader = reader.into_inner();
+            if is_avif_sequence(&mut reader)? && !args.only_analyze {
+                warn!("animated AVIF images are not supported, processing only the first frame");
+            }
+            reader.rewind()?;
+            let decoder = ImageReader::with_format(reader, ImageFormat::Avif).into_decoder().map_err(|err| match err {
+                // decoding needs the avif-native feature of the image crate, which needs the dav1d library
+                ImageError::Unsupported(_) => FixPixelartError::InvalidArgs("decoding AVIF images isn't supported by this build"),
+                err => err.into(),
+            })?;
+            let (img, icc_profile) = decode_still(decoder, &args)?;
+            resize_still_image(&img, output_format, None, icc_profile, args)?
+        }
         _ => {
-            let img = reader.decode()?;
-            resize_still_image(&img, output_format, args)?;
+            let (img, icc_profile) = decode_still(reader.into_decoder()?, &args)?;
+            resize_still_image(&img, output_format, None, icc_profile, args)?
+        }
+    };
+
+    Ok(processed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{native_art, native_art_of, upscale, OPAQUE_PALETTE};
+
+    /// A directory of its own for the files of a test, removed with them when
+    /// the test ends, even if it panics.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            loop {
+                let path = std::env::temp_dir().join(format!("fix-pixelart-test-{}-{}-{test}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+                // fails if the directory already exists, so it's never someone else's
+                match std::fs::create_dir(&path) {
+                    Ok(()) => return TempDir(path),
+                    Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                    Err(err) => panic!("{}: {err}", path.display()),
+                }
+            }
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
         }
     }
 
-    Ok(())
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn parse_args(args: &[&OsStr]) -> Args {
+        Args::parse_from([OsStr::new("fix-pixelart"), OsStr::new("--quiet")].into_iter().chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn apng_keeps_hidden_default_image() {
+        let dir = TempDir::new("apng-keeps-hidden-default-image");
+        let (input, output) = (dir.path("hidden-default.png"), dir.path("hidden-default.scaled.png"));
+        let fallback = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        let frames = [native_art(4, 4, 0), native_art(4, 4, 0x55)];
+        {
+            let mut encoder = png::Encoder::new(File::create(&input).unwrap(), 12, 12);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_animated(frames.len() as u32, 0).unwrap();
+            encoder.set_sep_def_img(true).unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            for image in std::iter::once(&fallback).chain(&frames) {
+                writer.write_image_data(upscale(image, 3).as_raw()).unwrap();
+            }
+        }
+
+        let processed = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+        let info = read_png_info(File::open(&output).unwrap());
+        let default_image = image::open(&output);
+        let decoded: Vec<_> = PngDecoder::new(std::io::BufReader::new(File::open(&output).unwrap())).unwrap()
+            .apng().unwrap().into_frames().collect_frames().unwrap();
+
+        assert!(matches!(processed, Ok(Processed::Scaled(scale, _)) if scale == Scale::square(3)));
+        let apng = info.unwrap().apng.expect("output is animated");
+        assert!(apng.hidden_default_image);
+        assert_eq!(apng.num_frames, 2);
+        assert_eq!(default_image.unwrap().to_rgba8(), fallback);
+        let decoded: Vec<_> = decoded.into_iter().map(Frame::into_buffer).collect();
+        assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn still_formats_round_trip() {
+        let art = upscale(&native_art(5, 4, 0x33), 3);
+        let checkerboard = upscale(&RgbaImage::from_fn(5, 4, |x, y| if (x + y) % 2 == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255; 4]) }), 3);
+        let dir = TempDir::new("still-formats-round-trip");
+        for extension in ["bmp", "tiff", "tga", "ppm", "pgm", "pbm", "pam"] {
+            let (input, output) = (dir.path(&format!("art.{extension}")), dir.path(&format!("art.scaled.{extension}")));
+            let art = if extension == "pbm" { &checkerboard } else { &art };
+            if ImageFormat::from_path(&input).unwrap() == ImageFormat::Pnm {
+                write_pnm(art, input.as_os_str(), File::create(&input).unwrap()).unwrap();
+            } else {
+                art.save(&input).unwrap();
+            }
+            // the colors as the input format could store them, at the native size
+            let loaded = image::open(&input).unwrap().to_rgba8();
+            let expected = RgbaImage::from_fn(5, 4, |x, y| *loaded.get_pixel(x * 3 + 1, y * 3 + 1));
+
+            let processed = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+            let decoded = image::open(&output);
+
+            assert!(matches!(processed, Ok(Processed::Scaled(scale, _)) if scale == Scale::square(3)), "{extension}: {processed:?}");
+            assert_eq!(decoded.unwrap().to_rgba8(), expected, "{extension}");
+        }
+    }
+
+    #[test]
+    fn gradient_fails_detection() {
+        let dir = TempDir::new("gradient-fails-detection");
+        let (input, output) = (dir.path("gradient.png"), dir.path("gradient.scaled.png"));
+        RgbaImage::from_fn(64, 48, |x, y| Rgba([(x * 4) as u8, (y * 5) as u8, 128, 255])).save(&input).unwrap();
+
+        let failed = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+        let written = output.exists();
+        let copied = process(parse_args(&[OsStr::new("--lenient"), input.as_os_str(), output.as_os_str()]));
+        let unchanged = std::fs::read(&output).ok() == std::fs::read(&input).ok();
+        let _ = std::fs::remove_file(&output);
+        let native = process(parse_args(&[OsStr::new("--copy-if-native"), input.as_os_str(), output.as_os_str()]));
+        let passed_through = std::fs::read(&output).ok() == std::fs::read(&input).ok();
+
+        let err = failed.unwrap_err();
+        assert!(matches!(err, FixPixelartError::DetectionFailed), "{err:?}");
+        assert_eq!(err.exit_code(), EXIT_NOT_DETECTED);
+        assert!(!written);
+        assert!(matches!(copied, Ok(Processed::Copied)), "{copied:?}");
+        assert_eq!(summarize(&copied).0, "native");
+        assert!(unchanged);
+        assert!(matches!(native, Ok(Processed::Native)), "{native:?}");
+        assert!(passed_through);
+    }
+
+    #[test]
+    fn lossy_webp_output_is_refused() {
+        let dir = TempDir::new("lossy-webp-output-is-refused");
+        let (input, output) = (dir.path("art.png"), dir.path("art.webp"));
+        upscale(&native_art(4, 4, 7), 3).save(&input).unwrap();
+
+        let lossy = process(parse_args(&[OsStr::new("--lossy"), input.as_os_str(), output.as_os_str()]));
+        let written = output.exists();
+        let lossless = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+        let decoded = image::open(&output);
+
+        let err = lossy.unwrap_err();
+        assert!(matches!(err, FixPixelartError::InvalidArgs(_)), "{err:?}");
+        assert!(!written);
+        assert!(matches!(lossless, Ok(Processed::Scaled(scale, _)) if scale == Scale::square(3)), "{lossless:?}");
+        assert_eq!(decoded.unwrap().to_rgba8(), native_art(4, 4, 7));
+    }
+
+    #[test]
+    fn tiny_images_are_too_small() {
+        let dir = TempDir::new("tiny-images-are-too-small");
+        for (width, height, force_scale, scaled) in [(1, 1, "1x1", (1, 1)), (1, 6, "1x3", (1, 2)), (6, 1, "3x1", (2, 1))] {
+            let name = format!("tiny-{width}x{height}");
+            let (input, output) = (dir.path(&format!("{name}.png")), dir.path(&format!("{name}.scaled.png")));
+            RgbaImage::from_fn(width, height, |x, y| Rgba([(x / 3 * 100) as u8, (y / 3 * 100) as u8, 0, 255])).save(&input).unwrap();
+
+            let detected = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+            let forced = process(parse_args(&[OsStr::new("--force-scale"), OsStr::new(force_scale), input.as_os_str(), output.as_os_str()]));
+            let decoded = image::open(&output);
+
+            let err = detected.unwrap_err();
+            assert!(matches!(err, FixPixelartError::TooSmall { width: w, height: h } if (w, h) == (width, height)), "{err:?}");
+            assert_eq!(err.exit_code(), EXIT_NOT_DETECTED);
+            assert!(forced.is_ok(), "{name}: {forced:?}");
+            assert_eq!(decoded.unwrap().dimensions(), scaled, "{name}");
+        }
+    }
+
+    #[test]
+    fn stride_longer_than_the_image_is_not_detected() {
+        let dir = TempDir::new("stride-longer-than-the-image-is-not-detected");
+        let (input, output) = (dir.path("wide.png"), dir.path("wide.scaled.png"));
+        // runs of 3 along the rows, but only 2 pixels high
+        RgbaImage::from_fn(6, 2, |x, _| if x < 3 { Rgba([0, 0, 0, 255]) } else { Rgba([255; 4]) }).save(&input).unwrap();
+
+        let detected = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+        let analyzed = process(parse_args(&[OsStr::new("--only-analyze"), input.as_os_str()]));
+        let forced = process(parse_args(&[OsStr::new("--force-scale"), OsStr::new("3x2"), input.as_os_str(), output.as_os_str()]));
+        let decoded = image::open(&output);
+
+        let err = detected.unwrap_err();
+        assert!(matches!(err, FixPixelartError::DetectionFailed), "{err:?}");
+        assert!(analyzed.is_err(), "{analyzed:?}");
+        assert!(forced.is_ok(), "{forced:?}");
+        assert_eq!(decoded.unwrap().dimensions(), (2, 1));
+    }
+
+    #[test]
+    fn shrunk_size_is_at_least_one_pixel() {
+        let grid = Grid { scale: Scale::square(4), x: 0, y: 0, pad: false, dither: false };
+        assert_eq!(grid.shrink_size(1, 1), (1, 1));
+        assert_eq!(grid.shrink_size(1, 8), (1, 2));
+        assert_eq!(grid.shrink_size(8, 3), (2, 1));
+        assert_eq!(Grid { pad: true, ..grid }.shrink_size(2, 3), (1, 1));
+    }
+
+    #[test]
+    fn zip_is_processed_in_memory() {
+        let dir = TempDir::new("zip-is-processed-in-memory");
+        let (archive, zip_out, out_dir) = (dir.path("pack.zip"), dir.path("pack.scaled.zip"), dir.path("pack-out"));
+        let art = native_art(4, 3, 0);
+        let mut hero = Vec::new();
+        upscale(&art, 3).write_to(&mut std::io::Cursor::new(&mut hero), ImageFormat::Png).unwrap();
+        let mut writer = ZipWriter::new(Vec::new());
+        for (name, data) in [("sprites/", &[][..]), ("sprites/hero.png", &hero), ("README.txt", b"hello"), ("../evil.png", &hero)] {
+            writer.add(name, zip::DosTime::default(), data).unwrap();
+        }
+        std::fs::write(&archive, writer.finish().unwrap()).unwrap();
+
+        let analyzed = process_zip(archive.as_os_str(), parse_args(&[OsStr::new("--zip"), archive.as_os_str(), OsStr::new("--only-analyze")]));
+        let zipped = process_zip(archive.as_os_str(), parse_args(&[OsStr::new("--zip"), archive.as_os_str(), OsStr::new("--zip-out"), zip_out.as_os_str()]));
+        let new_entries = std::fs::read(&zip_out).and_then(|data| zip::read_archive(&data));
+        let extracted = process_zip(archive.as_os_str(), parse_args(&[OsStr::new("--zip"), archive.as_os_str(), OsStr::new("--out-dir"), out_dir.as_os_str()]));
+        let written = image::open(out_dir.join("sprites/hero.png"));
+        let out_files = std::fs::read_dir(&out_dir).map(|dir| dir.count());
+
+        assert_eq!((analyzed, zipped, extracted), (0, 0, 0));
+        let new_entries = new_entries.unwrap();
+        let names: Vec<_> = new_entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["sprites/", "sprites/hero.png", "README.txt"]);
+        assert_eq!(image::load_from_memory(&new_entries[1].data).unwrap().to_rgba8(), art);
+        assert_eq!(new_entries[2].data, b"hello");
+        assert_eq!(written.unwrap().to_rgba8(), art);
+        // only the images are written to --out-dir
+        assert_eq!(out_files.unwrap(), 1);
+    }
+
+    #[test]
+    fn gif_disposal_methods_round_trip() {
+        use gif::DisposalMethod;
+
+        let dir = TempDir::new("gif-disposal-methods-round-trip");
+        let (input, output) = (dir.path("disposal.gif"), dir.path("disposal.scaled.gif"));
+        let background = native_art_of(OPAQUE_PALETTE, 6, 6, 0);
+        let patches = [
+            (native_art_of(OPAQUE_PALETTE, 6, 6, 0), 0, 0, DisposalMethod::Keep),
+            (RgbaImage::from_pixel(2, 2, Rgba([255, 0, 0, 255])), 1, 1, DisposalMethod::Previous),
+            (RgbaImage::from_pixel(2, 3, Rgba([0, 255, 0, 255])), 3, 2, DisposalMethod::Background),
+            (RgbaImage::from_pixel(3, 2, Rgba([0, 0, 255, 255])), 0, 4, DisposalMethod::Keep),
+        ];
+        {
+            let mut encoder = gif::Encoder::new(File::create(&input).unwrap(), 18, 18, &[]).unwrap();
+            for (patch, left, top, dispose) in &patches {
+                let mut rgba = upscale(patch, 3).into_raw();
+                let mut frame = gif::Frame::from_rgba_speed(patch.width() as u16 * 3, patch.height() as u16 * 3, &mut rgba, 10);
+                frame.left = *left as u16 * 3;
+                frame.top = *top as u16 * 3;
+                frame.dispose = *dispose;
+                frame.delay = 10;
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        // the frames as they are shown, at the native size
+        let mut expected = vec![background.clone()];
+        let mut canvas = background.clone();
+        imageops::replace(&mut canvas, &patches[1].0, 1, 1);
+        expected.push(canvas);
+        // previous restores the canvas from before the second frame
+        let mut canvas = background.clone();
+        imageops::replace(&mut canvas, &patches[2].0, 3, 2);
+        expected.push(canvas);
+        // background clears the area of the third frame
+        let mut canvas = background.clone();
+        imageops::replace(&mut canvas, &RgbaImage::new(2, 3), 3, 2);
+        imageops::replace(&mut canvas, &patches[3].0, 0, 4);
+        expected.push(canvas);
+
+        let processed = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+        let decoded: Vec<_> = GifDecoder::new(std::io::BufReader::new(File::open(&output).unwrap())).unwrap()
+            .into_frames().collect_frames().unwrap();
+
+        assert!(matches!(processed, Ok(Processed::Scaled(scale, _)) if scale == Scale::square(3)), "{processed:?}");
+        let decoded: Vec<_> = decoded.into_iter().map(Frame::into_buffer).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn rfc3339_timestamps() {
+        let at = |seconds: i64| if seconds >= 0 {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(seconds as u64)
+        } else {
+            SystemTime::UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+        };
+        for (value, seconds) in [
+            ("1970-01-01T00:00:00Z", 0),
+            ("1969-12-31T23:59:59Z", -1),
+            ("2024-02-29", 1709164800),
+            ("2000-02-29", 951782400),
+            ("2024-02-29T12:34:56Z", 1709210096),
+            ("2024-02-29T12:34:56.789z", 1709210096),
+            ("2024-02-29t12:34:56+02:00", 1709202896),
+            ("2024-02-29 12:34:56.5-05:30", 1709229896),
+            ("2016-12-31T23:59:60Z", 1483228800),
+        ] {
+            assert_eq!(parse_rfc3339(value), Some(at(seconds)), "{value}");
+        }
+        for value in [
+            "", "2024", "2024-1-01", "2024-13-01", "2024-00-10", "2024-01-00", "2024-01-32",
+            "2024-02-30", "2023-02-29", "1900-02-29", "2024-04-31", "2024-01-01T",
+            "2024-01-01T00:00:00", "2024-01-01T24:00:00Z", "2024-01-01T00:00Z",
+            "2024-01-01T00:00:00+2", "2024-01-01T00:00:00+02:00:00", "2024-01-01X00:00:00Z",
+            "2024-01-0éT00:00:00Z", "2024-01-01T00:00:0éZ", "２０２４-01-01",
+        ] {
+            assert_eq!(parse_rfc3339(value), None, "{value}");
+        }
+        let args = Args::try_parse_from(["fix-pixelart", "--since", "2024-01-0éT00:00:00Z", "in.png", "out.png"]);
+        assert_eq!(args.unwrap_err().kind(), clap::error::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn gif_delay_rounds_to_centiseconds() {
+        let ms = |numer, denom| Delay::from_numer_denom_ms(numer, denom);
+        assert_eq!(gif_delay(ms(40, 1), 0), ms(40, 1));
+        assert_eq!(gif_delay(ms(44, 1), 0), ms(40, 1));
+        assert_eq!(gif_delay(ms(45, 1), 0), ms(50, 1));
+        assert_eq!(gif_delay(ms(100, 3), 0), ms(30, 1));
+        assert_eq!(gif_delay(ms(1, 1), 0), ms(10, 1));
+        assert_eq!(gif_delay(ms(0, 1), 0), ms(0, 1));
+        assert_eq!(gif_delay(ms(40, 1), 55), ms(60, 1));
+    }
+
+    #[test]
+    fn gif_keeps_40ms_delay() {
+        let dir = TempDir::new("gif-keeps-40ms-delay");
+        let (input, output) = (dir.path("delay.gif"), dir.path("delay.scaled.gif"));
+        {
+            let mut encoder = GifEncoder::new(File::create(&input).unwrap());
+            encoder.set_repeat(Repeat::Infinite).unwrap();
+            let frames = [native_art_of(OPAQUE_PALETTE, 4, 4, 0), native_art_of(OPAQUE_PALETTE, 4, 4, 0x55)].map(|art| {
+                Frame::from_parts(upscale(&art, 3), 0, 0, Delay::from_numer_denom_ms(40, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+
+        let processed = process(parse_args(&[input.as_os_str(), output.as_os_str()]));
+        let mut decoder = gif::DecodeOptions::new().read_info(File::open(&output).unwrap()).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+
+        assert!(matches!(processed, Ok(Processed::Scaled(scale, _)) if scale == Scale::square(3)), "{processed:?}");
+        assert_eq!(delays, [4, 4]);
+    }
 }
@@ -0,0 +1,175 @@
+//! Where the output is written to, either a file, stdout, or memory.
+
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Output path that means stdout.
+pub const STDOUT: &str = "-";
+
+pub fn is_stdout(output: &OsStr) -> bool {
+    output == STDOUT
+}
+
+/// Tells apart the temporary files of one process, e.g. of the jobs of a batch.
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Some encoders need to seek, which stdout can't, so output to stdout is
+/// buffered in memory and only written by [`OutputWriter::finish`].
+pub enum OutputWriter {
+    File(TempFile),
+    Stdout(Cursor<Vec<u8>>),
+    Memory(MemoryOutput),
+}
+
+impl OutputWriter {
+    pub fn create(output: &OsStr) -> io::Result<Self> {
+        if is_stdout(output) {
+            Ok(OutputWriter::Stdout(Cursor::new(Vec::new())))
+        } else {
+            Ok(OutputWriter::File(TempFile::create(Path::new(output))?))
+        }
+    }
+
+    /// Keeps the output in `outputs` instead of writing it, as made from `input`.
+    pub fn in_memory(outputs: &Arc<MemoryOutputs>, input: &OsStr, output: &OsStr) -> Self {
+        OutputWriter::Memory(MemoryOutput {
+            outputs: Arc::clone(outputs),
+            input: input.to_owned(),
+            output: output.to_owned(),
+            buffer: Cursor::new(Vec::new()),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::File(file) => file.persist(),
+            OutputWriter::Stdout(buffer) => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(buffer.get_ref())?;
+                stdout.flush()
+            }
+            OutputWriter::Memory(memory) => {
+                memory.outputs.lock().entry(memory.input).or_default().push((memory.output, memory.buffer.into_inner()));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::File(file) => file.writer.write(buf),
+            OutputWriter::Stdout(buffer) => buffer.write(buf),
+            OutputWriter::Memory(memory) => memory.buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::File(file) => file.writer.flush(),
+            OutputWriter::Stdout(buffer) => buffer.flush(),
+            OutputWriter::Memory(memory) => memory.buffer.flush(),
+        }
+    }
+}
+
+impl Seek for OutputWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            OutputWriter::File(file) => file.writer.seek(pos),
+            OutputWriter::Stdout(buffer) => buffer.seek(pos),
+            OutputWriter::Memory(memory) => memory.buffer.seek(pos),
+        }
+    }
+}
+
+/// A file that is written next to the output and only renamed to it once it is
+/// complete, so an interrupted run never leaves a truncated output behind, nor
+/// destroys the input with --in-place. The file is removed if it is dropped
+/// before that.
+pub struct TempFile {
+    writer: BufWriter<File>,
+    temp_path: Option<PathBuf>,
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn create(path: &Path) -> io::Result<Self> {
+        // Replace the target of a symbolic link, not the link itself.
+        let path = if path.is_symlink() { fs::canonicalize(path)? } else { path.to_owned() };
+        let mut name = OsStr::new(".").to_owned();
+        name.push(path.file_name().unwrap_or(OsStr::new("output")));
+        name.push(format!(".{}-{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let temp_path = path.with_file_name(name);
+        let file = File::options().write(true).create_new(true).open(&temp_path)?;
+        Ok(TempFile {
+            writer: BufWriter::new(file),
+            temp_path: Some(temp_path),
+            path,
+        })
+    }
+
+    /// Renames the file to the output, which gets replaced, keeping its permissions.
+    fn persist(mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let Some(temp_path) = self.temp_path.take() else {
+            return Ok(());
+        };
+        let result = fs::metadata(&self.path)
+            .map_or(Ok(()), |meta| fs::set_permissions(&temp_path, meta.permissions()))
+            .and_then(|_| fs::rename(&temp_path, &self.path));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(temp_path) = &self.temp_path {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
+
+/// Outputs that are kept in memory instead of being written, grouped by the
+/// input they were made from, e.g. to put them into a new archive for --zip-out.
+#[derive(Debug, Default)]
+pub struct MemoryOutputs(Mutex<HashMap<OsString, Vec<Output>>>);
+
+/// The path and the data of an output.
+type Output = (OsString, Vec<u8>);
+
+impl MemoryOutputs {
+    fn lock(&self) -> MutexGuard<'_, HashMap<OsString, Vec<Output>>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Removes the outputs made from `input` and returns them with their
+    /// paths, in the order they were written.
+    pub fn take(&self, input: &OsStr) -> Vec<Output> {
+        self.lock().remove(input).unwrap_or_default()
+    }
+
+    /// Size of the output kept for the path `output`.
+    pub fn len_of(&self, output: &OsStr) -> Option<u64> {
+        self.lock().values().flatten()
+            .find(|(path, _)| path == output)
+            .map(|(_, data)| data.len() as u64)
+    }
+}
+
+/// An output that is added to [`MemoryOutputs`] by [`OutputWriter::finish`].
+pub struct MemoryOutput {
+    outputs: Arc<MemoryOutputs>,
+    input: OsString,
+    output: OsString,
+    buffer: Cursor<Vec<u8>>,
+}
@@ -0,0 +1,64 @@
+//! A progress indicator on stderr for long animations and big batches.
+
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Whether a progress line is currently shown, so messages can clear it first.
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+/// Counts processed items and redraws a single line like `frames 12/500`.
+/// Only drawn if stderr is a terminal. The line is removed when dropped.
+pub struct Progress {
+    label: &'static str,
+    total: Option<usize>,
+    done: AtomicUsize,
+    enabled: bool,
+}
+
+impl Progress {
+    /// `total` is `None` if the number of items isn't known up front.
+    pub fn new(label: &'static str, total: Option<usize>, enabled: bool) -> Self {
+        let progress = Progress {
+            label,
+            total,
+            done: AtomicUsize::new(0),
+            enabled: enabled && io::stderr().is_terminal(),
+        };
+        progress.draw(0);
+        progress
+    }
+
+    /// Counts one more item as processed.
+    pub fn inc(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.draw(done);
+    }
+
+    fn draw(&self, done: usize) {
+        if !self.enabled {
+            return;
+        }
+        let mut stderr = io::stderr().lock();
+        let _ = match self.total {
+            Some(total) => write!(stderr, "\r\x1b[K{} {done}/{total}", self.label),
+            None => write!(stderr, "\r\x1b[K{} {done}", self.label),
+        };
+        SHOWN.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.enabled {
+            clear();
+        }
+    }
+}
+
+/// Removes the progress line, if any, so a message can be printed in its place.
+/// It is drawn again with the next processed item.
+pub fn clear() {
+    if SHOWN.swap(false, Ordering::Relaxed) {
+        let _ = write!(io::stderr().lock(), "\r\x1b[K");
+    }
+}
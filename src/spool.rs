@@ -0,0 +1,96 @@
+//! A temporary file the frames of an animation are spooled to for --spool-dir,
+//! so they don't all have to be in memory between the detection and the encoding.
+
+use image::{Delay, RgbaImage};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Tells apart the spool files of one process, e.g. of the jobs of a batch.
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A spooled frame: delay, left, top, and the frame itself.
+pub type SpooledFrame = (Delay, u32, u32, RgbaImage);
+
+/// What is needed to read a frame back, the pixels follow each other in the file.
+struct Entry {
+    delay: Delay,
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Removes the file when dropped, even if reading it back failed.
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Frames written one after another as raw RGBA with 8 bits per channel.
+pub struct Spool {
+    writer: BufWriter<File>,
+    entries: Vec<Entry>,
+    path: TempPath,
+}
+
+impl Spool {
+    /// Creates a new hidden file in `dir`.
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        let name = format!(".fix-pixelart-{}-{}.spool", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = dir.join(name);
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Spool {
+            writer: BufWriter::new(file),
+            entries: Vec::new(),
+            path: TempPath(path),
+        })
+    }
+
+    pub fn push(&mut self, delay: Delay, left: u32, top: u32, frame: &RgbaImage) -> io::Result<()> {
+        self.writer.write_all(frame.as_raw())?;
+        let (width, height) = frame.dimensions();
+        self.entries.push(Entry { delay, left, top, width, height });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Reads the frames back in the order they were pushed. The file is
+    /// removed once they are all read or the iterator is dropped.
+    pub fn into_frames(self) -> io::Result<SpooledFrames> {
+        let mut file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(SpooledFrames {
+            reader: BufReader::new(file),
+            entries: self.entries.into_iter(),
+            _path: self.path,
+        })
+    }
+}
+
+pub struct SpooledFrames {
+    reader: BufReader<File>,
+    entries: std::vec::IntoIter<Entry>,
+    _path: TempPath,
+}
+
+impl Iterator for SpooledFrames {
+    type Item = io::Result<SpooledFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Entry { delay, left, top, width, height } = self.entries.next()?;
+        let mut pixels = vec![0; width as usize * height as usize * 4];
+        if let Err(err) = self.reader.read_exact(&mut pixels) {
+            return Some(Err(err));
+        }
+        let frame = RgbaImage::from_raw(width, height, pixels).expect("buffer fits the frame");
+        Some(Ok((delay, left, top, frame)))
+    }
+}
@@ -0,0 +1,48 @@
+//! ANSI colors of the messages on stderr, see --color.
+
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether messages are colored, set once at startup.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Green, for written output.
+    Success,
+    /// Yellow.
+    Warning,
+    /// Red.
+    Error,
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Success => "\x1b[32m",
+            Style::Warning => "\x1b[33m",
+            Style::Error => "\x1b[31m",
+        }
+    }
+}
+
+/// A value that is displayed in a color if coloring is enabled.
+pub struct Styled<T>(Style, T);
+
+pub fn paint<T: Display>(style: Style, value: T) -> Styled<T> {
+    Styled(style, value)
+}
+
+impl<T: Display> Display for Styled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if ENABLED.load(Ordering::Relaxed) {
+            write!(f, "{}{}\x1b[0m", self.0.code(), self.1)
+        } else {
+            self.1.fmt(f)
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Writes animated WebP files. The image crate can only write still WebP
+//! images, so each frame is encoded as a lossless still image and its
+//! bitstream is wrapped into the extended (animated) WebP container.
+
+use image::codecs::webp::WebPEncoder;
+use image::{Delay, ExtendedColorType, ImageError, ImageResult, RgbaImage};
+use image::error::{EncodingError, ImageFormatHint};
+use image::ImageFormat;
+
+use std::borrow::Cow;
+use std::io::Write;
+
+const FLAG_ICC_PROFILE: u8 = 0x20;
+const FLAG_ALPHA: u8 = 0x10;
+const FLAG_ANIMATION: u8 = 0x02;
+const FRAME_NO_BLEND: u8 = 0x02;
+
+/// Largest value of the 24 bit fields of the container.
+const MAX_U24: u32 = (1 << 24) - 1;
+
+pub struct AnimatedWebPEncoder<W: Write> {
+    writer: W,
+    width: u32,
+    height: u32,
+    loop_count: u16,
+    has_alpha: bool,
+    icc_profile: Option<Vec<u8>>,
+    frames: Vec<u8>,
+}
+
+impl<W: Write> AnimatedWebPEncoder<W> {
+    /// `loop_count` 0 means the animation loops infinitely.
+    pub fn new(writer: W, width: u32, height: u32, loop_count: u16) -> Self {
+        Self {
+            writer,
+            width,
+            height,
+            loop_count,
+            has_alpha: false,
+            icc_profile: None,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Embeds the ICC color profile as an `ICCP` chunk.
+    pub fn set_icc_profile(&mut self, icc_profile: Vec<u8>) {
+        self.icc_profile = Some(icc_profile);
+    }
+
+    /// Adds a frame at the given position of the canvas. The frame replaces
+    /// the area of the canvas it covers. WebP can only place frames at even
+    /// coordinates, so frames at odd coordinates are expanded to the left/top
+    /// with transparent pixels and blended onto the canvas instead.
+    pub fn add_frame(&mut self, img: &RgbaImage, left: u32, top: u32, delay: Delay) -> ImageResult<()> {
+        let (left, top, img, frame_flags) = if !left.is_multiple_of(2) || !top.is_multiple_of(2) {
+            let mut expanded = RgbaImage::new(img.width() + left % 2, img.height() + top % 2);
+            image::imageops::replace(&mut expanded, img, (left % 2) as i64, (top % 2) as i64);
+            (left - left % 2, top - top % 2, Cow::Owned(expanded), 0)
+        } else {
+            (left, top, Cow::Borrowed(img), FRAME_NO_BLEND)
+        };
+
+        if img.pixels().any(|pixel| pixel[3] != 255) {
+            self.has_alpha = true;
+        }
+
+        let mut still = Vec::new();
+        WebPEncoder::new_lossless(&mut still).encode(img.as_raw(), img.width(), img.height(), ExtendedColorType::Rgba8)?;
+        let bitstream = find_chunk(&still, b"VP8L").ok_or_else(|| encoding_error("encoded WebP frame has no VP8L chunk"))?;
+
+        let (numer, denom) = delay.numer_denom_ms();
+        let duration = ((numer as u64 + denom as u64 / 2) / denom as u64).min(MAX_U24 as u64) as u32;
+
+        let mut payload = Vec::with_capacity(16 + 8 + bitstream.len() + 1);
+        payload.extend_from_slice(&u24(left / 2));
+        payload.extend_from_slice(&u24(top / 2));
+        payload.extend_from_slice(&u24(img.width() - 1));
+        payload.extend_from_slice(&u24(img.height() - 1));
+        payload.extend_from_slice(&u24(duration));
+        payload.push(frame_flags);
+        write_chunk(&mut payload, b"VP8L", bitstream);
+
+        write_chunk(&mut self.frames, b"ANMF", &payload);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> ImageResult<()> {
+        let mut flags = FLAG_ANIMATION;
+        if self.has_alpha {
+            flags |= FLAG_ALPHA;
+        }
+        if self.icc_profile.is_some() {
+            flags |= FLAG_ICC_PROFILE;
+        }
+
+        let mut vp8x = Vec::with_capacity(10);
+        vp8x.push(flags);
+        vp8x.extend_from_slice(&[0; 3]);
+        vp8x.extend_from_slice(&u24(self.width - 1));
+        vp8x.extend_from_slice(&u24(self.height - 1));
+
+        let mut anim = Vec::with_capacity(6);
+        // background color (BGRA), only a hint for viewers
+        anim.extend_from_slice(&[0; 4]);
+        anim.extend_from_slice(&self.loop_count.to_le_bytes());
+
+        let mut data = Vec::with_capacity(4 + 18 + 14 + self.frames.len());
+        data.extend_from_slice(b"WEBP");
+        write_chunk(&mut data, b"VP8X", &vp8x);
+        if let Some(icc_profile) = &self.icc_profile {
+            write_chunk(&mut data, b"ICCP", icc_profile);
+        }
+        write_chunk(&mut data, b"ANIM", &anim);
+        data.extend_from_slice(&self.frames);
+
+        self.writer.write_all(b"RIFF")?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&data)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let bytes = value.min(MAX_U24).to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+fn write_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if !data.len().is_multiple_of(2) {
+        out.push(0);
+    }
+}
+
+/// Finds the payload of a top level chunk of a RIFF WebP file.
+fn find_chunk<'a>(webp: &'a [u8], fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut data = webp.get(12..)?;
+    while data.len() >= 8 {
+        let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let payload = data.get(8..8 + size)?;
+        if &data[..4] == fourcc {
+            return Some(payload);
+        }
+        data = data.get(8 + size + size % 2..).unwrap_or(&[]);
+    }
+    None
+}
+
+fn encoding_error(message: &str) -> ImageError {
+    ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::WebP), message))
+}
@@ -0,0 +1,268 @@
+//! Just enough of the ZIP format to read and write sprite packs for --zip:
+//! stored and deflated entries, without ZIP64, encryption, or multiple disks.
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+const FLAG_ENCRYPTED: u16 = 1;
+/// The name is UTF-8 instead of code page 437.
+const FLAG_UTF8: u16 = 1 << 11;
+
+/// Version 2.0, which has deflate and directories.
+const VERSION: u16 = 20;
+
+/// Modification time of an entry in MS-DOS format.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DosTime {
+    pub time: u16,
+    pub date: u16,
+}
+
+/// An entry of an archive with its data decompressed.
+pub struct Entry {
+    pub name: String,
+    pub modified: DosTime,
+    pub data: Vec<u8>,
+}
+
+impl Entry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// The path to extract the entry to, relative to the target directory.
+    /// `None` if the name would escape it, like ../x or /x.
+    pub fn relative_path(&self) -> Option<PathBuf> {
+        let path = Path::new(&self.name);
+        let safe = !self.name.contains('\\') && path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        safe.then(|| path.to_path_buf())
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn u16_at(data: &[u8], offset: usize) -> io::Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or_else(|| invalid("truncated ZIP archive"))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> io::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .ok_or_else(|| invalid("truncated ZIP archive"))
+}
+
+/// Reads all entries of an archive in the order of its central directory.
+pub fn read_archive(data: &[u8]) -> io::Result<Vec<Entry>> {
+    // the end of central directory record is followed by a comment of up to 64 KiB
+    let search_start = data.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE + u16::MAX as usize);
+    let end = (search_start..=data.len().saturating_sub(END_OF_CENTRAL_DIRECTORY_SIZE)).rev()
+        .find(|&offset| u32_at(data, offset).is_ok_and(|signature| signature == END_OF_CENTRAL_DIRECTORY))
+        .ok_or_else(|| invalid("not a ZIP archive"))?;
+    let count = u16_at(data, end + 10)?;
+    let mut offset = u32_at(data, end + 16)? as usize;
+    if count == u16::MAX || offset == u32::MAX as usize {
+        return Err(invalid("ZIP64 archives are not supported"));
+    }
+
+    let mut entries = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if u32_at(data, offset)? != CENTRAL_HEADER {
+            return Err(invalid("broken central directory of ZIP archive"));
+        }
+        let flags = u16_at(data, offset + 8)?;
+        let method = u16_at(data, offset + 10)?;
+        let modified = DosTime { time: u16_at(data, offset + 12)?, date: u16_at(data, offset + 14)? };
+        let crc = u32_at(data, offset + 16)?;
+        let compressed_size = u32_at(data, offset + 20)? as usize;
+        let size = u32_at(data, offset + 24)? as usize;
+        let name_len = u16_at(data, offset + 28)? as usize;
+        let extra_len = u16_at(data, offset + 30)? as usize;
+        let comment_len = u16_at(data, offset + 32)? as usize;
+        let local_offset = u32_at(data, offset + 42)? as usize;
+        let name = data.get(offset + 46..offset + 46 + name_len).ok_or_else(|| invalid("truncated ZIP archive"))?;
+        // names that aren't flagged as UTF-8 are code page 437, which is the same for ASCII
+        let name = String::from_utf8_lossy(name).into_owned();
+        offset += 46 + name_len + extra_len + comment_len;
+
+        if flags & FLAG_ENCRYPTED != 0 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{name}: encrypted ZIP entries are not supported")));
+        }
+        if compressed_size == u32::MAX as usize || size == u32::MAX as usize || local_offset == u32::MAX as usize {
+            return Err(invalid("ZIP64 archives are not supported"));
+        }
+        if u32_at(data, local_offset)? != LOCAL_HEADER {
+            return Err(invalid("broken local header in ZIP archive"));
+        }
+        let start = local_offset + 30 + u16_at(data, local_offset + 26)? as usize + u16_at(data, local_offset + 28)? as usize;
+        let compressed = data.get(start..start + compressed_size).ok_or_else(|| invalid("truncated ZIP archive"))?;
+        let data = match method {
+            METHOD_STORED => compressed.to_vec(),
+            METHOD_DEFLATED => {
+                // the sizes come from the archive, so don't preallocate from
+                // them and stop as soon as there's more than declared
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(compressed).take(size as u64 + 1).read_to_end(&mut decompressed)?;
+                if decompressed.len() > size {
+                    return Err(invalid(&format!("{name}: corrupt ZIP entry, more data than declared")));
+                }
+                decompressed
+            }
+            method => return Err(io::Error::new(io::ErrorKind::Unsupported, format!("{name}: unsupported ZIP compression method {method}"))),
+        };
+        if data.len() != size || crc32fast::hash(&data) != crc {
+            return Err(invalid(&format!("{name}: corrupt ZIP entry, checksum mismatch")));
+        }
+        entries.push(Entry { name, modified, data });
+    }
+    Ok(entries)
+}
+
+/// What the central directory needs to know of a written entry.
+struct Written {
+    name: String,
+    modified: DosTime,
+    method: u16,
+    crc: u32,
+    compressed_size: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes an archive entry by entry. The central directory is written by
+/// [`ZipWriter::finish`].
+pub struct ZipWriter<W: Write> {
+    writer: W,
+    written: Vec<Written>,
+    offset: u64,
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(writer: W) -> Self {
+        ZipWriter { writer, written: Vec::new(), offset: 0 }
+    }
+
+    /// Adds an entry, deflated unless that doesn't make it smaller, which is
+    /// usual for images.
+    pub fn add(&mut self, name: &str, modified: DosTime, data: &[u8]) -> io::Result<()> {
+        let too_large = || io::Error::new(io::ErrorKind::Unsupported, format!("{name}: ZIP64 archives are not supported"));
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        let deflated = encoder.finish()?;
+        let (method, stored) = if deflated.len() < data.len() { (METHOD_DEFLATED, deflated.as_slice()) } else { (METHOD_STORED, data) };
+        let entry = Written {
+            name: name.to_owned(),
+            modified,
+            method,
+            crc: crc32fast::hash(data),
+            compressed_size: stored.len().try_into().map_err(|_| too_large())?,
+            size: data.len().try_into().map_err(|_| too_large())?,
+            offset: self.offset.try_into().map_err(|_| too_large())?,
+        };
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend(LOCAL_HEADER.to_le_bytes());
+        header.extend(VERSION.to_le_bytes());
+        header.extend(FLAG_UTF8.to_le_bytes());
+        header.extend(method.to_le_bytes());
+        header.extend(modified.time.to_le_bytes());
+        header.extend(modified.date.to_le_bytes());
+        header.extend(entry.crc.to_le_bytes());
+        header.extend(entry.compressed_size.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(stored)?;
+        self.offset += (header.len() + stored.len()) as u64;
+        self.written.push(entry);
+        Ok(())
+    }
+
+    /// Writes the central directory and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let too_large = || io::Error::new(io::ErrorKind::Unsupported, "ZIP64 archives are not supported");
+        let start: u32 = self.offset.try_into().map_err(|_| too_large())?;
+        let count: u16 = self.written.len().try_into().map_err(|_| too_large())?;
+        let mut directory = Vec::new();
+        for entry in &self.written {
+            directory.extend(CENTRAL_HEADER.to_le_bytes());
+            directory.extend(VERSION.to_le_bytes());
+            directory.extend(VERSION.to_le_bytes());
+            directory.extend(FLAG_UTF8.to_le_bytes());
+            directory.extend(entry.method.to_le_bytes());
+            directory.extend(entry.modified.time.to_le_bytes());
+            directory.extend(entry.modified.date.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.compressed_size.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            // extra field, comment, disk, internal and external attributes
+            directory.extend([0; 12]);
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let size: u32 = directory.len().try_into().map_err(|_| too_large())?;
+        directory.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        // this disk and the disk with the central directory
+        directory.extend([0; 4]);
+        directory.extend(count.to_le_bytes());
+        directory.extend(count.to_le_bytes());
+        directory.extend(size.to_le_bytes());
+        directory.extend(start.to_le_bytes());
+        // comment
+        directory.extend([0; 2]);
+        self.writer.write_all(&directory)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(data: &[u8]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Vec::new());
+        writer.add("image.png", DosTime { time: 0, date: 0x21 }, data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn entries_survive_a_round_trip() {
+        let data = vec![7; 4096];
+        let entries = read_archive(&archive(&data)).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "image.png");
+        assert_eq!(entries[0].data, data);
+    }
+
+    #[test]
+    fn deflated_entry_larger_than_declared_is_refused() {
+        let mut zip = archive(&vec![0; 1 << 20]);
+        // the central directory's entry comes right before its end record
+        let end = zip.len() - 22;
+        let entry = u32_at(&zip, end + 16).unwrap() as usize;
+        assert_eq!(u16_at(&zip, entry + 10).unwrap(), METHOD_DEFLATED);
+        zip[entry + 24..entry + 28].copy_from_slice(&16u32.to_le_bytes());
+
+        let Err(error) = read_archive(&zip) else { panic!("read an entry larger than declared") };
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("more data than declared"), "{error}");
+    }
+}